@@ -57,6 +57,7 @@
             --set TOPLEVEL ''${!toplevelVar} \
             --set DISTRO_ID ${lib.escapeShellArg config.system.nixos.distroId} \
             --set INSTALL_BOOTLOADER ${lib.escapeShellArg config.system.build.installBootLoader} \
+            --set BOOTLOADER_ID ${lib.escapeShellArg config.system.boot.loader.id} \
             --set PRE_SWITCH_CHECK ${lib.escapeShellArg config.system.preSwitchChecksScript} \
             --set LOCALE_ARCHIVE ${config.i18n.glibcLocales}/lib/locale/locale-archive \
             --set SYSTEMD ${config.systemd.package}
@@ -149,10 +150,16 @@
         '';
     };
 
+    # Allows switch-to-configuration to publish the progress of a switch.
+    services.dbus.packages = [ pkgs.switch-to-configuration-ng ];
+
     security =
       let
+        # SSH_CONNECTION tells switch-to-configuration that it runs in an SSH session, e.g. with
+        # nixos-rebuild --target-host.
         extraConfig = ''
           Defaults env_keep+=NIXOS_NO_CHECK
+          Defaults env_keep+=SSH_CONNECTION
         '';
       in
       {
@@ -390,7 +390,11 @@ def install_bootloader(args: argparse.Namespace) -> None:
             for specialisation in bootspec.specialisations.keys():
                 write_entry(gen.profile, gen.generation, specialisation, machine_id, bootspec, current=is_default)
             if is_default:
-                write_loader_conf(*gen)
+                # switch-to-configuration --specialisation asks for the specialisation to be the default.
+                specialisation = os.environ.get("NIXOS_SPECIALISATION")
+                if specialisation not in bootspec.specialisations:
+                    specialisation = None
+                write_loader_conf(gen.profile, gen.generation, specialisation)
         except OSError as e:
             # See https://github.com/NixOS/nixpkgs/issues/114552
             if e.errno == errno.EINVAL:
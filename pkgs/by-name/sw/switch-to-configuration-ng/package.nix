@@ -21,8 +21,14 @@ rustPlatform.buildRustPackage {
 
   env.SYSTEMD_DBUS_INTERFACE_DIR = "${buildPackages.systemd}/share/dbus-1/interfaces";
 
+  postInstall = ''
+    install -Dm444 org.nixos.SwitchToConfiguration1.conf -t $out/share/dbus-1/system.d
+  '';
+
+  # The tests of the D-Bus interface run a bus of their own.
   nativeCheckInputs = [
     clippy
+    dbus
   ];
 
   preCheck = ''
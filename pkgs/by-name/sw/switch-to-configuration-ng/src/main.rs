@@ -4,21 +4,35 @@
 
 use std::{
     cell::RefCell,
-    collections::HashMap,
-    io::{BufRead, Read, Write},
-    os::unix::{fs::PermissionsExt, process::CommandExt},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    io::{Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        process::{CommandExt, ExitStatusExt},
+    },
     path::{Path, PathBuf},
     rc::Rc,
     str::FromStr,
-    sync::OnceLock,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use dbus::{
-    blocking::{stdintf::org_freedesktop_dbus::Properties, LocalConnection, Proxy},
+    arg::PropMap,
+    arg::{RefArg, Variant},
+    blocking::{
+        stdintf::org_freedesktop_dbus::{
+            Properties, PropertiesPropertiesChanged, RequestNameReply,
+        },
+        LocalConnection, Proxy, SyncConnection,
+    },
+    channel::{MatchingReceiver, Sender},
+    message::{MatchRule, SignalArgs},
     Message,
 };
+use dbus_crossroads::{Crossroads, IfaceBuilder};
 use glob::glob;
 use ini::{Ini, ParseOption};
 use log::LevelFilter;
@@ -28,8 +42,10 @@ use nix::{
         signal::{self, SigHandler, Signal},
         stat::Mode,
     },
+    unistd::{Uid, User},
 };
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use syslog::Facility;
 
 mod systemd_manager {
@@ -62,12 +78,155 @@ type UnitInfo = HashMap<String, HashMap<String, Vec<String>>>;
 
 const SYSINIT_REACTIVATION_TARGET: &str = "sysinit-reactivation.target";
 
-// To be robust against interruption, record what units need to be started etc. We read these files
-// again every time this program starts to make sure we continue where the old (interrupted) script
-// left off.
-const START_LIST_FILE: &str = "/run/nixos/start-list";
-const RESTART_LIST_FILE: &str = "/run/nixos/restart-list";
-const RELOAD_LIST_FILE: &str = "/run/nixos/reload-list";
+// Where the units of the system and of the user managers are, relative to the root of a
+// configuration.
+const SYSTEM_UNIT_DIR: &str = "etc/systemd/system";
+const USER_UNIT_DIR: &str = "etc/systemd/user";
+
+// Directory for runtime state of switch-to-configuration.
+const RUN_DIR: &str = "/run/nixos";
+
+// To be robust against interruption, the progress of a switch is recorded in a journal (relative to
+// RUN_DIR). It is read again every time this program starts to make sure we continue where the old
+// (interrupted) switch left off.
+const SWITCH_JOURNAL_FILE: &str = "switch-journal.json";
+
+// Version of the journal format. Journals with a different version are ignored.
+const SWITCH_JOURNAL_VERSION: u32 = 1;
+
+// Units whose restart was deferred by a switch (relative to RUN_DIR), see `X-RestartDeferred`.
+// The file is kept until the units are restarted, either by a later switch or by the `pending`
+// action.
+const PENDING_RESTARTS_FILE: &str = "pending-restarts";
+
+// Version of the pending restarts format. Files with a different version are ignored.
+const PENDING_RESTARTS_VERSION: u32 = 1;
+
+// Older versions recorded the units that still needed to be started, restarted and reloaded in
+// these files. They are still picked up once so that a switch interrupted by an older version is
+// not lost.
+const LEGACY_START_LIST_FILE: &str = "start-list";
+const LEGACY_RESTART_LIST_FILE: &str = "restart-list";
+const LEGACY_RELOAD_LIST_FILE: &str = "reload-list";
+
+// A switch that would stop the session running it continues in a transient service. How that switch
+// ended is recorded in this file and its output is written to the log file (both relative to
+// RUN_DIR).
+const DETACHED_SWITCH_FILE: &str = "detached-switch.json";
+const DETACHED_SWITCH_LOG_FILE: &str = "detached-switch.log";
+
+// Version of the detached switch format.
+const DETACHED_SWITCH_VERSION: u32 = 1;
+
+// Set in the environment of the transient service to the name of the service, and in the
+// environment of the switch that runs in it.
+const DETACHED_SWITCH_UNIT_ENV: &str = "__NIXOS_SWITCH_TO_CONFIGURATION_DETACHED_UNIT";
+const DETACHED_SWITCH_ENV: &str = "__NIXOS_SWITCH_TO_CONFIGURATION_DETACHED";
+
+// When the switch started, in milliseconds since the epoch. A switch that continues in another
+// process (detached or in a specialisation) keeps the time limit of the switch that started it.
+const SWITCH_STARTED_ENV: &str = "__NIXOS_SWITCH_TO_CONFIGURATION_STARTED";
+
+// The environment variables that are passed on to a detached switch, besides the `STC_*` settings:
+// the ones set by the wrapper of switch-to-configuration, the settings of the switch and the SSH
+// connection. Everything else, like the agent sockets of the user running the switch, would be
+// visible in the properties of the transient service.
+const DETACHED_SWITCH_ENV_VARS: [&str; 14] = [
+    "OUT",
+    "TOPLEVEL",
+    "DISTRO_ID",
+    "INSTALL_BOOTLOADER",
+    "BOOTLOADER_ID",
+    "PRE_SWITCH_CHECK",
+    "LOCALE_ARCHIVE",
+    "SYSTEMD",
+    "NIXOS_NO_CHECK",
+    "NIXOS_NO_SYNC",
+    "SSH_CONNECTION",
+    SPECIALISATION_OF_ENV,
+    SPECIALISATION_ENV,
+    SWITCH_STARTED_ENV,
+];
+
+// How often the output of a detached switch is forwarded.
+const DETACHED_SWITCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Units that an SSH session depends on without running in them. Entries ending with '*' are
+// prefixes. sshd.service is not one of them, restarting it keeps the existing sessions.
+const SSH_SESSION_UNITS: [&str; 8] = [
+    "network-setup.service",
+    "network-addresses-*",
+    "network-link-*",
+    "systemd-networkd.service",
+    "NetworkManager.service",
+    "dhcpcd.service",
+    "wpa_supplicant*",
+    "iwd.service",
+];
+
+// Every switch appends a record of what it did to this file, one JSON document per line. Unlike the
+// files in RUN_DIR, this has to survive reboots.
+const SWITCH_HISTORY_FILE: &str = "/var/log/nixos/switch-history.jsonl";
+
+// Version of the switch history records. Records with a different version are skipped.
+const SWITCH_HISTORY_VERSION: u32 = 1;
+
+// Once the switch history grows larger than this, it is moved to `<file>.1` (replacing the one
+// that was there) and a new history is started.
+const SWITCH_HISTORY_MAX_SIZE: u64 = 1024 * 1024;
+
+// The specialisation that was last switched to with `--specialisation`. `boot` makes its boot entry
+// the default if the new configuration has a specialisation with this name. Unlike the files in
+// RUN_DIR, this has to survive reboots.
+const SPECIALISATION_FILE: &str = "/var/lib/nixos/specialisation";
+
+// Set when a configuration runs the switch-to-configuration of one of its specialisations, to the
+// configuration the specialisation belongs to. Only that configuration has boot entries.
+const SPECIALISATION_OF_ENV: &str = "__NIXOS_SWITCH_TO_CONFIGURATION_SPECIALISATION_OF";
+
+// Tells the bootloader installation which specialisation to make the default boot entry.
+const SPECIALISATION_ENV: &str = "NIXOS_SPECIALISATION";
+
+// The bootloaders (by `system.boot.loader.id`) whose installation honours `SPECIALISATION_ENV`.
+const SPECIALISATION_BOOTLOADERS: &[&str] = &["systemd-boot"];
+
+// The specialisation that the running configuration was switched to as, if any (relative to
+// RUN_DIR). A rollback to the running configuration switches to it as that specialisation again.
+const RUNNING_SPECIALISATION_FILE: &str = "running-specialisation.json";
+
+// Version of the running specialisation format.
+const RUNNING_SPECIALISATION_VERSION: u32 = 1;
+
+// Which parts of the switched-to configuration only take effect after a reboot (relative to
+// RUN_DIR). The file only exists while a reboot is needed.
+const REBOOT_REQUIRED_FILE: &str = "reboot-required";
+
+// Version of the reboot-required format.
+const REBOOT_REQUIRED_VERSION: u32 = 1;
+
+// The configuration that the system was booted into.
+const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+// Parts of a configuration that are only used when it is booted. Symlinks are compared by the
+// store path they point to and `kernel-params` by its contents.
+const BOOT_COMPONENTS: [&str; 5] = [
+    "kernel",
+    "initrd",
+    "kernel-modules",
+    "kernel-params",
+    "firmware",
+];
+
+// Scratch directory (relative to the run directory) that the fstab generator of the new
+// configuration writes its units to.
+const FSTAB_GENERATOR_DIR: &str = "fstab-generator";
+
+// Where the cgroup hierarchy and the information about processes are mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PROC_ROOT: &str = "/proc";
+
+// Where systemd puts the units of the running configuration's generators.
+const SYSTEMD_GENERATOR_DIR: &str = "/run/systemd/generator";
 
 // Parse restart/reload requests by the activation script. Activation scripts may write
 // newline-separated units to the restart file and switch-to-configuration will handle them. While
@@ -93,6 +252,20 @@ const DAEMON_RELOAD_TIMEOUT: Duration = Duration::from_secs(180);
 // Used during times of waiting for D-Bus to process messages.
 const DBUS_PROCESS_TIME: Duration = Duration::from_millis(500);
 
+// A switch publishes its progress on the system bus under this name, which is also the name of the
+// interface, see `SwitchProgress`.
+const PROGRESS_INTERFACE: &str = "org.nixos.SwitchToConfiguration1";
+const PROGRESS_OBJECT_PATH: &str = "/org/nixos/SwitchToConfiguration1";
+
+// Exit code used when systemd jobs did not finish within the configured timeouts.
+const JOB_TIMEOUT_EXIT_CODE: i32 = 5;
+
+// How long the units of a user may take to switch unless STC_USER_SWITCH_TIMEOUT says otherwise.
+const DEFAULT_USER_SWITCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+// How often to check whether the user switches have finished.
+const USER_SWITCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone, PartialEq)]
 enum Action {
     Switch,
@@ -100,6 +273,12 @@ enum Action {
     Boot,
     Test,
     DryActivate,
+    Status,
+    Resume,
+    SoftReboot,
+    Diff,
+    Pending,
+    History,
 }
 
 impl std::str::FromStr for Action {
@@ -112,6 +291,12 @@ impl std::str::FromStr for Action {
             "test" => Self::Test,
             "dry-activate" => Self::DryActivate,
             "check" => Self::Check,
+            "status" => Self::Status,
+            "resume" => Self::Resume,
+            "soft-reboot" => Self::SoftReboot,
+            "diff" => Self::Diff,
+            "pending" => Self::Pending,
+            "history" => Self::History,
             _ => bail!("invalid action {s}"),
         })
     }
@@ -125,2103 +310,8648 @@ impl From<&Action> for &'static str {
             Action::Test => "test",
             Action::DryActivate => "dry-activate",
             Action::Check => "check",
+            Action::Status => "status",
+            Action::Resume => "resume",
+            Action::SoftReboot => "soft-reboot",
+            Action::Diff => "diff",
+            Action::Pending => "pending",
+            Action::History => "history",
         }
     }
 }
 
-// Allow for this switch-to-configuration to remain consistent with the perl implementation.
-// Perl's "die" uses errno to set the exit code: https://perldoc.perl.org/perlvar#%24%21
-fn die() -> ! {
-    let code = match std::io::Error::last_os_error().raw_os_error().unwrap_or(1) {
-        // Ensure that even if errno did not point to a helpful error code, we still have a
-        // non-zero exit code
-        0 => 1,
-        other => other,
-    };
-
-    std::process::exit(code);
+// Flags that can follow the action on the command line.
+#[derive(Debug, Default)]
+struct Options {
+    // Print the computed plan as JSON on stdout (only meaningful for dry-activate).
+    json: bool,
+    // Activate the previous configuration again if units fail during switch or test.
+    rollback_on_failure: bool,
+    // Show how changed units differ and why they are restarted or reloaded.
+    explain: bool,
+    // Soft-reboot into the configuration on switch if its init is incompatible with the running one.
+    soft_reboot_fallback: bool,
+    // Restart the units whose restart was deferred (only meaningful for pending).
+    apply: bool,
+    // The units to restart with `pending --apply`, all of them if empty.
+    units: Vec<String>,
+    // Restart services that still use store paths of the previous configuration.
+    restart_stale: bool,
+    // Switch to this specialisation of the configuration instead of the configuration itself.
+    specialisation: Option<String>,
+    // How many of the last switches to show (only meaningful for history), all of them if unset.
+    count: Option<usize>,
 }
 
-fn parse_os_release() -> Result<HashMap<String, String>> {
-    Ok(std::fs::read_to_string("/etc/os-release")
-        .context("Failed to read /etc/os-release")?
-        .lines()
-        .fold(HashMap::new(), |mut acc, line| {
-            if let Some((k, v)) = line.split_once('=') {
-                acc.insert(k.to_string(), v.to_string());
-            }
+// Version of the document printed by `dry-activate --json`. This must be bumped whenever a field is
+// removed or changes its meaning so that consumers can detect incompatible output.
+const SWITCH_PLAN_VERSION: u32 = 1;
 
-            acc
-        }))
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum MountAction {
+    Reload,
+    Restart,
+    Stop,
+    Skip,
 }
 
-fn do_pre_switch_check(command: &str, toplevel: &Path, action: &Action) -> Result<()> {
-    let mut cmd_split = command.split_whitespace();
-    let Some(argv0) = cmd_split.next() else {
-        bail!("missing first argument in pre-switch check");
-    };
+// The decision that was made for a filesystem from fstab.
+#[derive(Debug, Serialize)]
+struct MountChange {
+    mountpoint: String,
+    unit: String,
+    action: MountAction,
+}
 
-    match std::process::Command::new(argv0)
-        .args(cmd_split.collect::<Vec<&str>>())
-        .arg(toplevel)
-        .arg::<&str>(action.into())
-        .spawn()
-        .map(|mut child| child.wait())
-    {
-        Ok(Ok(status)) if status.success() => {}
-        _ => {
-            eprintln!("Pre-switch checks failed");
-            std::process::exit(1);
-        }
-    }
+// A swap device whose options changed in a way that only takes effect when it is turned on again.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct SwapChange {
+    device: String,
+    old_options: String,
+    new_options: String,
+}
 
-    Ok(())
+// Everything switch-to-configuration has decided to do. This is computed by `plan_switch` without
+// talking to systemd and is then carried out through a `SystemdManager`.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct SwitchPlan {
+    #[serde(serialize_with = "serialize_units")]
+    units_to_stop: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_start: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_restart: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_reload: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_skip: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_try_restart: HashMap<String, ()>,
+    #[serde(serialize_with = "serialize_units")]
+    units_to_reload_or_restart: HashMap<String, ()>,
+    // Units that are sent a signal instead of being restarted (`X-SwitchMethod=kill-signal:`).
+    #[serde(serialize_with = "serialize_signals")]
+    units_to_signal: BTreeMap<String, Signal>,
+    // Changed units whose restart is left for later (`X-SwitchMethod=defer` or
+    // `X-RestartDeferred=true`), with the reason they need a restart.
+    units_to_defer: BTreeMap<String, String>,
+    // Units that are acted upon but not shown to the user.
+    #[serde(rename = "units-filtered", serialize_with = "serialize_units")]
+    units_to_filter: HashMap<String, ()>,
+    swaps_to_stop: Vec<String>,
+    // Swap devices that are new in fstab. Their swap units are started with the other units.
+    swaps_to_start: Vec<String>,
+    swaps_to_reapply: Vec<SwapChange>,
+    mounts: Vec<MountChange>,
+    // Aliases of units that were replaced by the unit they refer to, keyed by the alias.
+    unit_aliases: BTreeMap<String, String>,
+    // How changed units differ from their current version. Only recorded when explaining.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    unit_differences: BTreeMap<String, Vec<UnitDifference>>,
+    restart_systemd: bool,
+    // Boot components that differ from the booted system and need a reboot to be used.
+    reboot_required: Vec<BootComponentChange>,
 }
 
-fn do_install_bootloader(command: &str, toplevel: &Path) -> Result<()> {
-    let mut cmd_split = command.split_whitespace();
-    let Some(argv0) = cmd_split.next() else {
-        bail!("missing first argument in install bootloader commands");
-    };
+impl SwitchPlan {
+    // Replaces aliases in the units to start, restart and reload by the units they refer to.
+    // Otherwise systemd would get two jobs for the same unit, which fails with "Failed to add path
+    // to set".
+    fn collapse_aliases(&mut self, aliases: &HashMap<String, String>) {
+        for units in [
+            &mut self.units_to_start,
+            &mut self.units_to_restart,
+            &mut self.units_to_reload,
+            &mut self.units_to_try_restart,
+            &mut self.units_to_reload_or_restart,
+        ] {
+            for unit in units.keys().cloned().collect::<Vec<_>>() {
+                let canonical = canonical_unit_name(&unit, aliases);
+                if canonical != unit {
+                    units.remove(&unit);
+                    units.insert(canonical.clone(), ());
+                    self.unit_aliases.insert(unit, canonical);
+                }
+            }
+        }
 
-    match std::process::Command::new(argv0)
-        .args(cmd_split.collect::<Vec<&str>>())
-        .arg(toplevel)
-        .spawn()
-        .map(|mut child| child.wait())
-    {
-        Ok(Ok(status)) if status.success() => {}
-        _ => {
-            eprintln!("Failed to install bootloader");
-            std::process::exit(1);
+        for unit in self.units_to_restart.keys() {
+            self.units_to_reload.remove(unit);
         }
     }
-
-    Ok(())
 }
 
-extern "C" fn handle_sigpipe(_signal: nix::libc::c_int) {}
-
-fn required_env(var: &str) -> anyhow::Result<String> {
-    std::env::var(var).with_context(|| format!("missing required environment variable ${var}"))
+// The document printed by `dry-activate --json`.
+#[derive(Serialize)]
+struct SwitchPlanDocument<'a> {
+    version: u32,
+    action: &'static str,
+    toplevel: &'a Path,
+    #[serde(flatten)]
+    plan: &'a SwitchPlan,
 }
 
-struct UnitState<'a, 'b> {
-    state: String,
-    substate: String,
-    proxy: Proxy<'a, &'b LocalConnection>,
+// What the `diff` action found out about two configurations.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigurationDiff {
+    added_units: Vec<String>,
+    removed_units: Vec<String>,
+    // Units whose unit file or drop-ins differ, including templates and targets.
+    changed_units: Vec<String>,
+    // The new configuration can't be switched to without a reboot.
+    init_interface_changed: bool,
+    // What a switch from the old to the new configuration would do if every unit of the old
+    // configuration was active.
+    #[serde(flatten)]
+    plan: SwitchPlan,
 }
 
-// Asks the currently running systemd instance via dbus which units are active. Returns a hash
-// where the key is the name of each unit and the value a hash of load, state, substate.
-fn get_active_units<'a, 'b>(
-    systemd_manager: &Proxy<'a, &'b LocalConnection>,
-) -> Result<HashMap<String, UnitState<'a, 'b>>> {
-    let units = systemd_manager
-        .list_units_by_patterns(Vec::new(), Vec::new())
-        .context("Failed to list systemd units")?;
+// The document printed by `diff --json`. It shares its version with the plan it contains.
+#[derive(Serialize)]
+struct ConfigurationDiffDocument<'a> {
+    version: u32,
+    old: &'a Path,
+    new: &'a Path,
+    #[serde(flatten)]
+    diff: &'a ConfigurationDiff,
+}
 
-    Ok(units
-        .into_iter()
-        .filter_map(
-            |(
-                id,
-                _description,
-                _load_state,
-                state,
-                substate,
-                following,
-                unit_path,
-                _job_id,
-                _job_type,
-                _job_path,
-            )| {
-                let proxy = systemd_manager.connection.with_proxy(
-                    "org.freedesktop.systemd1",
-                    unit_path,
-                    Duration::from_millis(5000),
-                );
+// How far a switch got. The phases are reached in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SwitchPhase {
+    // Nothing was done yet.
+    Planned,
+    // Units that needed to be stopped were stopped.
+    UnitsStopped,
+    // The activation script ran.
+    Activated,
+    // systemd loaded the units of the new configuration.
+    SystemdReloaded,
+}
 
-                if following.is_empty() && state != "inactive" {
-                    Some((
-                        id,
-                        UnitState {
-                            state,
-                            substate,
-                            proxy,
-                        },
-                    ))
-                } else {
-                    None
-                }
-            },
+impl std::fmt::Display for SwitchPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SwitchPhase::Planned => "planned",
+                SwitchPhase::UnitsStopped => "units stopped",
+                SwitchPhase::Activated => "activated",
+                SwitchPhase::SystemdReloaded => "systemd reloaded",
+            }
         )
-        .fold(HashMap::new(), |mut acc, (id, unit_state)| {
-            acc.insert(id, unit_state);
+    }
+}
 
-            acc
-        }))
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum JobStatus {
+    Pending,
+    Done,
 }
 
-// This function takes a single ini file that specified systemd configuration like unit
-// configuration and parses it into a HashMap where the keys are the sections of the unit file and
-// the values are HashMaps themselves. These HashMaps have the unit file keys as their keys (left
-// side of =) and an array of all values that were set as their values. If a value is empty (for
-// example `ExecStart=`), then all current definitions are removed.
-//
-// Instead of returning the HashMap, this function takes a mutable reference to a HashMap to return
-// the data in. This allows calling the function multiple times with the same Hashmap to parse
-// override files.
-fn parse_systemd_ini(data: &mut UnitInfo, mut unit_file: impl Read) -> Result<()> {
-    let mut unit_file_content = String::new();
-    _ = unit_file
-        .read_to_string(&mut unit_file_content)
-        .context("Failed to read unit file")?;
+// A job of a switch and whether it already ran.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct JournalEntry {
+    unit: String,
+    job: Job,
+    status: JobStatus,
+}
 
-    let ini = Ini::load_from_str_opt(
-        &unit_file_content,
-        ParseOption {
-            enabled_quote: true,
-            enabled_indented_mutiline_value: false,
-            enabled_preserve_key_leading_whitespace: false,
-            // Allow for escaped characters that won't get interpreted by the INI parser. These
-            // often show up in systemd unit files device/mount/swap unit names (e.g. dev-disk-by\x2dlabel-root.device).
-            enabled_escape: false,
-        },
-    )
-    .context("Failed parse unit file as INI")?;
+// A unit that is sent a signal instead of being restarted and whether that happened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct JournalSignal {
+    unit: String,
+    signal: String,
+    status: JobStatus,
+}
 
-    // Copy over all sections
-    for (section, properties) in ini.iter() {
-        let Some(section) = section else {
-            continue;
+// The progress of a switch. It is written to disk (see `SWITCH_JOURNAL_FILE`) whenever it changes
+// and removed once all jobs ran.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SwitchJournal {
+    version: u32,
+    toplevel: PathBuf,
+    // The configuration that was running before the switch. User units are compared against it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_toplevel: Option<PathBuf>,
+    action: String,
+    phase: SwitchPhase,
+    restart_systemd: bool,
+    units: Vec<JournalEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    signals: Vec<JournalSignal>,
+    // Units whose restart is deferred, with the reason they need a restart.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    deferred: BTreeMap<String, String>,
+}
+
+impl SwitchJournal {
+    fn new(
+        toplevel: &Path,
+        previous_toplevel: Option<PathBuf>,
+        action: &Action,
+        plan: &SwitchPlan,
+    ) -> Self {
+        let mut journal = Self {
+            version: SWITCH_JOURNAL_VERSION,
+            toplevel: toplevel.to_path_buf(),
+            previous_toplevel,
+            action: Into::<&'static str>::into(action).to_string(),
+            phase: SwitchPhase::Planned,
+            restart_systemd: plan.restart_systemd,
+            units: Vec::new(),
+            signals: Vec::new(),
+            deferred: BTreeMap::new(),
         };
+        journal.update(plan);
 
-        if section == "Install" {
-            // Skip the [Install] section because it has no relevant keys for us
-            continue;
+        journal
+    }
+
+    // Adds the jobs of `plan` that are not in the journal yet and drops pending jobs that are no
+    // longer part of the plan.
+    fn update(&mut self, plan: &SwitchPlan) {
+        let planned = [
+            (Job::Stop, &plan.units_to_stop),
+            (Job::Reload, &plan.units_to_reload),
+            (Job::ReloadOrRestart, &plan.units_to_reload_or_restart),
+            (Job::Restart, &plan.units_to_restart),
+            (Job::TryRestart, &plan.units_to_try_restart),
+            (Job::Start, &plan.units_to_start),
+        ];
+
+        self.units.retain(|entry| {
+            entry.status != JobStatus::Pending
+                || planned
+                    .iter()
+                    .any(|(job, units)| *job == entry.job && units.contains_key(&entry.unit))
+        });
+
+        for (job, units) in planned {
+            for unit in sorted_units(units) {
+                if !self
+                    .units
+                    .iter()
+                    .any(|entry| entry.job == job && entry.unit == unit)
+                {
+                    self.units.push(JournalEntry {
+                        unit,
+                        job,
+                        status: JobStatus::Pending,
+                    });
+                }
+            }
         }
 
-        let section_map = if let Some(section_map) = data.get_mut(section) {
-            section_map
-        } else {
-            data.insert(section.to_string(), HashMap::new());
-            data.get_mut(section)
-                .ok_or(anyhow!("section name should exist in hashmap"))?
-        };
+        self.signals.retain(|entry| {
+            entry.status != JobStatus::Pending || plan.units_to_signal.contains_key(&entry.unit)
+        });
+        for (unit, signal) in &plan.units_to_signal {
+            if !self.signals.iter().any(|entry| entry.unit == *unit) {
+                self.signals.push(JournalSignal {
+                    unit: unit.clone(),
+                    signal: signal.as_str().to_string(),
+                    status: JobStatus::Pending,
+                });
+            }
+        }
 
-        for (ini_key, _) in properties {
-            let values = properties.get_all(ini_key);
-            let values = values
-                .into_iter()
-                .map(String::from)
-                .collect::<Vec<String>>();
+        self.deferred.extend(plan.units_to_defer.clone());
+    }
 
-            // Go over all values
-            let mut new_vals = Vec::new();
-            let mut clear_existing = false;
+    // Returns the units that still need a job of the given type.
+    fn pending(&self, job: Job) -> HashMap<String, ()> {
+        self.units
+            .iter()
+            .filter(|entry| entry.job == job && entry.status == JobStatus::Pending)
+            .map(|entry| (entry.unit.clone(), ()))
+            .collect()
+    }
 
-            for val in values {
-                // If a value is empty, it's an override that tells us to clean the value
-                if val.is_empty() {
-                    new_vals.clear();
-                    clear_existing = true;
-                } else {
-                    new_vals.push(val);
-                }
+    // Builds a plan containing the pending jobs.
+    fn pending_plan(&self) -> SwitchPlan {
+        SwitchPlan {
+            units_to_stop: self.pending(Job::Stop),
+            units_to_reload: self.pending(Job::Reload),
+            units_to_restart: self.pending(Job::Restart),
+            units_to_try_restart: self.pending(Job::TryRestart),
+            units_to_reload_or_restart: self.pending(Job::ReloadOrRestart),
+            units_to_start: self.pending(Job::Start),
+            units_to_signal: self
+                .signals
+                .iter()
+                .filter(|entry| entry.status == JobStatus::Pending)
+                .filter_map(|entry| {
+                    Some((entry.unit.clone(), Signal::from_str(&entry.signal).ok()?))
+                })
+                .collect(),
+            units_to_defer: self.deferred.clone(),
+            restart_systemd: self.restart_systemd,
+            ..Default::default()
+        }
+    }
+
+    // Marks the jobs that finished as done, `finished` is what `SystemdManager::finished_jobs`
+    // returns.
+    fn finish_jobs(&mut self, finished: &[(String, Job, String)]) {
+        for entry in &mut self.units {
+            if finished
+                .iter()
+                .any(|(unit, job, _)| *unit == entry.unit && *job == entry.job)
+            {
+                entry.status = JobStatus::Done;
             }
+        }
+    }
 
-            match (section_map.get_mut(ini_key), clear_existing) {
-                (Some(existing_vals), false) => existing_vals.extend(new_vals),
-                _ => _ = section_map.insert(ini_key.to_string(), new_vals),
-            };
+    // Marks the signal to a unit as sent.
+    fn finish_signal(&mut self, unit: &str) {
+        for entry in &mut self.signals {
+            if entry.unit == unit {
+                entry.status = JobStatus::Done;
+            }
         }
     }
 
-    Ok(())
-}
+    // Records that the switch reached `phase` and writes the journal.
+    fn reach(&mut self, phase: SwitchPhase, run_dir: &Path) -> Result<()> {
+        self.phase = phase;
+        self.write(run_dir)
+    }
 
-// This function takes the path to a systemd configuration file (like a unit configuration) and
-// parses it into a UnitInfo structure.
-//
-// If a directory with the same basename ending in .d exists next to the unit file, it will be
-// assumed to contain override files which will be parsed as well and handled properly.
-fn parse_unit(unit_file: &Path, base_unit_path: &Path) -> Result<UnitInfo> {
-    // Parse the main unit and all overrides
-    let mut unit_data = HashMap::new();
+    fn write(&self, run_dir: &Path) -> Result<()> {
+        write_state_file(run_dir, SWITCH_JOURNAL_FILE, self)
+    }
 
-    let base_unit_file = std::fs::File::open(base_unit_path)
-        .with_context(|| format!("Failed to open unit file {}", base_unit_path.display()))?;
-    parse_systemd_ini(&mut unit_data, base_unit_file).with_context(|| {
+    // Reads the journal of an interrupted switch, if there is one.
+    fn read(run_dir: &Path) -> Result<Option<Self>> {
+        read_state_file(run_dir, SWITCH_JOURNAL_FILE, SWITCH_JOURNAL_VERSION)
+    }
+
+    fn remove(run_dir: &Path) -> Result<()> {
+        let path = run_dir.join(SWITCH_JOURNAL_FILE);
+        remove_file_if_exists(&path).with_context(|| format!("Failed to remove {}", path.display()))
+    }
+}
+
+// Replaces a JSON file in `run_dir`. The new file is synced to disk before it is renamed over the
+// old one so that a crash leaves either of them intact.
+fn write_state_file(run_dir: &Path, file: &str, state: &impl Serialize) -> Result<()> {
+    let path = run_dir.join(file);
+    let tmp_path = run_dir.join(format!("{file}.tmp"));
+
+    let mut tmp_file = std::fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    serde_json::to_writer_pretty(&mut tmp_file, state)
+        .with_context(|| format!("Failed to serialize {}", path.display()))?;
+    tmp_file
+        .sync_all()
+        .with_context(|| format!("Failed to sync {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &path).with_context(|| {
         format!(
-            "Failed to parse systemd unit file {}",
-            base_unit_path.display()
+            "Failed to rename {} to {}",
+            tmp_path.display(),
+            path.display()
         )
     })?;
+    std::fs::File::open(run_dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", run_dir.display()))?;
 
-    for entry in
-        glob(&format!("{}.d/*.conf", base_unit_path.display())).context("Invalid glob pattern")?
-    {
-        let Ok(entry) = entry else {
-            continue;
-        };
+    Ok(())
+}
 
-        let unit_file = std::fs::File::open(&entry)
-            .with_context(|| format!("Failed to open unit file {}", entry.display()))?;
-        parse_systemd_ini(&mut unit_data, unit_file)?;
+// Reads a JSON file written by `write_state_file`. Files with a `version` other than `version` are
+// ignored with a warning.
+fn read_state_file<T: serde::de::DeserializeOwned>(
+    run_dir: &Path,
+    file: &str,
+    version: u32,
+) -> Result<Option<T>> {
+    let path = run_dir.join(file);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let state: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let found_version = state.get("version").and_then(serde_json::Value::as_u64);
+    if found_version != Some(version.into()) {
+        eprintln!(
+            "warning: ignoring {} with unsupported version {}",
+            path.display(),
+            found_version.map_or_else(|| "(none)".to_string(), |version| version.to_string())
+        );
+        return Ok(None);
     }
 
-    // Handle drop-in template-unit instance overrides
-    if unit_file != base_unit_path {
-        for entry in
-            glob(&format!("{}.d/*.conf", unit_file.display())).context("Invalid glob pattern")?
-        {
-            let Ok(entry) = entry else {
-                continue;
-            };
+    serde_json::from_value(state)
+        .map(Some)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+// A unit that still runs with the configuration it had before its restart was deferred.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PendingRestart {
+    reason: String,
+    // The configuration whose switch deferred the restart.
+    toplevel: PathBuf,
+}
+
+// The units whose restart was deferred, see `PENDING_RESTARTS_FILE`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PendingRestarts {
+    version: u32,
+    units: BTreeMap<String, PendingRestart>,
+}
+
+impl PendingRestarts {
+    fn read(run_dir: &Path) -> Result<Self> {
+        Ok(
+            read_state_file(run_dir, PENDING_RESTARTS_FILE, PENDING_RESTARTS_VERSION)?.unwrap_or(
+                Self {
+                    version: PENDING_RESTARTS_VERSION,
+                    units: BTreeMap::new(),
+                },
+            ),
+        )
+    }
+
+    fn write(&self, run_dir: &Path) -> Result<()> {
+        write_state_file(run_dir, PENDING_RESTARTS_FILE, self)
+    }
 
-            let unit_file = std::fs::File::open(&entry)
-                .with_context(|| format!("Failed to open unit file {}", entry.display()))?;
-            parse_systemd_ini(&mut unit_data, unit_file)?;
+    // Forgets the units that `plan` restarts or stops and records the units it defers. Units that
+    // were already deferred keep the configuration that first deferred them, they have been
+    // running the old code since then.
+    fn update(&mut self, plan: &SwitchPlan, toplevel: &Path) {
+        for units in [
+            &plan.units_to_stop,
+            &plan.units_to_restart,
+            &plan.units_to_try_restart,
+            &plan.units_to_reload_or_restart,
+        ] {
+            self.units.retain(|unit, _| !units.contains_key(unit));
+        }
+
+        for (unit, reason) in &plan.units_to_defer {
+            self.units
+                .entry(unit.clone())
+                .or_insert_with(|| PendingRestart {
+                    reason: reason.clone(),
+                    toplevel: toplevel.to_path_buf(),
+                });
         }
     }
+}
 
-    Ok(unit_data)
+// A boot component that differs between two configurations. A component is missing if the
+// configuration doesn't have it, e.g. because it is a container.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BootComponentChange {
+    component: String,
+    old: Option<String>,
+    new: Option<String>,
 }
 
-// Checks whether a specified boolean in a systemd unit is true or false, with a default that is
-// applied when the value is not set.
-fn parse_systemd_bool(
-    unit_data: Option<&UnitInfo>,
-    section_name: &str,
-    bool_name: &str,
-    default: bool,
-) -> bool {
-    if let Some(Some(Some(Some(b)))) = unit_data.map(|data| {
-        data.get(section_name).map(|section| {
-            section.get(bool_name).map(|vals| {
-                vals.last()
-                    .map(|last| matches!(last.as_str(), "1" | "yes" | "true" | "on"))
+// Returns the boot components of `new` that differ from the ones of `old`.
+fn boot_component_changes(old: &Path, new: &Path) -> Vec<BootComponentChange> {
+    let component = |toplevel: &Path, component: &str| {
+        let path = toplevel.join(component);
+        if component == "kernel-params" {
+            std::fs::read_to_string(path).ok()
+        } else {
+            path.canonicalize()
+                .ok()
+                .map(|path| path.display().to_string())
+        }
+    };
+
+    BOOT_COMPONENTS
+        .into_iter()
+        .filter_map(|name| {
+            let old = component(old, name);
+            let new = component(new, name);
+            (old != new).then(|| BootComponentChange {
+                component: name.to_string(),
+                old,
+                new,
             })
         })
-    }) {
-        b
-    } else {
-        default
-    }
+        .collect()
 }
 
-#[derive(Debug, PartialEq)]
-enum UnitComparison {
-    Equal,
-    UnequalNeedsRestart,
-    UnequalNeedsReload,
+// The contents of `REBOOT_REQUIRED_FILE`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RebootRequired {
+    version: u32,
+    booted_system: PathBuf,
+    toplevel: PathBuf,
+    components: Vec<BootComponentChange>,
 }
 
-// Compare the contents of two unit files and return whether the unit needs to be restarted or
-// reloaded. If the units differ, the service is restarted unless the only difference is
-// `X-Reload-Triggers` in the `Unit` section. If this is the only modification, the unit is
-// reloaded instead of restarted. If the only difference is `Options` in the `[Mount]` section, the
-// unit is reloaded rather than restarted.
-fn compare_units(current_unit: &UnitInfo, new_unit: &UnitInfo) -> UnitComparison {
-    let mut ret = UnitComparison::Equal;
-
-    let unit_section_ignores = HashMap::from(
-        [
-            "X-Reload-Triggers",
-            "Description",
-            "Documentation",
-            "OnFailure",
-            "OnSuccess",
-            "OnFailureJobMode",
-            "IgnoreOnIsolate",
-            "StopWhenUnneeded",
-            "RefuseManualStart",
-            "RefuseManualStop",
-            "AllowIsolate",
-            "CollectMode",
-            "SourcePath",
-        ]
-        .map(|name| (name, ())),
-    );
-
-    let mut section_cmp = new_unit.keys().fold(HashMap::new(), |mut acc, key| {
-        acc.insert(key.as_str(), ());
-        acc
-    });
+impl RebootRequired {
+    fn read(run_dir: &Path) -> Result<Option<Self>> {
+        read_state_file(run_dir, REBOOT_REQUIRED_FILE, REBOOT_REQUIRED_VERSION)
+    }
 
-    // Iterate over the sections
-    for (section_name, section_val) in current_unit {
-        // Missing section in the new unit?
-        if !section_cmp.contains_key(section_name.as_str()) {
-            // If the [Unit] section was removed, make sure that only keys were in it that are
-            // ignored
-            if section_name == "Unit" {
-                for ini_key in section_val.keys() {
-                    if !unit_section_ignores.contains_key(ini_key.as_str()) {
-                        return UnitComparison::UnequalNeedsRestart;
-                    }
-                }
-                continue; // check the next section
-            } else {
-                return UnitComparison::UnequalNeedsRestart;
-            }
+    // Writes the boot components of `toplevel` that differ from the booted system, or removes the
+    // file if there are none, e.g. after switching back to the booted configuration.
+    fn record(
+        run_dir: &Path,
+        booted_system: &Path,
+        toplevel: &Path,
+        components: &[BootComponentChange],
+    ) -> Result<()> {
+        if components.is_empty() {
+            let path = run_dir.join(REBOOT_REQUIRED_FILE);
+            return remove_file_if_exists(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()));
         }
 
-        section_cmp.remove(section_name.as_str());
-
-        // Comparison hash for the section contents
-        let mut ini_cmp = new_unit
-            .get(section_name)
-            .map(|section_val| {
-                section_val.keys().fold(HashMap::new(), |mut acc, ini_key| {
-                    acc.insert(ini_key.as_str(), ());
-                    acc
-                })
-            })
-            .unwrap_or_default();
-
-        // Iterate over the keys of the section
-        for (ini_key, current_value) in section_val {
-            ini_cmp.remove(ini_key.as_str());
-            let Some(Some(new_value)) = new_unit
-                .get(section_name)
-                .map(|section| section.get(ini_key))
-            else {
-                // If the key is missing in the new unit, they are different unless the key that is
-                // now missing is one of the ignored keys
-                if section_name == "Unit" && unit_section_ignores.contains_key(ini_key.as_str()) {
-                    continue;
-                }
-                return UnitComparison::UnequalNeedsRestart;
-            };
-
-            // If the contents are different, the units are different
-            if current_value != new_value {
-                if section_name == "Unit" {
-                    if ini_key == "X-Reload-Triggers" {
-                        ret = UnitComparison::UnequalNeedsReload;
-                        continue;
-                    } else if unit_section_ignores.contains_key(ini_key.as_str()) {
-                        continue;
-                    }
-                }
-
-                // If this is a mount unit, check if it was only `Options`
-                if section_name == "Mount" && ini_key == "Options" {
-                    ret = UnitComparison::UnequalNeedsReload;
-                    continue;
-                }
+        write_state_file(
+            run_dir,
+            REBOOT_REQUIRED_FILE,
+            &Self {
+                version: REBOOT_REQUIRED_VERSION,
+                booted_system: booted_system
+                    .canonicalize()
+                    .unwrap_or_else(|_| booted_system.to_path_buf()),
+                toplevel: toplevel.to_path_buf(),
+                components: components.to_vec(),
+            },
+        )
+    }
+}
 
-                return UnitComparison::UnequalNeedsRestart;
-            }
-        }
+// The contents of `RUNNING_SPECIALISATION_FILE`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RunningSpecialisation {
+    version: u32,
+    toplevel: PathBuf,
+    // The configuration the specialisation belongs to.
+    parent: PathBuf,
+    name: String,
+}
 
-        // A key was introduced that was missing in the previous unit
-        if !ini_cmp.is_empty() {
-            if section_name == "Unit" {
-                for (ini_key, _) in ini_cmp {
-                    if ini_key == "X-Reload-Triggers" {
-                        ret = UnitComparison::UnequalNeedsReload;
-                    } else if unit_section_ignores.contains_key(ini_key) {
-                        continue;
-                    } else {
-                        return UnitComparison::UnequalNeedsRestart;
-                    }
-                }
-            } else {
-                return UnitComparison::UnequalNeedsRestart;
-            }
-        }
+impl RunningSpecialisation {
+    // Returns the specialisation that `toplevel` was switched to as, if it is the running one.
+    fn read(run_dir: &Path, toplevel: &Path) -> Result<Option<Self>> {
+        Ok(read_state_file::<Self>(
+            run_dir,
+            RUNNING_SPECIALISATION_FILE,
+            RUNNING_SPECIALISATION_VERSION,
+        )?
+        .filter(|specialisation| specialisation.toplevel == toplevel))
     }
 
-    // A section was introduced that was missing in the previous unit
-    if !section_cmp.is_empty() {
-        if section_cmp.keys().len() == 1 && section_cmp.contains_key("Unit") {
-            if let Some(new_unit_unit) = new_unit.get("Unit") {
-                for ini_key in new_unit_unit.keys() {
-                    if !unit_section_ignores.contains_key(ini_key.as_str()) {
-                        return UnitComparison::UnequalNeedsRestart;
-                    } else if ini_key == "X-Reload-Triggers" {
-                        ret = UnitComparison::UnequalNeedsReload;
-                    }
-                }
-            }
-        } else {
-            return UnitComparison::UnequalNeedsRestart;
-        }
-    }
+    // Records which specialisation `toplevel` is switched to as, or removes the file if it is
+    // switched to as a configuration of its own.
+    fn record(
+        run_dir: &Path,
+        toplevel: &Path,
+        specialisation: Option<(&Path, &str)>,
+    ) -> Result<()> {
+        let Some((parent, name)) = specialisation else {
+            let path = run_dir.join(RUNNING_SPECIALISATION_FILE);
+            return remove_file_if_exists(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()));
+        };
 
-    ret
+        write_state_file(
+            run_dir,
+            RUNNING_SPECIALISATION_FILE,
+            &Self {
+                version: RUNNING_SPECIALISATION_VERSION,
+                toplevel: toplevel
+                    .canonicalize()
+                    .unwrap_or_else(|_| toplevel.to_path_buf()),
+                parent: parent.to_path_buf(),
+                name: name.to_string(),
+            },
+        )
+    }
 }
 
-// Called when a unit exists in both the old systemd and the new system and the units differ. This
-// figures out of what units are to be stopped, restarted, reloaded, started, and skipped.
-fn handle_modified_unit(
-    toplevel: &Path,
-    unit: &str,
-    base_name: &str,
-    new_unit_file: &Path,
-    new_base_unit_file: &Path,
-    new_unit_info: Option<&UnitInfo>,
-    active_cur: &HashMap<String, UnitState>,
-    units_to_stop: &mut HashMap<String, ()>,
-    units_to_start: &mut HashMap<String, ()>,
-    units_to_reload: &mut HashMap<String, ()>,
-    units_to_restart: &mut HashMap<String, ()>,
-    units_to_skip: &mut HashMap<String, ()>,
-) -> Result<()> {
-    let use_restart_as_stop_and_start = new_unit_info.is_none();
+// Returns the names of boot components, separated by commas.
+fn component_names(components: &[BootComponentChange]) -> String {
+    components
+        .iter()
+        .map(|change| change.component.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    if matches!(
-        unit,
-        "sysinit.target" | "basic.target" | "multi-user.target" | "graphical.target"
-    ) || unit.ends_with(".unit")
-        || unit.ends_with(".slice")
-    {
-        // Do nothing.  These cannot be restarted directly.
+// Lists the units whose restart was deferred for the `pending` action.
+fn print_pending_restarts(run_dir: &Path, json: bool) -> Result<()> {
+    let pending = PendingRestarts::read(run_dir)?;
 
-        // Slices and Paths don't have to be restarted since properties (resource limits and
-        // inotify watches) seem to get applied on daemon-reload.
-    } else if unit.ends_with(".mount") {
-        // Just restart the unit. We wouldn't have gotten into this subroutine if only `Options`
-        // was changed, in which case the unit would be reloaded. The only exception is / and /nix
-        // because it's very unlikely we can safely unmount them so we reload them instead. This
-        // means that we may not get all changes into the running system but it's better than
-        // crashing it.
-        if unit == "-.mount" || unit == "nix.mount" {
-            units_to_reload.insert(unit.to_string(), ());
-            record_unit(RELOAD_LIST_FILE, unit);
-        } else {
-            units_to_restart.insert(unit.to_string(), ());
-            record_unit(RESTART_LIST_FILE, unit);
-        }
-    } else if unit.ends_with(".socket") {
-        // FIXME: do something?
-        // Attempt to fix this: https://github.com/NixOS/nixpkgs/pull/141192
-        // Revert of the attempt: https://github.com/NixOS/nixpkgs/pull/147609
-        // More details: https://github.com/NixOS/nixpkgs/issues/74899#issuecomment-981142430
-    } else {
-        let fallback = parse_unit(new_unit_file, new_base_unit_file)?;
-        let new_unit_info = if new_unit_info.is_some() {
-            new_unit_info
-        } else {
-            Some(&fallback)
-        };
+    if json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &pending)
+            .context("Failed to serialize pending restarts")?;
+        writeln!(&mut stdout).context("Failed to write pending restarts")?;
+        return Ok(());
+    }
 
-        if parse_systemd_bool(new_unit_info, "Service", "X-ReloadIfChanged", false)
-            && !units_to_restart.contains_key(unit)
-            && !(if use_restart_as_stop_and_start {
-                units_to_restart.contains_key(unit)
-            } else {
-                units_to_stop.contains_key(unit)
-            })
-        {
-            units_to_reload.insert(unit.to_string(), ());
-            record_unit(RELOAD_LIST_FILE, unit);
-        } else if !parse_systemd_bool(new_unit_info, "Service", "X-RestartIfChanged", true)
-            || parse_systemd_bool(new_unit_info, "Unit", "RefuseManualStop", false)
-            || parse_systemd_bool(new_unit_info, "Unit", "X-OnlyManualStart", false)
-        {
-            units_to_skip.insert(unit.to_string(), ());
-        } else {
-            // It doesn't make sense to stop and start non-services because they can't have
-            // ExecStop=
-            if !parse_systemd_bool(new_unit_info, "Service", "X-StopIfChanged", true)
-                || !unit.ends_with(".service")
-            {
-                // This unit should be restarted instead of stopped and started.
-                units_to_restart.insert(unit.to_string(), ());
-                record_unit(RESTART_LIST_FILE, unit);
-                // Remove from units to reload so we don't restart and reload
-                if units_to_reload.contains_key(unit) {
-                    units_to_reload.remove(unit);
-                    unrecord_unit(RELOAD_LIST_FILE, unit);
-                }
-            } else {
-                // If this unit is socket-activated, then stop the socket unit(s) as well, and
-                // restart the socket(s) instead of the service.
-                // We count as "socket-activated" any unit that doesn't declare itself not so
-                // via X-NotSocketActivated, that has any associated .socket units.
-                let mut socket_activated = false;
-                if unit.ends_with(".service") {
-                    let mut sockets = if let Some(Some(Some(sockets))) = new_unit_info.map(|info| {
-                        info.get("Service")
-                            .map(|service_section| service_section.get("Sockets"))
-                    }) {
-                        sockets
-                            .join(" ")
-                            .split_whitespace()
-                            .map(String::from)
-                            .collect()
-                    } else {
-                        Vec::new()
-                    };
+    if pending.units.is_empty() {
+        eprintln!("there are no pending restarts");
+        return Ok(());
+    }
 
-                    if sockets.is_empty() {
-                        sockets.push(format!("{base_name}.socket"));
-                    }
+    for (unit, restart) in &pending.units {
+        eprintln!(
+            "{unit}: {} (deferred by {})",
+            restart.reason,
+            restart.toplevel.display()
+        );
+    }
 
-                    for socket in &sockets {
-                        if active_cur.contains_key(socket) {
-                            // We can now be sure this is a socket-activated unit
+    Ok(())
+}
 
-                            if use_restart_as_stop_and_start {
-                                units_to_restart.insert(socket.to_string(), ());
-                            } else {
-                                units_to_stop.insert(socket.to_string(), ());
-                            }
+// A switch that continues in a transient service, see `DETACHED_SWITCH_FILE`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DetachedSwitch {
+    version: u32,
+    unit: String,
+    action: String,
+    toplevel: PathBuf,
+    log: PathBuf,
+    // Missing while the switch is running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_status: Option<i32>,
+}
 
-                            // Only restart sockets that actually exist in new configuration:
-                            if toplevel.join("etc/systemd/system").join(socket).exists() {
-                                if use_restart_as_stop_and_start {
-                                    units_to_restart.insert(socket.to_string(), ());
-                                    record_unit(RESTART_LIST_FILE, socket);
-                                } else {
-                                    units_to_start.insert(socket.to_string(), ());
-                                    record_unit(START_LIST_FILE, socket);
-                                }
+impl DetachedSwitch {
+    fn read(run_dir: &Path) -> Result<Option<Self>> {
+        read_state_file(run_dir, DETACHED_SWITCH_FILE, DETACHED_SWITCH_VERSION)
+    }
 
-                                socket_activated = true;
-                            }
+    fn write(&self, run_dir: &Path) -> Result<()> {
+        write_state_file(run_dir, DETACHED_SWITCH_FILE, self)
+    }
+}
 
-                            // Remove from units to reload so we don't restart and reload
-                            if units_to_reload.contains_key(unit) {
-                                units_to_reload.remove(unit);
-                                unrecord_unit(RELOAD_LIST_FILE, unit);
-                            }
-                        }
-                    }
-                }
-                if parse_systemd_bool(new_unit_info, "Service", "X-NotSocketActivated", false) {
-                    // If the unit explicitly opts out of socket
-                    // activation, restart it as if it weren't (but do
-                    // restart its sockets, that's fine):
-                    socket_activated = false;
-                }
+// Returns the units whose cgroups contain the process `pid` or one of its ancestors, including the
+// slices they are in.
+fn ancestor_units(proc_root: &Path, pid: u32) -> BTreeSet<String> {
+    let mut units = BTreeSet::new();
+    let mut pid = pid;
 
-                // If the unit is not socket-activated, record that this unit needs to be started
-                // below. We write this to a file to ensure that the service gets restarted if
-                // we're interrupted.
-                if !socket_activated {
-                    if use_restart_as_stop_and_start {
-                        units_to_restart.insert(unit.to_string(), ());
-                        record_unit(RESTART_LIST_FILE, unit);
-                    } else {
-                        units_to_start.insert(unit.to_string(), ());
-                        record_unit(START_LIST_FILE, unit);
-                    }
-                }
+    while pid > 1 {
+        let proc_dir = proc_root.join(pid.to_string());
 
-                if use_restart_as_stop_and_start {
-                    units_to_restart.insert(unit.to_string(), ());
-                } else {
-                    units_to_stop.insert(unit.to_string(), ());
-                }
-                // Remove from units to reload so we don't restart and reload
-                if units_to_reload.contains_key(unit) {
-                    units_to_reload.remove(unit);
-                    unrecord_unit(RELOAD_LIST_FILE, unit);
-                }
+        // With the unified hierarchy, the only line is "0::<cgroup>".
+        for line in std::fs::read_to_string(proc_dir.join("cgroup"))
+            .unwrap_or_default()
+            .lines()
+        {
+            if let Some(cgroup) = line.strip_prefix("0::") {
+                units.extend(
+                    cgroup
+                        .split('/')
+                        .filter(|name| name.contains('.'))
+                        .map(str::to_string),
+                );
             }
         }
+
+        // The command may contain spaces and parentheses, the parent follows the last ')'.
+        let stat = std::fs::read_to_string(proc_dir.join("stat")).unwrap_or_default();
+        let Some(parent) = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(1))
+            .and_then(|parent| parent.parse().ok())
+        else {
+            break;
+        };
+        pid = parent;
     }
 
-    Ok(())
+    units
 }
 
-// Writes a unit name into a given file to be more resilient against crashes of the script. Does
-// nothing when the action is dry-activate.
-fn record_unit(p: impl AsRef<Path>, unit: &str) {
-    if ACTION.get() != Some(&Action::DryActivate) {
-        if let Ok(mut f) = std::fs::File::options().append(true).create(true).open(p) {
-            _ = writeln!(&mut f, "{unit}");
-        }
-    }
-}
+// Returns the units of the plan that are stopped or restarted although this switch depends on
+// them: units that contain this process or its ancestors and, if it runs in an SSH session, the
+// units that the connection depends on.
+fn endangering_units(
+    plan: &SwitchPlan,
+    ancestor_units: &BTreeSet<String>,
+    over_ssh: bool,
+) -> Vec<String> {
+    let session_unit = |unit: &str| {
+        ancestor_units.contains(unit)
+            || over_ssh
+                && SSH_SESSION_UNITS
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => unit.starts_with(prefix),
+                        None => unit == *pattern,
+                    })
+    };
 
-// The opposite of record_unit, removes a unit name from a file
-fn unrecord_unit(p: impl AsRef<Path>, unit: &str) {
-    if ACTION.get() != Some(&Action::DryActivate) {
-        if let Ok(contents) = std::fs::read_to_string(&p) {
-            if let Ok(mut f) = std::fs::File::options()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&p)
-            {
-                contents
-                    .lines()
-                    .filter(|line| line != &unit)
-                    .for_each(|line| _ = writeln!(&mut f, "{line}"))
-            }
-        }
-    }
+    let mut units = [
+        &plan.units_to_stop,
+        &plan.units_to_restart,
+        &plan.units_to_try_restart,
+        &plan.units_to_reload_or_restart,
+    ]
+    .into_iter()
+    .flat_map(|units| units.keys())
+    .filter(|unit| session_unit(unit))
+    .cloned()
+    .collect::<Vec<_>>();
+    units.sort();
+    units.dedup();
+
+    units
 }
 
-fn map_from_list_file(p: impl AsRef<Path>) -> HashMap<String, ()> {
-    std::fs::read_to_string(p)
-        .unwrap_or_default()
-        .lines()
-        .filter(|line| !line.is_empty())
-        .fold(HashMap::new(), |mut acc, line| {
-            acc.insert(line.to_string(), ());
-            acc
-        })
+// Returns the environment of the transient service that continues the switch as `unit`, picked
+// from the environment `vars` of this process.
+fn detached_switch_environment(
+    vars: impl Iterator<Item = (String, String)>,
+    unit: &str,
+) -> Vec<String> {
+    vars.filter(|(name, _)| {
+        DETACHED_SWITCH_ENV_VARS.contains(&name.as_str()) || name.starts_with("STC_")
+    })
+    .map(|(name, value)| format!("{name}={value}"))
+    .chain([format!("{DETACHED_SWITCH_UNIT_ENV}={unit}")])
+    .collect()
 }
 
-#[derive(Debug)]
-struct Filesystem {
-    device: String,
-    fs_type: String,
-    options: String,
-}
+// Continues the switch in a transient service so that it is not killed along with the session
+// running it. The output of the switch is forwarded for as long as this process lives and it exits
+// with the exit status of the switch. The lock is only released once the service was started, the
+// detached switch waits for it.
+fn detach_switch(
+    systemd: &DbusSystemdManager,
+    toplevel: &Path,
+    run_dir: &Path,
+    endangering_units: &[String],
+    guard: &mut SwitchGuard,
+    switch_lock: Flock<std::fs::File>,
+) -> Result<()> {
+    let unit = format!(
+        "nixos-switch-to-configuration-{}.service",
+        std::process::id()
+    );
+    let log = run_dir.join(DETACHED_SWITCH_LOG_FILE);
 
-#[derive(Debug)]
-#[allow(unused)]
-struct Swap(String);
+    let myself = Path::new("/proc/self/exe")
+        .canonicalize()
+        .context("Failed to get full path to /proc/self/exe")?;
+    let argv = std::iter::once(myself.display().to_string())
+        .chain(std::env::args().skip(1))
+        .collect::<Vec<_>>();
+    let environment = detached_switch_environment(std::env::vars(), &unit);
 
-// Parse a fstab file, given its path. Returns a tuple of filesystems and swaps.
-//
-// Filesystems is a hash of mountpoint and { device, fsType, options } Swaps is a hash of device
-// and { options }
-fn parse_fstab(fstab: impl BufRead) -> (HashMap<String, Filesystem>, HashMap<String, Swap>) {
-    let mut filesystems = HashMap::new();
-    let mut swaps = HashMap::new();
-
-    for line in fstab.lines() {
-        let Ok(line) = line else {
-            break;
-        };
+    eprintln!(
+        "this session depends on {}, which the switch stops or restarts",
+        endangering_units.join(", ")
+    );
+    eprintln!(
+        "continuing the switch in {unit}, its output is written to {}",
+        log.display()
+    );
+    eprintln!(
+        "if this session is disconnected, run switch-to-configuration status to see how it ended"
+    );
+    log::info!(
+        "continuing switch to system configuration {} in {unit}",
+        toplevel.display()
+    );
 
-        if line.contains('#') {
-            continue;
+    systemd.start_transient_service(&unit, &myself, argv, environment, &log)?;
+    systemd.block_on_jobs();
+    // The detached switch records itself.
+    guard.disarm();
+    drop(switch_lock);
+
+    let mut forwarded = 0;
+    let mut forward_output = || {
+        if let Ok(output) = std::fs::read(&log) {
+            if output.len() > forwarded {
+                _ = std::io::stderr().write_all(&output[forwarded..]);
+                forwarded = output.len();
+            }
         }
+    };
 
-        let mut split = line.split_whitespace();
-        let (Some(device), Some(mountpoint), Some(fs_type), options) = (
-            split.next(),
-            split.next(),
-            split.next(),
-            split.next().unwrap_or_default(),
-        ) else {
-            continue;
-        };
+    loop {
+        forward_output();
 
-        if fs_type == "swap" {
-            swaps.insert(device.to_string(), Swap(options.to_string()));
-        } else {
-            filesystems.insert(
-                mountpoint.to_string(),
-                Filesystem {
-                    device: device.to_string(),
-                    fs_type: fs_type.to_string(),
-                    options: options.to_string(),
-                },
-            );
+        let detached = DetachedSwitch::read(run_dir)?.filter(|detached| detached.unit == unit);
+        if let Some(exit_status) = detached.and_then(|detached| detached.exit_status) {
+            forward_output();
+            std::process::exit(exit_status);
         }
-    }
-
-    (filesystems, swaps)
-}
-
-// Returns a HashMap containing the same contents as the passed in `units`, minus the units in
-// `units_to_filter`.
-fn filter_units(
-    units_to_filter: &HashMap<String, ()>,
-    units: &HashMap<String, ()>,
-) -> HashMap<String, ()> {
-    let mut res = HashMap::new();
 
-    for unit in units.keys() {
-        if !units_to_filter.contains_key(unit) {
-            res.insert(unit.to_string(), ());
+        // The unit is garbage-collected once it stopped.
+        if !systemd.unit_is_active(&unit).unwrap_or(false)
+            && DetachedSwitch::read(run_dir)?
+                .is_none_or(|detached| detached.unit != unit || detached.exit_status.is_none())
+        {
+            forward_output();
+            bail!("{unit} stopped without recording how the switch ended");
         }
+
+        std::thread::sleep(DETACHED_SWITCH_POLL_INTERVAL);
     }
+}
 
-    res
+// Runs the switch in the transient service `unit` started by `detach_switch` and records how it
+// ended.
+fn do_detached_switch(unit: String) -> Result<()> {
+    let run_dir = Path::new(RUN_DIR);
+    let mut detached = DetachedSwitch {
+        version: DETACHED_SWITCH_VERSION,
+        unit,
+        action: std::env::args().nth(1).unwrap_or_default(),
+        toplevel: PathBuf::from(required_env("TOPLEVEL")?),
+        log: run_dir.join(DETACHED_SWITCH_LOG_FILE),
+        exit_status: None,
+    };
+    detached.write(run_dir)?;
+
+    let status = std::process::Command::new("/proc/self/exe")
+        .args(std::env::args_os().skip(1))
+        .env_remove(DETACHED_SWITCH_UNIT_ENV)
+        .env(DETACHED_SWITCH_ENV, "1")
+        .status()
+        .context("Failed to run the detached switch")?;
+
+    // Like a shell, report death by a signal as 128 + the signal number.
+    let exit_status = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1);
+    detached.exit_status = Some(exit_status);
+    detached.write(run_dir)?;
+
+    std::process::exit(exit_status);
 }
 
-fn unit_is_active(conn: &LocalConnection, unit: &str) -> Result<bool> {
-    let unit_object_path = conn
-        .with_proxy(
-            "org.freedesktop.systemd1",
-            "/org/freedesktop/systemd1",
-            Duration::from_millis(5000),
+// Prints the state of an interrupted or running switch for the `status` action.
+fn print_switch_status(run_dir: &Path, in_progress: bool, json: bool) -> Result<()> {
+    let journal = SwitchJournal::read(run_dir)?;
+    let reboot_required = RebootRequired::read(run_dir)?;
+    let detached = DetachedSwitch::read(run_dir)?;
+
+    if json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(
+            &mut stdout,
+            &serde_json::json!({
+                "in-progress": in_progress,
+                "journal": journal,
+                "reboot-required": reboot_required,
+                "detached-switch": detached,
+            }),
         )
-        .get_unit(unit)
-        .with_context(|| format!("Failed to get unit {unit}"))?;
+        .context("Failed to serialize switch status")?;
+        writeln!(&mut stdout).context("Failed to write switch status")?;
+        return Ok(());
+    }
 
-    let active_state: String = conn
-        .with_proxy(
-            "org.freedesktop.systemd1",
-            unit_object_path,
-            Duration::from_millis(5000),
-        )
-        .get("org.freedesktop.systemd1.Unit", "ActiveState")
-        .with_context(|| format!("Failed to get ExecMainStatus for {unit}"))?;
-
-    Ok(matches!(active_state.as_str(), "active" | "activating"))
-}
+    if let Some(detached) = &detached {
+        let result = match detached.exit_status {
+            Some(0) => "succeeded".to_string(),
+            Some(exit_status) => format!("failed with status {exit_status}"),
+            None => "is still running".to_string(),
+        };
+        eprintln!(
+            "the {} to {} that continued in {} {result}, its output is in {}",
+            detached.action,
+            detached.toplevel.display(),
+            detached.unit,
+            detached.log.display()
+        );
+    }
 
-static ACTION: OnceLock<Action> = OnceLock::new();
+    if let Some(reboot_required) = &reboot_required {
+        eprintln!(
+            "a reboot is required to use the {} of {}",
+            component_names(&reboot_required.components),
+            reboot_required.toplevel.display()
+        );
+    }
 
-#[derive(Debug)]
-enum Job {
-    Start,
-    Restart,
-    Reload,
-    Stop,
-}
+    let Some(journal) = journal else {
+        if in_progress {
+            eprintln!("a switch is in progress");
+        } else {
+            eprintln!("there is no interrupted switch");
+        }
+        return Ok(());
+    };
 
-impl std::fmt::Display for Job {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Job::Start => "start",
-                Job::Restart => "restart",
-                Job::Reload => "reload",
-                Job::Stop => "stop",
+    eprintln!(
+        "{} to {} {}",
+        journal.action,
+        journal.toplevel.display(),
+        if in_progress {
+            "is in progress"
+        } else {
+            "was interrupted"
+        }
+    );
+    eprintln!("phase: {}", journal.phase);
+    for status in [JobStatus::Done, JobStatus::Pending] {
+        for job in [
+            Job::Stop,
+            Job::Reload,
+            Job::ReloadOrRestart,
+            Job::Restart,
+            Job::TryRestart,
+            Job::Start,
+        ] {
+            let units = journal
+                .units
+                .iter()
+                .filter(|entry| entry.job == job && entry.status == status)
+                .map(|entry| entry.unit.as_str())
+                .collect::<Vec<_>>();
+            if !units.is_empty() {
+                let status = match status {
+                    JobStatus::Done => "done",
+                    JobStatus::Pending => "pending",
+                };
+                eprintln!("{status} {job}: {}", units.join(", "));
             }
-        )
+        }
+
+        let signals = journal
+            .signals
+            .iter()
+            .filter(|entry| entry.status == status)
+            .map(|entry| format!("{} ({})", entry.unit, entry.signal))
+            .collect::<Vec<_>>();
+        if !signals.is_empty() {
+            let status = match status {
+                JobStatus::Done => "done",
+                JobStatus::Pending => "pending",
+            };
+            eprintln!("{status} signal: {}", signals.join(", "));
+        }
+    }
+    if !journal.deferred.is_empty() {
+        eprintln!(
+            "deferred: {}",
+            journal
+                .deferred
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if !in_progress {
+        eprintln!(
+            "run {}/bin/switch-to-configuration resume to continue it",
+            journal.toplevel.display()
+        );
     }
-}
 
-fn systemd1_proxy(conn: &LocalConnection) -> Proxy<'_, &LocalConnection> {
-    conn.with_proxy(
-        "org.freedesktop.systemd1",
-        "/org/freedesktop/systemd1",
-        BUS_TIMEOUT,
-    )
+    Ok(())
 }
 
-fn login1_proxy(conn: &LocalConnection) -> Proxy<'_, &LocalConnection> {
-    conn.with_proxy(
-        "org.freedesktop.login1",
-        "/org/freedesktop/login1",
-        BUS_TIMEOUT,
-    )
+// A switch as recorded in `SWITCH_HISTORY_FILE`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SwitchRecord {
+    version: u32,
+    // When the switch started and finished, in UTC.
+    started: String,
+    finished: String,
+    duration_ms: u64,
+    action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_toplevel: Option<PathBuf>,
+    toplevel: PathBuf,
+    units_stopped: Vec<String>,
+    units_started: Vec<String>,
+    // Including units that were only restarted if they were running.
+    units_restarted: Vec<String>,
+    // Including units that were reloaded or restarted.
+    units_reloaded: Vec<String>,
+    failed_units: Vec<String>,
+    restart_systemd: bool,
+    exit_code: i32,
 }
 
-fn block_on_jobs(
-    conn: &LocalConnection,
-    submitted_jobs: &Rc<RefCell<HashMap<dbus::Path<'static>, Job>>>,
-) {
-    while !submitted_jobs.borrow().is_empty() {
-        log::debug!(
-            "waiting for submitted jobs to finish, still have {} job(s)",
-            submitted_jobs.borrow().len()
-        );
-        _ = conn.process(DBUS_PROCESS_TIME);
+impl SwitchRecord {
+    // Appends the record to the history at `path`, rotating it first if it grew too large. Appends
+    // of a single line are atomic, so concurrent switches (like a rollback) don't garble the file.
+    fn append(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        if std::fs::metadata(path).is_ok_and(|metadata| metadata.len() >= SWITCH_HISTORY_MAX_SIZE) {
+            let rotated = rotated_history(path);
+            std::fs::rename(path, &rotated).with_context(|| {
+                format!(
+                    "Failed to rename {} to {}",
+                    path.display(),
+                    rotated.display()
+                )
+            })?;
+        }
+
+        let mut line = serde_json::to_vec(self).context("Failed to serialize switch record")?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("Failed to append to {}", path.display()))
     }
 }
 
-fn remove_file_if_exists(p: impl AsRef<Path>) -> std::io::Result<()> {
-    match std::fs::remove_file(p) {
-        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
-        _ => Ok(()),
-    }
+// What is known about a switch when it starts. It is turned into a `SwitchRecord` once the switch
+// ended.
+struct SwitchStart {
+    time: SystemTime,
+    action: String,
+    previous_toplevel: Option<PathBuf>,
+    toplevel: PathBuf,
 }
 
-/// Performs switch-to-configuration functionality for a single non-root user
-fn do_user_switch(parent_exe: String) -> anyhow::Result<()> {
-    if Path::new(&parent_exe)
-        != Path::new("/proc/self/exe")
-            .canonicalize()
-            .context("Failed to get full path to current executable")?
-            .as_path()
-    {
-        eprintln!(
-            r#"This program is not meant to be called from outside of switch-to-configuration."#
-        );
-        die();
+impl SwitchStart {
+    fn new(action: &Action, toplevel: &Path) -> Self {
+        Self {
+            time: SystemTime::now(),
+            action: Into::<&'static str>::into(action).to_string(),
+            previous_toplevel: Path::new("/run/current-system").canonicalize().ok(),
+            toplevel: toplevel.to_path_buf(),
+        }
     }
 
-    let dbus_conn = LocalConnection::new_session().context("Failed to open dbus connection")?;
-    let systemd = systemd1_proxy(&dbus_conn);
-
-    let nixos_activation_done = Rc::new(RefCell::new(false));
-    let _nixos_activation_done = nixos_activation_done.clone();
-    let jobs_token = systemd
-        .match_signal(
-            move |signal: OrgFreedesktopSystemd1ManagerJobRemoved,
-                  _: &LocalConnection,
-                  _: &Message| {
-                if signal.unit.as_str() == "nixos-activation.service" {
-                    *_nixos_activation_done.borrow_mut() = true;
-                }
-
-                true
-            },
-        )
-        .context("Failed to add signal match for systemd removed jobs")?;
-
-    // The systemd user session seems to not send a Reloaded signal, so we don't have anything to
-    // wait on here.
-    _ = systemd.reexecute();
-
-    systemd
-        .restart_unit("nixos-activation.service", "replace")
-        .context("Failed to restart nixos-activation.service")?;
+    // Builds the record of the switch. The units are taken from the journal, which also contains
+    // the units requested by the activation script and, when resuming, the jobs that ran before
+    // the switch was interrupted.
+    fn finish(
+        &self,
+        journal: Option<&SwitchJournal>,
+        failed_units: &[String],
+        exit_code: i32,
+    ) -> SwitchRecord {
+        let finished = SystemTime::now();
+        let units = |jobs: &[Job]| {
+            let mut units = journal
+                .into_iter()
+                .flat_map(|journal| &journal.units)
+                .filter(|entry| jobs.contains(&entry.job))
+                .map(|entry| entry.unit.clone())
+                .collect::<Vec<_>>();
+            units.sort_by_key(|name| name.to_lowercase());
+            units.dedup();
+            units
+        };
 
-    log::debug!("waiting for nixos activation to finish");
-    while !*nixos_activation_done.borrow() {
-        _ = dbus_conn
-            .process(DBUS_PROCESS_TIME)
-            .context("Failed to process dbus messages")?;
+        SwitchRecord {
+            version: SWITCH_HISTORY_VERSION,
+            started: format_timestamp(self.time),
+            finished: format_timestamp(finished),
+            duration_ms: finished
+                .duration_since(self.time)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            action: self.action.clone(),
+            previous_toplevel: journal
+                .and_then(|journal| journal.previous_toplevel.clone())
+                .or_else(|| self.previous_toplevel.clone()),
+            toplevel: self.toplevel.clone(),
+            units_stopped: units(&[Job::Stop]),
+            units_started: units(&[Job::Start]),
+            units_restarted: units(&[Job::Restart, Job::TryRestart]),
+            units_reloaded: units(&[Job::Reload, Job::ReloadOrRestart]),
+            failed_units: failed_units.to_vec(),
+            restart_systemd: journal.is_some_and(|journal| journal.restart_systemd),
+            exit_code,
+        }
     }
-
-    dbus_conn
-        .remove_match(jobs_token)
-        .context("Failed to remove jobs token")?;
-
-    Ok(())
 }
 
-fn usage(argv0: &str) -> ! {
-    eprintln!(
-        r#"Usage: {argv0} [check|switch|boot|test|dry-activate]
-check:        run pre-switch checks and exit
-switch:       make the configuration the boot default and activate now
-boot:         make the configuration the boot default
-test:         activate the configuration, but don't make it the boot default
-dry-activate: show what would be done if this configuration were activated
-"#
-    );
-    std::process::exit(1);
+// Records a switch in the history and publishes its end on D-Bus however it ends. A switch that
+// ends normally does so with `finish` or `exit`. If it returns an error or panics instead, the
+// guard does it when it is dropped, with the jobs of the journal that the switch left behind.
+struct SwitchGuard {
+    start: SwitchStart,
+    history: PathBuf,
+    progress: Option<SwitchProgress>,
+    run_dir: PathBuf,
+    // Whether the journal in `run_dir` belongs to this switch.
+    journal_written: bool,
+    finished: bool,
 }
 
-/// Performs switch-to-configuration functionality for the entire system
-fn do_system_switch(action: Action) -> anyhow::Result<()> {
-    log::debug!("Performing system switch");
+impl SwitchGuard {
+    fn new(action: &Action, toplevel: &Path, run_dir: &Path) -> Self {
+        Self {
+            start: SwitchStart::new(action, toplevel),
+            history: PathBuf::from(SWITCH_HISTORY_FILE),
+            progress: None,
+            run_dir: run_dir.to_path_buf(),
+            journal_written: false,
+            // Dry runs are not recorded.
+            finished: *action == Action::DryActivate,
+        }
+    }
 
-    let out = PathBuf::from(required_env("OUT")?);
-    let toplevel = PathBuf::from(required_env("TOPLEVEL")?);
-    let distro_id = required_env("DISTRO_ID")?;
-    let pre_switch_check = required_env("PRE_SWITCH_CHECK")?;
-    let install_bootloader = required_env("INSTALL_BOOTLOADER")?;
-    let locale_archive = required_env("LOCALE_ARCHIVE")?;
-    let new_systemd = PathBuf::from(required_env("SYSTEMD")?);
-    let log_level = if std::env::var("STC_DEBUG").is_ok() {
-        LevelFilter::Debug
-    } else {
-        LevelFilter::Info
-    };
+    // Appends the record of the switch to the history and publishes that the switch finished,
+    // unless this was done already. Not being able to do so doesn't fail the switch.
+    fn finish(&mut self, journal: Option<&SwitchJournal>, failed_units: &[String], exit_code: i32) {
+        if std::mem::replace(&mut self.finished, true) {
+            return;
+        }
 
-    let action = ACTION.get_or_init(|| action);
-    log::debug!("Using action {:?}", action);
+        let record = self.start.finish(journal, failed_units, exit_code);
+        if let Err(err) = record.append(&self.history) {
+            eprintln!("warning: unable to record the switch in the history: {err:#}");
+        }
+        if let Some(progress) = &self.progress {
+            progress.finish(exit_code, failed_units);
+        }
+    }
 
-    // The action that is to be performed (like switch, boot, test, dry-activate) Also exposed via
-    // environment variable from now on
-    std::env::set_var("NIXOS_ACTION", Into::<&'static str>::into(action));
+    // Records the switch and exits with `exit_code`.
+    fn exit(mut self, journal: Option<&SwitchJournal>, exit_code: i32) -> ! {
+        self.finish(journal, &[], exit_code);
+        std::process::exit(exit_code);
+    }
 
-    // Expose the locale archive as an environment variable for systemctl and the activation script
-    if !locale_archive.is_empty() {
-        std::env::set_var("LOCALE_ARCHIVE", locale_archive);
+    // Like `die`, but records the switch first.
+    fn die(mut self) -> ! {
+        let exit_code = errno_exit_code();
+        let journal = self.journal();
+        self.finish(journal.as_ref(), &[], exit_code);
+        std::process::exit(exit_code);
     }
 
-    let os_release = parse_os_release().context("Failed to parse os-release")?;
+    // Stops the guard from recording the switch because another process continues it.
+    fn disarm(&mut self) {
+        self.finished = true;
+    }
 
-    let distro_id_re = Regex::new(format!("^\"?{distro_id}\"?$").as_str())
-        .context("Invalid regex for distro ID")?;
+    fn journal(&self) -> Option<SwitchJournal> {
+        if self.journal_written {
+            SwitchJournal::read(&self.run_dir).ok().flatten()
+        } else {
+            None
+        }
+    }
+}
 
-    // This is a NixOS installation if it has /etc/NIXOS or a proper /etc/os-release.
-    if !Path::new("/etc/NIXOS").is_file()
-        && !os_release
-            .get("ID")
-            .map(|id| distro_id_re.is_match(id))
-            .unwrap_or_default()
-    {
-        eprintln!("This is not a NixOS installation!");
-        die();
+impl Drop for SwitchGuard {
+    fn drop(&mut self) {
+        // The exit status of the process when main returns an error or panics.
+        let exit_code = if std::thread::panicking() { 101 } else { 1 };
+        let journal = self.journal();
+        self.finish(journal.as_ref(), &[], exit_code);
     }
+}
 
-    std::fs::create_dir_all("/run/nixos").context("Failed to create /run/nixos directory")?;
-    let perms = std::fs::Permissions::from_mode(0o755);
-    std::fs::set_permissions("/run/nixos", perms)
-        .context("Failed to set permissions on /run/nixos directory")?;
+// The file that the switch history at `path` is rotated to.
+fn rotated_history(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
 
-    log::debug!("Creating lock file /run/nixos/switch-to-configuration.lock");
-    let Ok(lock) = std::fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open("/run/nixos/switch-to-configuration.lock")
-    else {
-        eprintln!("Could not open lock");
-        die();
-    };
+// Formats a point in time as an RFC 3339 timestamp in UTC, like 2024-05-01T12:34:56Z.
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // The civil date of a day since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
 
-    log::debug!("Acquiring lock on file /run/nixos/switch-to-configuration.lock");
-    let Ok(_lock) = Flock::lock(lock, FlockArg::LockExclusiveNonblock) else {
-        eprintln!("Could not acquire lock");
-        die();
+// Reads the records of the switch history at `path` and of the history it was rotated to, oldest
+// first. Lines that can't be read, like the last line of a switch that was cut off, and records of
+// another version are skipped with a warning.
+fn read_switch_history(path: &Path) -> Result<Vec<SwitchRecord>> {
+    let mut records = Vec::new();
+    for path in [rotated_history(path).as_path(), path] {
+        read_switch_history_file(path, &mut records)?;
+    }
+
+    Ok(records)
+}
+
+fn read_switch_history_file(path: &Path, records: &mut Vec<SwitchRecord>) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+        }
     };
 
-    if syslog::init(Facility::LOG_USER, log_level, Some("nixos")).is_err() {
-        bail!("Failed to initialize logger");
-    }
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
 
-    if std::env::var("NIXOS_NO_CHECK")
-        .as_deref()
-        .unwrap_or_default()
-        != "1"
-    {
-        do_pre_switch_check(&pre_switch_check, &toplevel, action)?;
-        log::debug!("Done performing pre-switch checks");
+        match serde_json::from_str::<SwitchRecord>(line) {
+            Ok(record) if record.version == SWITCH_HISTORY_VERSION => records.push(record),
+            Ok(record) => eprintln!(
+                "warning: ignoring line {} of {} with unknown version {}",
+                number + 1,
+                path.display(),
+                record.version
+            ),
+            Err(err) => eprintln!(
+                "warning: ignoring line {} of {}: {err}",
+                number + 1,
+                path.display()
+            ),
+        }
     }
 
-    if *action == Action::Check {
-        return Ok(());
-    }
+    Ok(())
+}
 
-    // Install or update the bootloader.
-    if matches!(action, Action::Switch | Action::Boot) {
-        do_install_bootloader(&install_bootloader, &toplevel)?;
-        log::debug!("Done performing bootloader installation");
+// Shows the switch history for the `history` action, limited to the last `count` switches.
+fn print_switch_history(path: &Path, count: Option<usize>, json: bool) -> Result<()> {
+    let mut records = read_switch_history(path)?;
+    if let Some(count) = count {
+        records.drain(..records.len().saturating_sub(count));
     }
 
-    // Just in case the new configuration hangs the system, do a sync now.
-    if std::env::var("NIXOS_NO_SYNC")
-        .as_deref()
-        .unwrap_or_default()
-        != "1"
-    {
-        let fd = nix::fcntl::open("/nix/store", OFlag::O_NOCTTY, Mode::S_IROTH)
-            .context("Failed to open /nix/store")?;
-        nix::unistd::syncfs(fd).context("Failed to sync /nix/store")?;
+    if json {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &records)
+            .context("Failed to serialize switch history")?;
+        writeln!(&mut stdout).context("Failed to write switch history")?;
+        return Ok(());
     }
 
-    if *action == Action::Boot {
-        std::process::exit(0);
+    if records.is_empty() {
+        eprintln!("there are no recorded switches");
+        return Ok(());
     }
 
-    let current_init_interface_version =
-        std::fs::read_to_string("/run/current-system/init-interface-version").unwrap_or_default();
-
-    let new_init_interface_version =
-        std::fs::read_to_string(toplevel.join("init-interface-version"))
-            .context("File init-interface-version should exist")?;
-
-    // Check if we can activate the new configuration.
-    if current_init_interface_version != new_init_interface_version {
-        eprintln!(
-            r#"Warning: the new NixOS configuration has an ‘init’ that is
-incompatible with the current configuration.  The new configuration
-won't take effect until you reboot the system.
-"#
+    for record in &records {
+        println!(
+            "{} {} to {}{}: exit status {} after {:.1}s",
+            record.started,
+            record.action,
+            record.toplevel.display(),
+            record
+                .previous_toplevel
+                .as_ref()
+                .map(|previous| format!(" from {}", previous.display()))
+                .unwrap_or_default(),
+            record.exit_code,
+            record.duration_ms as f64 / 1000.0
         );
-        std::process::exit(100);
+        if record.restart_systemd {
+            println!("  systemd was re-executed");
+        }
+        for (what, units) in [
+            ("stopped", &record.units_stopped),
+            ("started", &record.units_started),
+            ("restarted", &record.units_restarted),
+            ("reloaded", &record.units_reloaded),
+            ("failed", &record.failed_units),
+        ] {
+            if !units.is_empty() {
+                println!("  {what}: {}", units.join(", "));
+            }
+        }
     }
 
-    // Ignore SIGHUP so that we're not killed if we're running on (say) virtual console 1 and we
-    // restart the "tty1" unit.
-    let handler = SigHandler::Handler(handle_sigpipe);
-    unsafe { signal::signal(Signal::SIGPIPE, handler) }.context("Failed to set SIGPIPE handler")?;
+    Ok(())
+}
 
-    let mut units_to_stop = HashMap::new();
-    let mut units_to_skip = HashMap::new();
-    let mut units_to_filter = HashMap::new(); // units not shown
+// Returns the unit names of a set in the order they are shown to the user.
+fn sorted_units(units: &HashMap<String, ()>) -> Vec<String> {
+    let mut units = units.keys().cloned().collect::<Vec<String>>();
+    units.sort_by_key(|name| name.to_lowercase());
+    units
+}
 
-    let mut units_to_start = map_from_list_file(START_LIST_FILE);
-    let mut units_to_restart = map_from_list_file(RESTART_LIST_FILE);
-    let mut units_to_reload = map_from_list_file(RELOAD_LIST_FILE);
+fn serialize_units<S: serde::Serializer>(
+    units: &HashMap<String, ()>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(sorted_units(units))
+}
 
-    let dbus_conn = LocalConnection::new_system().context("Failed to open dbus connection")?;
-    let systemd = systemd1_proxy(&dbus_conn);
-    let logind = login1_proxy(&dbus_conn);
+fn serialize_signals<S: serde::Serializer>(
+    units: &BTreeMap<String, Signal>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        units
+            .iter()
+            .map(|(unit, signal)| (unit.as_str(), signal.as_str())),
+    )
+}
 
-    let submitted_jobs = Rc::new(RefCell::new(HashMap::new()));
-    let finished_jobs = Rc::new(RefCell::new(HashMap::new()));
+// Allow for this switch-to-configuration to remain consistent with the perl implementation.
+// Perl's "die" uses errno to set the exit code: https://perldoc.perl.org/perlvar#%24%21
+fn die() -> ! {
+    std::process::exit(errno_exit_code());
+}
 
-    let systemd_is_reloading = Rc::new(RefCell::new(false));
+// The exit code used by `die`.
+fn errno_exit_code() -> i32 {
+    match std::io::Error::last_os_error().raw_os_error().unwrap_or(1) {
+        // Ensure that even if errno did not point to a helpful error code, we still have a
+        // non-zero exit code
+        0 => 1,
+        other => other,
+    }
+}
 
-    systemd
-        .subscribe()
-        .context("Failed to subscribe to systemd dbus messages")?;
+fn parse_os_release() -> Result<HashMap<String, String>> {
+    Ok(std::fs::read_to_string("/etc/os-release")
+        .context("Failed to read /etc/os-release")?
+        .lines()
+        .fold(HashMap::new(), |mut acc, line| {
+            if let Some((k, v)) = line.split_once('=') {
+                acc.insert(k.to_string(), v.to_string());
+            }
+
+            acc
+        }))
+}
+
+fn do_pre_switch_check(command: &str, toplevel: &Path, action: &Action) -> Result<()> {
+    let mut cmd_split = command.split_whitespace();
+    let Some(argv0) = cmd_split.next() else {
+        bail!("missing first argument in pre-switch check");
+    };
+
+    match std::process::Command::new(argv0)
+        .args(cmd_split.collect::<Vec<&str>>())
+        .arg(toplevel)
+        .arg::<&str>(action.into())
+        .spawn()
+        .map(|mut child| child.wait())
+    {
+        Ok(Ok(status)) if status.success() => {}
+        _ => {
+            eprintln!("Pre-switch checks failed");
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn do_install_bootloader(
+    command: &str,
+    toplevel: &Path,
+    specialisation: Option<&str>,
+) -> Result<()> {
+    let mut cmd_split = command.split_whitespace();
+    let Some(argv0) = cmd_split.next() else {
+        bail!("missing first argument in install bootloader commands");
+    };
+
+    let mut install_bootloader = std::process::Command::new(argv0);
+    install_bootloader
+        .args(cmd_split.collect::<Vec<&str>>())
+        .arg(toplevel);
+    match specialisation {
+        Some(specialisation) => install_bootloader.env(SPECIALISATION_ENV, specialisation),
+        None => install_bootloader.env_remove(SPECIALISATION_ENV),
+    };
+
+    match install_bootloader.spawn().map(|mut child| child.wait()) {
+        Ok(Ok(status)) if status.success() => {}
+        _ => {
+            eprintln!("Failed to install bootloader");
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the toplevel of the specialisation `name` of `toplevel` after making sure that it is a
+// configuration that can be switched to.
+fn resolve_specialisation(toplevel: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("invalid specialisation name {name}");
+    }
+
+    let specialisation = toplevel.join("specialisation").join(name);
+    if !specialisation.is_dir() {
+        let mut available = std::fs::read_dir(toplevel.join("specialisation"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        available.sort();
+        bail!(
+            "{} has no specialisation {name}, available specialisations: {}",
+            toplevel.display(),
+            if available.is_empty() {
+                "(none)".to_string()
+            } else {
+                available.join(", ")
+            }
+        );
+    }
+
+    for file in ["nixos-version", "init-interface-version"] {
+        let contents = std::fs::read_to_string(specialisation.join(file)).with_context(|| {
+            format!("Specialisation {name} is not a NixOS configuration, failed to read {file}")
+        })?;
+        if contents.trim().is_empty() {
+            bail!("specialisation {name} has an empty {file}");
+        }
+    }
+
+    if !specialisation.join("bin/switch-to-configuration").exists() {
+        bail!("specialisation {name} can't be switched to");
+    }
+
+    Ok(specialisation)
+}
+
+// Whether the bootloader with the given `system.boot.loader.id` can make a specialisation the
+// default boot entry.
+fn bootloader_selects_specialisation(bootloader: &str) -> bool {
+    SPECIALISATION_BOOTLOADERS.contains(&bootloader)
+}
+
+// Switches to the specialisation `name` by running its own switch-to-configuration with the same
+// arguments. Once `switch` and `boot` installed the bootloader, the specialisation's
+// switch-to-configuration records it so that later `boot`s keep making it the default boot entry.
+fn switch_to_specialisation(toplevel: &Path, name: &str, action: &Action) -> Result<()> {
+    if !matches!(
+        action,
+        Action::Switch
+            | Action::Boot
+            | Action::Test
+            | Action::DryActivate
+            | Action::Check
+            | Action::SoftReboot
+    ) {
+        bail!(
+            "--specialisation can't be used with {}",
+            Into::<&'static str>::into(action)
+        );
+    }
+
+    let specialisation = resolve_specialisation(toplevel, name)?;
+
+    let bootloader = std::env::var("BOOTLOADER_ID").unwrap_or_default();
+    if !bootloader_selects_specialisation(&bootloader) {
+        let bootloader = if bootloader.is_empty() {
+            "this bootloader".to_string()
+        } else {
+            bootloader
+        };
+        match action {
+            Action::Boot => bail!(
+                "{bootloader} can't make specialisation {name} the default boot entry, only {} can",
+                SPECIALISATION_BOOTLOADERS.join(", ")
+            ),
+            Action::Switch | Action::SoftReboot => eprintln!(
+                "warning: {bootloader} can't make specialisation {name} the default boot entry, {} stays the default",
+                toplevel.display()
+            ),
+            _ => {}
+        }
+    }
+
+    // Everything but the specialisation itself is passed on.
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(i) = args.iter().position(|arg| arg == "--specialisation") {
+        args.drain(i..(i + 2).min(args.len()));
+    }
+
+    eprintln!("switching to specialisation {name}...");
+    let err = std::process::Command::new(specialisation.join("bin/switch-to-configuration"))
+        .args(args)
+        .env(SPECIALISATION_OF_ENV, toplevel)
+        .env(SPECIALISATION_ENV, name)
+        .exec();
+
+    Err(err).with_context(|| format!("Failed to run switch-to-configuration of {name}"))
+}
+
+// Returns the specialisation whose boot entry the bootloader installation for `toplevel` should
+// make the default. `boot` keeps using the recorded specialisation as long as the configuration
+// has it.
+fn boot_specialisation(action: &Action, toplevel: &Path) -> Option<String> {
+    if *action != Action::Boot {
+        return None;
+    }
+
+    let name = std::fs::read_to_string(SPECIALISATION_FILE)
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())?;
+
+    if resolve_specialisation(toplevel, &name).is_err() {
+        eprintln!(
+            "warning: {} has no specialisation {name} anymore, booting the configuration itself",
+            toplevel.display()
+        );
+        return None;
+    }
+
+    Some(name)
+}
+
+// Records the specialisation that the bootloader installation made the default boot entry, or
+// forgets the recorded one. Only called once the bootloader was installed.
+fn record_boot_specialisation(name: Option<&str>) -> Result<()> {
+    let path = Path::new(SPECIALISATION_FILE);
+    match name {
+        Some(name) => std::fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))
+            .and_then(|_| std::fs::write(path, name))
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None => remove_file_if_exists(path)
+            .with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+// Stages `toplevel` for the next userspace and asks systemd to soft-reboot into it. Only userspace
+// is restarted, the kernel and firmware are not involved.
+//
+// The activation script of the new configuration sets up /etc and points /run/current-system at
+// it, without touching any units. On a soft-reboot, systemd stops all units and re-executes itself
+// from /run/current-system/systemd, so the new systemd starts the units of the new configuration.
+fn do_soft_reboot(out: &Path, toplevel: &Path, run_dir: &Path) -> Result<()> {
+    let parent = std::env::var_os(SPECIALISATION_OF_ENV).map(PathBuf::from);
+    let name = std::env::var(SPECIALISATION_ENV).ok();
+    RunningSpecialisation::record(run_dir, toplevel, parent.as_deref().zip(name.as_deref()))?;
+
+    eprintln!("activating the configuration...");
+    let status = std::process::Command::new(out.join("activate"))
+        .arg(out)
+        .status()
+        .context("Failed to run activate script")?;
+    if !status.success() {
+        bail!("Failed to run activate script ({status}), not soft-rebooting");
+    }
+
+    // All units are started from scratch by the next userspace.
+    for file in [
+        RESTART_BY_ACTIVATION_LIST_FILE,
+        RELOAD_BY_ACTIVATION_LIST_FILE,
+    ] {
+        remove_file_if_exists(file).with_context(|| format!("Failed to remove {file}"))?;
+    }
+
+    let current_system = Path::new("/run/current-system")
+        .canonicalize()
+        .context("Failed to resolve /run/current-system")?;
+    if current_system
+        != toplevel
+            .canonicalize()
+            .unwrap_or_else(|_| toplevel.to_path_buf())
+    {
+        bail!(
+            "the activation script did not make {} the current system, not soft-rebooting",
+            toplevel.display()
+        );
+    }
+
+    let dbus_conn = LocalConnection::new_system().context("Failed to open dbus connection")?;
+    eprintln!("soft-rebooting into {}...", toplevel.display());
+    log::info!(
+        "soft-rebooting into system configuration {}",
+        toplevel.display()
+    );
+    systemd1_proxy(&dbus_conn)
+        .soft_reboot("")
+        .context("Failed to request soft-reboot from systemd")?;
+
+    Ok(())
+}
+
+// Activates a previous configuration again by running its own switch-to-configuration with the
+// same action. Pre-switch checks are skipped because that configuration was running before. A
+// previous configuration that was switched to as a specialisation is switched to as one again.
+fn do_rollback(
+    previous_toplevel: &Path,
+    action: &Action,
+    specialisation: Option<&RunningSpecialisation>,
+) -> Result<std::process::ExitStatus> {
+    let mut rollback =
+        std::process::Command::new(previous_toplevel.join("bin/switch-to-configuration"));
+    rollback
+        .arg::<&str>(action.into())
+        .env("NIXOS_NO_CHECK", "1")
+        // The rollback is a switch of its own with its own time limit.
+        .env_remove(SWITCH_STARTED_ENV);
+    match specialisation {
+        Some(specialisation) => rollback
+            .env(SPECIALISATION_OF_ENV, &specialisation.parent)
+            .env(SPECIALISATION_ENV, &specialisation.name),
+        None => rollback
+            .env_remove(SPECIALISATION_OF_ENV)
+            .env_remove(SPECIALISATION_ENV),
+    };
+
+    rollback
+        .spawn()
+        .map(|mut child| child.wait())
+        .with_context(|| {
+            format!(
+                "Failed to run switch-to-configuration of {}",
+                previous_toplevel.display()
+            )
+        })?
+        .context("Failed to wait for rollback")
+}
+
+extern "C" fn handle_sigpipe(_signal: nix::libc::c_int) {}
+
+fn required_env(var: &str) -> anyhow::Result<String> {
+    std::env::var(var).with_context(|| format!("missing required environment variable ${var}"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct UnitState {
+    state: String,
+    substate: String,
+    // Path of the unit file systemd loaded the unit from.
+    fragment_path: String,
+}
+
+// This function takes a single ini file that specified systemd configuration like unit
+// configuration and parses it into a HashMap where the keys are the sections of the unit file and
+// the values are HashMaps themselves. These HashMaps have the unit file keys as their keys (left
+// side of =) and an array of all values that were set as their values. If a value is empty (for
+// example `ExecStart=`), then all current definitions are removed.
+//
+// Instead of returning the HashMap, this function takes a mutable reference to a HashMap to return
+// the data in. This allows calling the function multiple times with the same Hashmap to parse
+// override files.
+fn parse_systemd_ini(data: &mut UnitInfo, mut unit_file: impl Read) -> Result<()> {
+    let mut unit_file_content = String::new();
+    _ = unit_file
+        .read_to_string(&mut unit_file_content)
+        .context("Failed to read unit file")?;
+
+    let ini = Ini::load_from_str_opt(
+        &unit_file_content,
+        ParseOption {
+            enabled_quote: true,
+            enabled_indented_mutiline_value: false,
+            enabled_preserve_key_leading_whitespace: false,
+            // Allow for escaped characters that won't get interpreted by the INI parser. These
+            // often show up in systemd unit files device/mount/swap unit names (e.g. dev-disk-by\x2dlabel-root.device).
+            enabled_escape: false,
+        },
+    )
+    .context("Failed parse unit file as INI")?;
+
+    // Copy over all sections
+    for (section, properties) in ini.iter() {
+        let Some(section) = section else {
+            continue;
+        };
+
+        if section == "Install" {
+            // Skip the [Install] section because it has no relevant keys for us
+            continue;
+        }
+
+        let section_map = if let Some(section_map) = data.get_mut(section) {
+            section_map
+        } else {
+            data.insert(section.to_string(), HashMap::new());
+            data.get_mut(section)
+                .ok_or(anyhow!("section name should exist in hashmap"))?
+        };
+
+        for (ini_key, _) in properties {
+            let values = properties.get_all(ini_key);
+            let values = values
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>();
+
+            // Go over all values
+            let mut new_vals = Vec::new();
+            let mut clear_existing = false;
+
+            for val in values {
+                // If a value is empty, it's an override that tells us to clean the value
+                if val.is_empty() {
+                    new_vals.clear();
+                    clear_existing = true;
+                } else {
+                    new_vals.push(val);
+                }
+            }
+
+            match (section_map.get_mut(ini_key), clear_existing) {
+                (Some(existing_vals), false) => existing_vals.extend(new_vals),
+                _ => _ = section_map.insert(ini_key.to_string(), new_vals),
+            };
+        }
+    }
+
+    Ok(())
+}
+
+// Directories besides the unit directory of a configuration that the system manager reads
+// drop-ins from, relative to the root of the running system. Drop-ins in the control directories
+// (written by `systemctl set-property`) take precedence over the configuration, runtime drop-ins
+// don't.
+const CONTROL_DROP_IN_DIRS: [&str; 3] = [
+    "etc/systemd/system.control",
+    "run/systemd/system.control",
+    "run/systemd/transient",
+];
+const RUNTIME_DROP_IN_DIRS: [&str; 1] = ["run/systemd/system"];
+
+// Directories besides the unit directory of a configuration that a service manager reads drop-ins
+// from, split into the ones searched before and after the unit directory.
+#[derive(Debug, Default)]
+struct DropInDirs {
+    before: Vec<PathBuf>,
+    after: Vec<PathBuf>,
+}
+
+impl DropInDirs {
+    // The directories of the system manager of the system rooted at `root`.
+    fn system(root: &Path) -> Self {
+        Self {
+            before: CONTROL_DROP_IN_DIRS.map(|dir| root.join(dir)).to_vec(),
+            after: RUNTIME_DROP_IN_DIRS.map(|dir| root.join(dir)).to_vec(),
+        }
+    }
+
+    // The directories of the user manager of the user whose environment variables `env` looks
+    // up. Besides the control and runtime directories, the user's own configuration in
+    // `~/.config/systemd/user` takes precedence over /etc/systemd/user.
+    fn user(env: impl Fn(&str) -> Option<OsString>) -> Self {
+        let config_home = env("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env("HOME").map(|home| Path::new(&home).join(".config")));
+        let runtime_dir = env("XDG_RUNTIME_DIR").map(PathBuf::from);
+
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        if let Some(config_home) = &config_home {
+            before.push(config_home.join("systemd/user.control"));
+        }
+        if let Some(runtime_dir) = &runtime_dir {
+            before.push(runtime_dir.join("systemd/user.control"));
+            before.push(runtime_dir.join("systemd/transient"));
+        }
+        if let Some(config_home) = &config_home {
+            before.push(config_home.join("systemd/user"));
+        }
+        if let Some(runtime_dir) = &runtime_dir {
+            after.push(runtime_dir.join("systemd/user"));
+        }
+        after.push(PathBuf::from("/run/systemd/user"));
+        Self { before, after }
+    }
+}
+
+// Returns the names of the drop-in directories of `unit` in the order systemd searches them: the
+// unit itself, its template, the prefixes of its name up to each dash (`foo-bar-.service.d` and
+// `foo-.service.d` for `foo-bar-baz.service`). The top-level drop-in directory of the unit type
+// (`service.d`) is not included since it is searched last in each directory.
+fn drop_in_dir_names(unit: &str, names: &mut Vec<String>) {
+    let name = format!("{unit}.d");
+    if !names.contains(&name) {
+        names.push(name);
+    }
+
+    let Some((prefix, unit_type)) = unit.rsplit_once('.') else {
+        return;
+    };
+
+    let prefix = match prefix.split_once('@') {
+        Some((template_prefix, instance)) => {
+            if !instance.is_empty() {
+                drop_in_dir_names(&format!("{template_prefix}@.{unit_type}"), names);
+            }
+            template_prefix
+        }
+        None => prefix,
+    };
+
+    // A trailing dash means that `unit` already is a prefix, so the next shorter one is used.
+    let prefix = prefix.strip_suffix('-').unwrap_or(prefix);
+    if let Some(dash) = prefix.rfind('-').filter(|dash| *dash > 0) {
+        drop_in_dir_names(&format!("{}.{unit_type}", &prefix[..=dash]), names);
+    }
+}
+
+// This function takes the path to a systemd configuration file (like a unit configuration) and
+// parses it into a UnitInfo structure.
+//
+// Drop-ins are looked up the same way systemd does it. The drop-in directories of the unit (see
+// `drop_in_dir_names`) are searched in the directory of `unit_file` and, if `drop_in_dirs` is
+// given, in the directories of the running service manager. Each of these directories is searched
+// completely, ending with the top-level drop-in directory of the unit type, before the next one.
+// A drop-in masks drop-ins with the same file name in directories that are searched later, the
+// remaining ones are applied in the lexical order of their file names.
+fn parse_unit(
+    unit_file: &Path,
+    base_unit_path: &Path,
+    drop_in_dirs: Option<&DropInDirs>,
+) -> Result<UnitInfo> {
+    // Parse the main unit and all overrides
+    let mut unit_data = HashMap::new();
+
+    let base_unit_file = std::fs::File::open(base_unit_path)
+        .with_context(|| format!("Failed to open unit file {}", base_unit_path.display()))?;
+    parse_systemd_ini(&mut unit_data, base_unit_file).with_context(|| {
+        format!(
+            "Failed to parse systemd unit file {}",
+            base_unit_path.display()
+        )
+    })?;
+
+    let (Some(unit_dir), Some(unit)) = (
+        unit_file.parent(),
+        unit_file.file_name().map(|name| name.to_string_lossy()),
+    ) else {
+        return Ok(unit_data);
+    };
+
+    let mut search_path = Vec::new();
+    if let Some(drop_in_dirs) = drop_in_dirs {
+        search_path.extend(drop_in_dirs.before.iter().cloned());
+    }
+    search_path.push(unit_dir.to_path_buf());
+    if let Some(drop_in_dirs) = drop_in_dirs {
+        search_path.extend(drop_in_dirs.after.iter().cloned());
+    }
+
+    let mut names = Vec::new();
+    drop_in_dir_names(&unit, &mut names);
+    if let Some((_, unit_type)) = unit.rsplit_once('.') {
+        names.push(format!("{unit_type}.d"));
+    }
+    let drop_in_dirs = search_path
+        .iter()
+        .flat_map(|dir| names.iter().map(|name| dir.join(name)))
+        .collect::<Vec<_>>();
+
+    let mut drop_ins = HashMap::new();
+    for drop_in_dir in drop_in_dirs {
+        for entry in
+            glob(&format!("{}/*.conf", drop_in_dir.display())).context("Invalid glob pattern")?
+        {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let Some(file_name) = entry.file_name().map(|name| name.to_os_string()) else {
+                continue;
+            };
+            drop_ins.entry(file_name).or_insert(entry);
+        }
+    }
+
+    let mut drop_ins = drop_ins.into_iter().collect::<Vec<_>>();
+    drop_ins.sort();
+    for (_, drop_in) in drop_ins {
+        let unit_file = std::fs::File::open(&drop_in)
+            .with_context(|| format!("Failed to open unit file {}", drop_in.display()))?;
+        parse_systemd_ini(&mut unit_data, unit_file)?;
+    }
+
+    Ok(unit_data)
+}
+
+// Checks whether a specified boolean in a systemd unit is true or false, with a default that is
+// applied when the value is not set.
+fn parse_systemd_bool(
+    unit_data: Option<&UnitInfo>,
+    section_name: &str,
+    bool_name: &str,
+    default: bool,
+) -> bool {
+    if let Some(Some(Some(Some(b)))) = unit_data.map(|data| {
+        data.get(section_name).map(|section| {
+            section.get(bool_name).map(|vals| {
+                vals.last()
+                    .map(|last| matches!(last.as_str(), "1" | "yes" | "true" | "on"))
+            })
+        })
+    }) {
+        b
+    } else {
+        default
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum UnitComparison {
+    Equal,
+    UnequalNeedsRestart,
+    UnequalNeedsReload,
+}
+
+// Keys in the [Unit] section that can change without restarting the unit.
+const UNIT_SECTION_IGNORES: [&str; 13] = [
+    "X-Reload-Triggers",
+    "Description",
+    "Documentation",
+    "OnFailure",
+    "OnSuccess",
+    "OnFailureJobMode",
+    "IgnoreOnIsolate",
+    "StopWhenUnneeded",
+    "RefuseManualStart",
+    "RefuseManualStop",
+    "AllowIsolate",
+    "CollectMode",
+    "SourcePath",
+];
+
+// The rule that decided what a difference between two versions of a unit means.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DifferenceRule {
+    // A key in the [Unit] section that doesn't affect the running unit.
+    IgnoredUnitKey,
+    ReloadTriggers,
+    MountOptions,
+    Restart,
+}
+
+impl std::fmt::Display for DifferenceRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DifferenceRule::IgnoredUnitKey => "ignored",
+                DifferenceRule::ReloadTriggers => "reload, X-Reload-Triggers changed",
+                DifferenceRule::MountOptions => "reload, only mount options changed",
+                DifferenceRule::Restart => "restart",
+            }
+        )
+    }
+}
+
+// A key that differs between the current and the new version of a unit. `key` is only missing if
+// a section without any keys was added or removed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct UnitDifference {
+    section: String,
+    key: Option<String>,
+    old: Option<Vec<String>>,
+    new: Option<Vec<String>>,
+    rule: DifferenceRule,
+}
+
+impl std::fmt::Display for UnitDifference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values = |values: &Option<Vec<String>>| match values {
+            Some(values) => values.join(" "),
+            None => "(unset)".to_string(),
+        };
+
+        match &self.key {
+            Some(key) => write!(
+                f,
+                "[{}] {key}: {} -> {} ({})",
+                self.section,
+                values(&self.old),
+                values(&self.new),
+                self.rule
+            ),
+            None if self.old.is_some() => write!(f, "[{}] removed ({})", self.section, self.rule),
+            None => write!(f, "[{}] added ({})", self.section, self.rule),
+        }
+    }
+}
+
+// Describes the differences of a unit that require a restart, e.g. "changed [Service] ExecStart".
+fn restart_reason(differences: &[UnitDifference]) -> String {
+    let keys = differences
+        .iter()
+        .filter(|difference| difference.rule == DifferenceRule::Restart)
+        .map(|difference| match &difference.key {
+            Some(key) => format!("[{}] {key}", difference.section),
+            None => format!("[{}]", difference.section),
+        })
+        .collect::<Vec<_>>();
+
+    if keys.is_empty() {
+        "unit changed".to_string()
+    } else {
+        format!("changed {}", keys.join(", "))
+    }
+}
+
+// Returns all differences between two versions of a unit, sorted by section and key. Differences
+// in the [Unit] section are ignored unless the key affects the running unit, changes of
+// `X-Reload-Triggers` in the `[Unit]` section and of `Options` in the `[Mount]` section only
+// require a reload. Everything else requires a restart.
+fn diff_units(current_unit: &UnitInfo, new_unit: &UnitInfo) -> Vec<UnitDifference> {
+    let mut differences = Vec::new();
+
+    let mut sections = current_unit
+        .keys()
+        .chain(new_unit.keys())
+        .collect::<Vec<_>>();
+    sections.sort();
+    sections.dedup();
+
+    for section in sections {
+        let empty = HashMap::new();
+        let current_section = current_unit.get(section);
+        let new_section = new_unit.get(section);
+
+        if current_section.is_none() != new_section.is_none()
+            && current_section
+                .or(new_section)
+                .is_some_and(HashMap::is_empty)
+        {
+            // An empty section was added or removed
+            differences.push(UnitDifference {
+                section: section.to_string(),
+                key: None,
+                old: current_section.map(|_| Vec::new()),
+                new: new_section.map(|_| Vec::new()),
+                rule: if section == "Unit" {
+                    DifferenceRule::IgnoredUnitKey
+                } else {
+                    DifferenceRule::Restart
+                },
+            });
+            continue;
+        }
+
+        let current_section = current_section.unwrap_or(&empty);
+        let new_section = new_section.unwrap_or(&empty);
+
+        let mut keys = current_section
+            .keys()
+            .chain(new_section.keys())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        for key in keys {
+            let old = current_section.get(key);
+            let new = new_section.get(key);
+            if old == new {
+                continue;
+            }
+
+            let rule = if section == "Unit" && key == "X-Reload-Triggers" && new.is_some() {
+                DifferenceRule::ReloadTriggers
+            } else if section == "Unit" && UNIT_SECTION_IGNORES.contains(&key.as_str()) {
+                DifferenceRule::IgnoredUnitKey
+            } else if section == "Mount" && key == "Options" && old.is_some() && new.is_some() {
+                DifferenceRule::MountOptions
+            } else {
+                DifferenceRule::Restart
+            };
+
+            differences.push(UnitDifference {
+                section: section.to_string(),
+                key: Some(key.to_string()),
+                old: old.cloned(),
+                new: new.cloned(),
+                rule,
+            });
+        }
+    }
+
+    differences
+}
+
+// Decides whether a unit with the given differences (see `diff_units`) needs to be restarted or
+// reloaded. If the units differ, the service is restarted unless the only difference is
+// `X-Reload-Triggers` in the `Unit` section. If this is the only modification, the unit is
+// reloaded instead of restarted. If the only difference is `Options` in the `[Mount]` section, the
+// unit is reloaded rather than restarted.
+fn compare_differences(differences: &[UnitDifference]) -> UnitComparison {
+    if differences
+        .iter()
+        .any(|difference| difference.rule == DifferenceRule::Restart)
+    {
+        UnitComparison::UnequalNeedsRestart
+    } else if differences.iter().any(|difference| {
+        matches!(
+            difference.rule,
+            DifferenceRule::ReloadTriggers | DifferenceRule::MountOptions
+        )
+    }) {
+        UnitComparison::UnequalNeedsReload
+    } else {
+        UnitComparison::Equal
+    }
+}
+
+// Returns true if switch-to-configuration may stop the unit when it changed.
+fn unit_may_be_stopped(unit_info: Option<&UnitInfo>) -> bool {
+    parse_systemd_bool(unit_info, "Service", "X-RestartIfChanged", true)
+        && !parse_systemd_bool(unit_info, "Unit", "RefuseManualStop", false)
+        && !parse_systemd_bool(unit_info, "Unit", "X-OnlyManualStart", false)
+}
+
+// Called for socket units that changed. A socket cannot be restarted while the service it activates
+// is running (systemd refuses to start a socket whose service is already active), so the socket is
+// stopped together with its active services and only the socket is started again. The services are
+// then activated by the next connection.
+//
+// Sockets with `Accept=yes` spawn a new instance of a template service for every connection. These
+// instances only hold their own connection and keep running; stopping them would drop connections
+// such as the SSH session running this switch.
+//
+// Sockets are skipped (and reported as such) if they or any of their active services must not be
+// stopped, or if the restart was requested after units have already been stopped.
+fn handle_modified_socket(
+    new_unit_dir: &Path,
+    drop_in_dirs: &DropInDirs,
+    unit: &str,
+    base_name: &str,
+    new_unit_info: &UnitInfo,
+    use_restart_as_stop_and_start: bool,
+    active_cur: &HashMap<String, UnitState>,
+    plan: &mut SwitchPlan,
+) {
+    let services = if parse_systemd_bool(Some(new_unit_info), "Socket", "Accept", false) {
+        Vec::new()
+    } else {
+        let mut services = unit_value(new_unit_info, "Socket", "Service")
+            .map(|service| vec![service.to_string()])
+            .unwrap_or_default();
+        if services.is_empty() {
+            services.push(format!("{base_name}.service"));
+        }
+        services
+            .into_iter()
+            .filter(|service| active_cur.contains_key(service))
+            .collect()
+    };
+
+    let safe_to_restart = !use_restart_as_stop_and_start
+        && unit_may_be_stopped(Some(new_unit_info))
+        && services.iter().all(|service| {
+            let service_file = new_unit_dir.join(service);
+            unit_may_be_stopped(
+                parse_unit(&service_file, &service_file, Some(drop_in_dirs))
+                    .ok()
+                    .as_ref(),
+            )
+        });
+
+    if !safe_to_restart {
+        plan.units_to_skip.insert(unit.to_string(), ());
+        return;
+    }
+
+    for service in services {
+        plan.units_to_reload.remove(&service);
+        plan.units_to_stop.insert(service, ());
+    }
+    plan.units_to_stop.insert(unit.to_string(), ());
+    plan.units_to_start.insert(unit.to_string(), ());
+}
+
+// How a changed unit is switched, chosen with `X-SwitchMethod=` in the `[Service]` section. This
+// takes precedence over `X-ReloadIfChanged`, `X-RestartIfChanged` and `X-StopIfChanged`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SwitchMethod {
+    Restart,
+    StopStart,
+    Reload,
+    // Restart the unit only if it is still running when the restarts happen.
+    TryRestart,
+    ReloadOrRestart,
+    // Send a signal to the main process, e.g. for services that reload on SIGHUP.
+    KillSignal(Signal),
+    // Leave the unit running and record that it needs a restart.
+    Defer,
+    None,
+}
+
+impl std::str::FromStr for SwitchMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "restart" => Self::Restart,
+            "stop-start" => Self::StopStart,
+            "reload" => Self::Reload,
+            "try-restart" => Self::TryRestart,
+            "reload-or-restart" => Self::ReloadOrRestart,
+            "defer" => Self::Defer,
+            "none" => Self::None,
+            _ => match s.strip_prefix("kill-signal:") {
+                Some(signal) => Self::KillSignal(
+                    Signal::from_str(signal).with_context(|| format!("invalid signal {signal}"))?,
+                ),
+                None => bail!("invalid switch method {s}"),
+            },
+        })
+    }
+}
+
+// Adds a changed unit to the plan according to its switch method.
+fn apply_switch_method(
+    plan: &mut SwitchPlan,
+    unit: &str,
+    method: SwitchMethod,
+    use_restart_as_stop_and_start: bool,
+) {
+    let unit = unit.to_string();
+    match method {
+        SwitchMethod::Restart => {
+            plan.units_to_reload.remove(&unit);
+            plan.units_to_restart.insert(unit, ());
+        }
+        // The activation script already ran, so stopping the unit first doesn't help anymore.
+        SwitchMethod::StopStart if use_restart_as_stop_and_start => {
+            plan.units_to_reload.remove(&unit);
+            plan.units_to_restart.insert(unit, ());
+        }
+        SwitchMethod::StopStart => {
+            plan.units_to_reload.remove(&unit);
+            plan.units_to_stop.insert(unit.clone(), ());
+            plan.units_to_start.insert(unit, ());
+        }
+        SwitchMethod::Reload => {
+            if !plan.units_to_restart.contains_key(&unit) {
+                plan.units_to_reload.insert(unit, ());
+            }
+        }
+        SwitchMethod::TryRestart => {
+            plan.units_to_reload.remove(&unit);
+            plan.units_to_try_restart.insert(unit, ());
+        }
+        SwitchMethod::ReloadOrRestart => {
+            plan.units_to_reload.remove(&unit);
+            plan.units_to_reload_or_restart.insert(unit, ());
+        }
+        SwitchMethod::KillSignal(signal) => {
+            plan.units_to_signal.insert(unit, signal);
+        }
+        SwitchMethod::Defer => {
+            let reason = if use_restart_as_stop_and_start {
+                "restart requested by the activation script"
+            } else {
+                "unit changed"
+            };
+            plan.units_to_defer.insert(unit, reason.to_string());
+        }
+        SwitchMethod::None => {
+            plan.units_to_skip.insert(unit, ());
+        }
+    }
+}
+
+// Called when a unit exists in both the old systemd and the new system and the units differ. This
+// figures out of what units are to be stopped, restarted, reloaded, started, and skipped.
+fn handle_modified_unit(
+    new_unit_dir: &Path,
+    drop_in_dirs: &DropInDirs,
+    unit: &str,
+    base_name: &str,
+    new_unit_file: &Path,
+    new_base_unit_file: &Path,
+    new_unit_info: Option<&UnitInfo>,
+    active_cur: &HashMap<String, UnitState>,
+    plan: &mut SwitchPlan,
+) -> Result<()> {
+    let use_restart_as_stop_and_start = new_unit_info.is_none();
+
+    if matches!(
+        unit,
+        "sysinit.target" | "basic.target" | "multi-user.target" | "graphical.target"
+    ) || unit.ends_with(".unit")
+        || unit.ends_with(".slice")
+    {
+        // Do nothing.  These cannot be restarted directly.
+
+        // Slices and Paths don't have to be restarted since properties (resource limits and
+        // inotify watches) seem to get applied on daemon-reload.
+    } else if unit.ends_with(".mount") {
+        // Just restart the unit. We wouldn't have gotten into this subroutine if only `Options`
+        // was changed, in which case the unit would be reloaded. The only exception is / and /nix
+        // because it's very unlikely we can safely unmount them so we reload them instead. This
+        // means that we may not get all changes into the running system but it's better than
+        // crashing it.
+        if unit == "-.mount" || unit == "nix.mount" {
+            plan.units_to_reload.insert(unit.to_string(), ());
+        } else {
+            plan.units_to_restart.insert(unit.to_string(), ());
+        }
+    } else if unit.ends_with(".socket") {
+        let fallback = parse_unit(new_unit_file, new_base_unit_file, Some(drop_in_dirs))?;
+        handle_modified_socket(
+            new_unit_dir,
+            drop_in_dirs,
+            unit,
+            base_name,
+            new_unit_info.unwrap_or(&fallback),
+            use_restart_as_stop_and_start,
+            active_cur,
+            plan,
+        );
+    } else {
+        let fallback = parse_unit(new_unit_file, new_base_unit_file, Some(drop_in_dirs))?;
+        let new_unit_info = if new_unit_info.is_some() {
+            new_unit_info
+        } else {
+            Some(&fallback)
+        };
+
+        let switch_method = new_unit_info
+            .and_then(|unit_info| unit_value(unit_info, "Service", "X-SwitchMethod"))
+            .and_then(|method| match method.parse::<SwitchMethod>() {
+                Ok(method) => Some(method),
+                Err(err) => {
+                    eprintln!("warning: ignoring X-SwitchMethod={method} of {unit}: {err}");
+                    None
+                }
+            })
+            .or_else(|| {
+                parse_systemd_bool(new_unit_info, "Service", "X-RestartDeferred", false)
+                    .then_some(SwitchMethod::Defer)
+            });
+
+        if let Some(method) = switch_method {
+            apply_switch_method(plan, unit, method, use_restart_as_stop_and_start);
+        } else if parse_systemd_bool(new_unit_info, "Service", "X-ReloadIfChanged", false)
+            && !plan.units_to_restart.contains_key(unit)
+            && !(if use_restart_as_stop_and_start {
+                plan.units_to_restart.contains_key(unit)
+            } else {
+                plan.units_to_stop.contains_key(unit)
+            })
+        {
+            plan.units_to_reload.insert(unit.to_string(), ());
+        } else if !parse_systemd_bool(new_unit_info, "Service", "X-RestartIfChanged", true)
+            || parse_systemd_bool(new_unit_info, "Unit", "RefuseManualStop", false)
+            || parse_systemd_bool(new_unit_info, "Unit", "X-OnlyManualStart", false)
+        {
+            plan.units_to_skip.insert(unit.to_string(), ());
+        } else {
+            // It doesn't make sense to stop and start non-services because they can't have
+            // ExecStop=. Instances restarted in batches can't be stopped before the activation
+            // either, that would take all of them down at once.
+            if !parse_systemd_bool(new_unit_info, "Service", "X-StopIfChanged", true)
+                || !unit.ends_with(".service")
+                || (unit.contains('@') && new_unit_info.and_then(restart_batch_size).is_some())
+            {
+                // This unit should be restarted instead of stopped and started.
+                plan.units_to_restart.insert(unit.to_string(), ());
+                // Remove from units to reload so we don't restart and reload
+                plan.units_to_reload.remove(unit);
+            } else {
+                // If this unit is socket-activated, then stop the socket unit(s) as well, and
+                // restart the socket(s) instead of the service.
+                // We count as "socket-activated" any unit that doesn't declare itself not so
+                // via X-NotSocketActivated, that has any associated .socket units.
+                let mut socket_activated = false;
+                if unit.ends_with(".service") {
+                    let mut sockets = if let Some(Some(Some(sockets))) = new_unit_info.map(|info| {
+                        info.get("Service")
+                            .map(|service_section| service_section.get("Sockets"))
+                    }) {
+                        sockets
+                            .join(" ")
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect()
+                    } else {
+                        Vec::new()
+                    };
+
+                    if sockets.is_empty() {
+                        sockets.push(format!("{base_name}.socket"));
+                    }
+
+                    for socket in &sockets {
+                        if active_cur.contains_key(socket) {
+                            // We can now be sure this is a socket-activated unit
+
+                            if use_restart_as_stop_and_start {
+                                plan.units_to_restart.insert(socket.to_string(), ());
+                            } else {
+                                plan.units_to_stop.insert(socket.to_string(), ());
+                            }
+
+                            // Only restart sockets that actually exist in new configuration:
+                            if new_unit_dir.join(socket).exists() {
+                                if use_restart_as_stop_and_start {
+                                    plan.units_to_restart.insert(socket.to_string(), ());
+                                } else {
+                                    plan.units_to_start.insert(socket.to_string(), ());
+                                }
+
+                                socket_activated = true;
+                            }
+
+                            // Remove from units to reload so we don't restart and reload
+                            plan.units_to_reload.remove(unit);
+                        }
+                    }
+                }
+                if parse_systemd_bool(new_unit_info, "Service", "X-NotSocketActivated", false) {
+                    // If the unit explicitly opts out of socket
+                    // activation, restart it as if it weren't (but do
+                    // restart its sockets, that's fine):
+                    socket_activated = false;
+                }
+
+                // If the unit is not socket-activated, record that this unit needs to be started
+                // below. We write this to a file to ensure that the service gets restarted if
+                // we're interrupted.
+                if !socket_activated {
+                    if use_restart_as_stop_and_start {
+                        plan.units_to_restart.insert(unit.to_string(), ());
+                    } else {
+                        plan.units_to_start.insert(unit.to_string(), ());
+                    }
+                }
+
+                if use_restart_as_stop_and_start {
+                    plan.units_to_restart.insert(unit.to_string(), ());
+                } else {
+                    plan.units_to_stop.insert(unit.to_string(), ());
+                }
+                // Remove from units to reload so we don't restart and reload
+                plan.units_to_reload.remove(unit);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the units that an interrupted switch still needed to run a job of the given type for,
+// both from its journal and from the list file an older version may have left behind.
+fn pending_jobs(journal: &Option<SwitchJournal>, run_dir: &Path) -> SwitchPlan {
+    let mut pending = journal
+        .as_ref()
+        .map(SwitchJournal::pending_plan)
+        .unwrap_or_default();
+    for (units, legacy_list_file) in [
+        (&mut pending.units_to_start, LEGACY_START_LIST_FILE),
+        (&mut pending.units_to_restart, LEGACY_RESTART_LIST_FILE),
+        (&mut pending.units_to_reload, LEGACY_RELOAD_LIST_FILE),
+    ] {
+        units.extend(map_from_list_file(run_dir.join(legacy_list_file)));
+    }
+
+    pending
+}
+
+fn map_from_list_file(p: impl AsRef<Path>) -> HashMap<String, ()> {
+    std::fs::read_to_string(p)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .fold(HashMap::new(), |mut acc, line| {
+            acc.insert(line.to_string(), ());
+            acc
+        })
+}
+
+// Flags for swapon(2), see linux/swap.h
+const SWAP_FLAG_PREFER: i32 = 0x8000;
+const SWAP_FLAG_PRIO_MASK: i32 = 0x7fff;
+const SWAP_FLAG_DISCARD: i32 = 0x10000;
+const SWAP_FLAG_DISCARD_ONCE: i32 = 0x20000;
+const SWAP_FLAG_DISCARD_PAGES: i32 = 0x40000;
+
+// Translates the fstab options of a swap device into flags for swapon(2) the same way swapon(8)
+// does. Options that only matter at boot (like `nofail`) don't have a flag.
+fn swap_flags(options: &str) -> i32 {
+    let mut flags = 0;
+
+    for option in options.split(',') {
+        match option.split_once('=') {
+            Some(("pri", prio)) => {
+                if let Ok(prio) = prio.parse::<i32>() {
+                    if prio >= 0 {
+                        flags |= SWAP_FLAG_PREFER | (prio & SWAP_FLAG_PRIO_MASK);
+                    }
+                }
+            }
+            Some(("discard", "once")) => flags |= SWAP_FLAG_DISCARD | SWAP_FLAG_DISCARD_ONCE,
+            Some(("discard", "pages")) => flags |= SWAP_FLAG_DISCARD | SWAP_FLAG_DISCARD_PAGES,
+            None if option == "discard" => flags |= SWAP_FLAG_DISCARD,
+            _ => {}
+        }
+    }
+
+    flags
+}
+
+fn swap_off(device: &str) -> std::io::Result<()> {
+    let c_device = std::ffi::CString::new(device)?;
+    if unsafe { nix::libc::swapoff(c_device.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn swap_on(device: &str, flags: i32) -> std::io::Result<()> {
+    let c_device = std::ffi::CString::new(device)?;
+    if unsafe { nix::libc::swapon(c_device.as_ptr(), flags) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Returns the last value of `key` in `section` of a unit.
+fn unit_value<'a>(unit_info: &'a UnitInfo, section: &str, key: &str) -> Option<&'a str> {
+    unit_info
+        .get(section)
+        .and_then(|section| section.get(key))
+        .and_then(|values| values.last())
+        .map(String::as_str)
+}
+
+// Returns the options of a swap unit in fstab syntax. Older versions of systemd-fstab-generator
+// turn `pri=` into a separate `Priority=` setting.
+fn swap_options(unit_info: &UnitInfo) -> String {
+    let mut options = unit_value(unit_info, "Swap", "Options")
+        .unwrap_or("defaults")
+        .to_string();
+    if let Some(priority) = unit_value(unit_info, "Swap", "Priority") {
+        options.push_str(&format!(",pri={priority}"));
+    }
+
+    options
+}
+
+// Reads the mount, automount and swap units that systemd-fstab-generator created from `fstab`
+// into `generator_dir`. Units written by other generators are ignored.
+fn read_fstab_units(generator_dir: &Path, fstab: &Path) -> Result<HashMap<String, UnitInfo>> {
+    let mut units = HashMap::new();
+
+    let Ok(entries) = std::fs::read_dir(generator_dir) else {
+        return Ok(units);
+    };
+
+    for entry in entries {
+        let entry = entry.context("Failed to read generator directory")?;
+        let unit = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_file())
+            || ![".mount", ".automount", ".swap"]
+                .iter()
+                .any(|suffix| unit.ends_with(suffix))
+        {
+            continue;
+        }
+
+        let unit_info = parse_unit(&entry.path(), &entry.path(), None)?;
+        if unit_value(&unit_info, "Unit", "SourcePath").map(Path::new) == Some(fstab) {
+            units.insert(unit, unit_info);
+        }
+    }
+
+    Ok(units)
+}
+
+// Runs the systemd-fstab-generator shipped with `systemd` on `fstab` and returns the units it
+// generates. `scratch_dir` is used for the generator output and removed afterwards.
+fn generate_fstab_units(
+    systemd: &Path,
+    fstab: &Path,
+    scratch_dir: &Path,
+) -> Result<HashMap<String, UnitInfo>> {
+    if scratch_dir.exists() {
+        std::fs::remove_dir_all(scratch_dir)
+            .with_context(|| format!("Failed to remove {}", scratch_dir.display()))?;
+    }
+
+    let [normal_dir, early_dir, late_dir] =
+        ["normal", "early", "late"].map(|dir| scratch_dir.join(dir));
+    for dir in [&normal_dir, &early_dir, &late_dir] {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let status = std::process::Command::new(
+        systemd.join("lib/systemd/system-generators/systemd-fstab-generator"),
+    )
+    .arg(&normal_dir)
+    .arg(&early_dir)
+    .arg(&late_dir)
+    .env("SYSTEMD_FSTAB", fstab)
+    .env("SYSTEMD_LOG_TARGET", "console")
+    .status()
+    .context("Failed to run systemd-fstab-generator")?;
+    if !status.success() {
+        bail!("systemd-fstab-generator failed with {status}");
+    }
+
+    let units = read_fstab_units(&normal_dir, fstab)?;
+    _ = std::fs::remove_dir_all(scratch_dir);
+
+    Ok(units)
+}
+
+// A newly created private directory in the temporary directory that is removed along with its
+// contents when this is dropped.
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    fn new(prefix: &str) -> Result<Self> {
+        let template = std::env::temp_dir().join(format!("{prefix}-XXXXXX"));
+        let path = nix::unistd::mkdtemp(&template)
+            .with_context(|| format!("Failed to create {}", template.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// Returns a HashMap containing the same contents as the passed in `units`, minus the units in
+// `units_to_filter`.
+fn filter_units(
+    units_to_filter: &HashMap<String, ()>,
+    units: &HashMap<String, ()>,
+) -> HashMap<String, ()> {
+    let mut res = HashMap::new();
+
+    for unit in units.keys() {
+        if !units_to_filter.contains_key(unit) {
+            res.insert(unit.to_string(), ());
+        }
+    }
+
+    res
+}
+
+// The state of the running system that a switch is planned against.
+struct SystemSnapshot {
+    // Root directory of the running configuration, i.e. `/` outside of tests.
+    root: PathBuf,
+    // Drop-in directories of the running service manager, which apply to both configurations.
+    drop_in_dirs: DropInDirs,
+    // Directory below `root` and the new configuration with the units of the manager that is
+    // switched, SYSTEM_UNIT_DIR or USER_UNIT_DIR.
+    unit_dir: &'static str,
+    // Units that systemd currently has loaded and that are not inactive.
+    active_units: HashMap<String, UnitState>,
+    // Resolved path of the binary running as PID 1.
+    pid1_path: PathBuf,
+    // Jobs that a previous switch that was interrupted left pending.
+    pending: SwitchPlan,
+    // Mount, automount and swap units that systemd-fstab-generator created from the running
+    // configuration's fstab.
+    fstab_units: HashMap<String, UnitInfo>,
+}
+
+// Maps the names of units in `unit_dir` that are aliases of other units to the names of those
+// units. An alias is either a symlink to a unit file with a different name (which is how NixOS
+// installs aliases) or a name listed in `Alias=` of a unit.
+fn unit_aliases(unit_dir: &Path) -> Result<HashMap<String, String>> {
+    let mut aliases = HashMap::new();
+
+    let Ok(entries) = std::fs::read_dir(unit_dir) else {
+        return Ok(aliases);
+    };
+
+    for entry in entries {
+        let entry = entry.context("Failed to read unit directory")?;
+        let unit = entry.file_name().to_string_lossy().into_owned();
+        let Some((_, unit_type)) = unit.rsplit_once('.') else {
+            continue;
+        };
+        let Ok(unit_file) = entry.path().canonicalize() else {
+            continue;
+        };
+        if !unit_file.is_file() {
+            continue;
+        }
+
+        // Units that are masked resolve to /dev/null, which is not a unit file.
+        if let Some(target) = unit_file.file_name().map(|name| name.to_string_lossy()) {
+            if target != unit && target.ends_with(&format!(".{unit_type}")) {
+                aliases.insert(unit.clone(), target.into_owned());
+                continue;
+            }
+        }
+
+        // `Alias=` lives in the [Install] section, which `parse_systemd_ini` skips.
+        let contents = std::fs::read_to_string(&unit_file)
+            .with_context(|| format!("Failed to read unit file {}", unit_file.display()))?;
+        let mut in_install_section = false;
+        for line in contents.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_install_section = line == "[Install]";
+            } else if let Some(names) = line.strip_prefix("Alias=") {
+                if in_install_section {
+                    for alias in names.split_whitespace() {
+                        aliases.entry(alias.to_string()).or_insert(unit.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(aliases)
+}
+
+// Returns the name of the unit that `unit` is an alias of, or `unit` itself if it is not an alias.
+// Aliases of template units also apply to their instances.
+fn canonical_unit_name(unit: &str, aliases: &HashMap<String, String>) -> String {
+    if let Some(canonical) = aliases.get(unit) {
+        return canonical.to_string();
+    }
+
+    if let Some((prefix, rest)) = unit.split_once('@') {
+        if let Some((instance, unit_type)) = rest.rsplit_once('.') {
+            if let Some((template_prefix, _)) = aliases
+                .get(&format!("{prefix}@.{unit_type}"))
+                .and_then(|template| template.split_once('@'))
+            {
+                return format!("{template_prefix}@{instance}.{unit_type}");
+            }
+        }
+    }
+
+    unit.to_string()
+}
+
+// Splits a unit name into the name of the unit file that defines it (the template for template
+// instances) and that file's name without the unit type suffix. Template units are only
+// considered if the instance does not have a unit file of its own in any of `unit_dirs`.
+fn base_unit_names(unit: &str, unit_dirs: &[&Path]) -> Result<(String, String)> {
+    let template_unit_re = Regex::new(r"^(.*)@[^\.]*\.(.*)$")
+        .context("Invalid regex for matching systemd template units")?;
+    let unit_name_re = Regex::new(r"^(.*)\.[[:lower:]]*$")
+        .context("Invalid regex for matching systemd unit names")?;
+
+    let mut base_unit = unit.to_string();
+
+    // Detect template instances
+    if let Some((Some(template_name), Some(template_instance))) =
+        template_unit_re.captures(unit).map(|captures| {
+            (
+                captures.get(1).map(|c| c.as_str()),
+                captures.get(2).map(|c| c.as_str()),
+            )
+        })
+    {
+        if unit_dirs.iter().all(|dir| !dir.join(unit).exists()) {
+            base_unit = format!("{template_name}@.{template_instance}");
+        }
+    }
+
+    let base_name = unit_name_re
+        .captures(&base_unit)
+        .and_then(|capture| capture.get(1).map(|first| first.as_str().to_string()))
+        .unwrap_or_else(|| base_unit.clone());
+
+    Ok((base_unit, base_name))
+}
+
+// Computes what needs to be done to get from the currently running system to the configuration in
+// `toplevel`. This only reads unit files from both configurations and does not talk to systemd,
+// the running state is taken from `current`. `new_fstab_units` are the units that
+// systemd-fstab-generator creates for the new configuration.
+fn plan_switch(
+    current: &SystemSnapshot,
+    toplevel: &Path,
+    new_pid1_path: &Path,
+    new_fstab_units: &HashMap<String, UnitInfo>,
+    display_all_units: bool,
+    explain: bool,
+) -> Result<SwitchPlan> {
+    let mut plan = SwitchPlan {
+        units_to_stop: current.pending.units_to_stop.clone(),
+        units_to_start: current.pending.units_to_start.clone(),
+        units_to_restart: current.pending.units_to_restart.clone(),
+        units_to_reload: current.pending.units_to_reload.clone(),
+        units_to_try_restart: current.pending.units_to_try_restart.clone(),
+        units_to_reload_or_restart: current.pending.units_to_reload_or_restart.clone(),
+        units_to_signal: current.pending.units_to_signal.clone(),
+        ..Default::default()
+    };
+
+    let current_unit_dir = current.root.join(current.unit_dir);
+    let new_unit_dir = toplevel.join(current.unit_dir);
+
+    for (unit, unit_state) in &current.active_units {
+        // Don't touch units not explicitly written by NixOS (e.g. units created by generators in
+        // /run/systemd/generator*)
+        if !unit_state
+            .fragment_path
+            .starts_with(&format!("/{}", current.unit_dir))
+        {
+            continue;
+        }
+
+        let current_unit_file = current_unit_dir.join(unit);
+        let new_unit_file = new_unit_dir.join(unit);
+
+        let (base_unit, base_name) = base_unit_names(unit, &[&current_unit_dir, &new_unit_dir])?;
+        let current_base_unit_file = current_unit_dir.join(&base_unit);
+        let new_base_unit_file = new_unit_dir.join(&base_unit);
+
+        if current_base_unit_file.exists()
+            && (unit_state.state == "active" || unit_state.state == "activating")
+        {
+            if new_base_unit_file
+                .canonicalize()
+                .map(|full_path| full_path == Path::new("/dev/null"))
+                .unwrap_or(true)
+            {
+                let current_unit_info = parse_unit(
+                    &current_unit_file,
+                    &current_base_unit_file,
+                    Some(&current.drop_in_dirs),
+                )?;
+                if parse_systemd_bool(Some(&current_unit_info), "Unit", "X-StopOnRemoval", true) {
+                    _ = plan.units_to_stop.insert(unit.to_string(), ());
+                }
+            } else if unit.ends_with(".target") {
+                let new_unit_info = parse_unit(
+                    &new_unit_file,
+                    &new_base_unit_file,
+                    Some(&current.drop_in_dirs),
+                )?;
+
+                // Cause all active target units to be restarted below. This should start most
+                // changed units we stop here as well as any new dependencies (including new mounts
+                // and swap devices).  FIXME: the suspend target is sometimes active after the
+                // system has resumed, which probably should not be the case.  Just ignore it.
+                if !(matches!(
+                    unit.as_str(),
+                    "suspend.target" | "hibernate.target" | "hybrid-sleep.target"
+                ) || parse_systemd_bool(
+                    Some(&new_unit_info),
+                    "Unit",
+                    "RefuseManualStart",
+                    false,
+                ) || parse_systemd_bool(
+                    Some(&new_unit_info),
+                    "Unit",
+                    "X-OnlyManualStart",
+                    false,
+                )) {
+                    plan.units_to_start.insert(unit.to_string(), ());
+                    // Don't spam the user with target units that always get started.
+                    if !display_all_units {
+                        plan.units_to_filter.insert(unit.to_string(), ());
+                    }
+                }
+
+                // Stop targets that have X-StopOnReconfiguration set. This is necessary to respect
+                // dependency orderings involving targets: if unit X starts after target Y and
+                // target Y starts after unit Z, then if X and Z have both changed, then X should
+                // be restarted after Z.  However, if target Y is in the "active" state, X and Z
+                // will be restarted at the same time because X's dependency on Y is already
+                // satisfied.  Thus, we need to stop Y first. Stopping a target generally has no
+                // effect on other units (unless there is a PartOf dependency), so this is just a
+                // bookkeeping thing to get systemd to do the right thing.
+                if parse_systemd_bool(
+                    Some(&new_unit_info),
+                    "Unit",
+                    "X-StopOnReconfiguration",
+                    false,
+                ) {
+                    plan.units_to_stop.insert(unit.to_string(), ());
+                }
+            } else {
+                let current_unit_info = parse_unit(
+                    &current_unit_file,
+                    &current_base_unit_file,
+                    Some(&current.drop_in_dirs),
+                )?;
+                let new_unit_info = parse_unit(
+                    &new_unit_file,
+                    &new_base_unit_file,
+                    Some(&current.drop_in_dirs),
+                )?;
+                let differences = diff_units(&current_unit_info, &new_unit_info);
+                match compare_differences(&differences) {
+                    UnitComparison::UnequalNeedsRestart => {
+                        handle_modified_unit(
+                            &new_unit_dir,
+                            &current.drop_in_dirs,
+                            unit,
+                            &base_name,
+                            &new_unit_file,
+                            &new_base_unit_file,
+                            Some(&new_unit_info),
+                            &current.active_units,
+                            &mut plan,
+                        )?;
+                        if let Some(reason) = plan.units_to_defer.get_mut(unit) {
+                            *reason = restart_reason(&differences);
+                        }
+                    }
+                    UnitComparison::UnequalNeedsReload
+                        if !plan.units_to_restart.contains_key(unit) =>
+                    {
+                        plan.units_to_reload.insert(unit.clone(), ());
+                    }
+                    _ => {}
+                }
+                if explain && !differences.is_empty() {
+                    plan.unit_differences.insert(unit.clone(), differences);
+                }
+            }
+        }
+    }
+
+    // Compare the units systemd-fstab-generator creates for the previous and new fstab to figure
+    // out which filesystems need a remount or need to be unmounted. New filesystems are mounted
+    // automatically by starting local-fs.target. Units that are not active (e.g. because of
+    // `noauto`) are left alone.
+    for (unit, current_unit_info) in &current.fstab_units {
+        if !current.active_units.contains_key(unit) {
+            continue;
+        }
+        let new_unit_info = new_fstab_units.get(unit);
+
+        if unit.ends_with(".swap") {
+            let device = unit_value(current_unit_info, "Swap", "What").unwrap_or(unit);
+            let Some(new_unit_info) = new_unit_info else {
+                // Swap entry disappeared, so turn it off.
+                plan.swaps_to_stop.push(device.to_string());
+                continue;
+            };
+
+            // Options like the priority can only be changed by turning the swap device off and on
+            // again.
+            let current_options = swap_options(current_unit_info);
+            let new_options = swap_options(new_unit_info);
+            if swap_flags(&current_options) != swap_flags(&new_options) {
+                plan.swaps_to_reapply.push(SwapChange {
+                    device: device.to_string(),
+                    old_options: current_options,
+                    new_options,
+                });
+            }
+            continue;
+        }
+
+        let section = if unit.ends_with(".automount") {
+            "Automount"
+        } else {
+            "Mount"
+        };
+        let mountpoint = unit_value(current_unit_info, section, "Where").unwrap_or(unit);
+        let is_essential = matches!(mountpoint, "/" | "/nix");
+
+        let mut differences = new_unit_info
+            .map(|new_unit_info| diff_units(current_unit_info, new_unit_info))
+            .unwrap_or_default();
+        let action = match new_unit_info {
+            // Filesystem entry disappeared, so unmount it.
+            None => MountAction::Stop,
+            Some(_) if compare_differences(&differences) == UnitComparison::Equal => continue,
+            Some(_) if section == "Automount" => {
+                if is_essential {
+                    MountAction::Skip
+                } else {
+                    MountAction::Restart
+                }
+            }
+            Some(new_unit_info) => {
+                let device_changed = ["What", "Type"].iter().any(|key| {
+                    unit_value(current_unit_info, "Mount", key)
+                        != unit_value(new_unit_info, "Mount", key)
+                });
+                let options_changed = unit_value(current_unit_info, "Mount", "Options")
+                    != unit_value(new_unit_info, "Mount", "Options");
+
+                if device_changed && !is_essential {
+                    // Filesystem type or device changed, so unmount and mount it.
+                    MountAction::Restart
+                } else if options_changed {
+                    // Mount options changes, so remount it. This is also done for / and /nix when
+                    // their device changed since they can't be unmounted.
+                    MountAction::Reload
+                } else if device_changed {
+                    // Don't unmount / or /nix if the device changed
+                    MountAction::Skip
+                } else {
+                    // Only dependencies or timeouts changed, which take effect with the daemon
+                    // reload.
+                    continue;
+                }
+            }
+        };
+
+        match action {
+            MountAction::Reload => &mut plan.units_to_reload,
+            MountAction::Restart => &mut plan.units_to_restart,
+            MountAction::Stop => &mut plan.units_to_stop,
+            MountAction::Skip => &mut plan.units_to_skip,
+        }
+        .insert(unit.to_string(), ());
+        plan.mounts.push(MountChange {
+            mountpoint: mountpoint.to_string(),
+            unit: unit.to_string(),
+            action,
+        });
+        if explain && !differences.is_empty() {
+            // Mounts from fstab are remounted whenever only their options changed, including when
+            // options were added to or removed from the defaults.
+            for difference in &mut differences {
+                if difference.section == "Mount" && difference.key.as_deref() == Some("Options") {
+                    difference.rule = DifferenceRule::MountOptions;
+                }
+            }
+            plan.unit_differences.insert(unit.to_string(), differences);
+        }
+    }
+    plan.mounts.sort_by(|a, b| {
+        a.mountpoint
+            .cmp(&b.mountpoint)
+            .then_with(|| a.unit.cmp(&b.unit))
+    });
+
+    for (unit, new_unit_info) in new_fstab_units {
+        if unit.ends_with(".swap") && !current.fstab_units.contains_key(unit) {
+            plan.units_to_start.insert(unit.to_string(), ());
+            plan.swaps_to_start.push(
+                unit_value(new_unit_info, "Swap", "What")
+                    .unwrap_or(unit)
+                    .to_string(),
+            );
+        }
+    }
+    plan.swaps_to_stop.sort();
+    plan.swaps_to_start.sort();
+    plan.swaps_to_reapply
+        .sort_by(|a, b| a.device.cmp(&b.device));
+
+    // Should we have systemd re-exec itself?
+    let current_systemd_system_config = current
+        .root
+        .join("etc/systemd/system.conf")
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from("/unknown"));
+    let new_systemd_system_config = toplevel
+        .join("etc/systemd/system.conf")
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from("/unknown"));
+
+    plan.restart_systemd = current.pid1_path != new_pid1_path
+        || current_systemd_system_config != new_systemd_system_config;
+
+    plan.collapse_aliases(&unit_aliases(&new_unit_dir)?);
+
+    // Restarts that were deferred by the interrupted switch are still deferred, unless the unit is
+    // restarted or stopped now.
+    for (unit, reason) in &current.pending.units_to_defer {
+        if ![
+            &plan.units_to_stop,
+            &plan.units_to_restart,
+            &plan.units_to_try_restart,
+            &plan.units_to_reload_or_restart,
+        ]
+        .iter()
+        .any(|units| units.contains_key(unit))
+        {
+            plan.units_to_defer
+                .entry(unit.clone())
+                .or_insert_with(|| reason.clone());
+        }
+    }
+
+    Ok(plan)
+}
+
+// Describes what `plan` does with `unit`.
+fn planned_action(plan: &SwitchPlan, unit: &str) -> &'static str {
+    if plan.units_to_skip.contains_key(unit) {
+        "skip"
+    } else if plan.units_to_restart.contains_key(unit) {
+        "restart"
+    } else if plan.units_to_stop.contains_key(unit) && plan.units_to_start.contains_key(unit) {
+        "stop and start"
+    } else if plan.units_to_stop.contains_key(unit) {
+        "stop"
+    } else if plan.units_to_reload.contains_key(unit) {
+        "reload"
+    } else if plan.units_to_try_restart.contains_key(unit) {
+        "try-restart"
+    } else if plan.units_to_reload_or_restart.contains_key(unit) {
+        "reload or restart"
+    } else if plan.units_to_signal.contains_key(unit) {
+        "signal"
+    } else if plan.units_to_defer.contains_key(unit) {
+        "defer"
+    } else if plan.units_to_start.contains_key(unit) {
+        "start"
+    } else {
+        "leave alone"
+    }
+}
+
+// Adds the units the activation script asked to be restarted or reloaded to the plan. Both lists
+// are newline-separated unit names.
+fn handle_activation_requests(
+    plan: &mut SwitchPlan,
+    current: &SystemSnapshot,
+    toplevel: &Path,
+    restart_requests: &str,
+    reload_requests: &str,
+) -> Result<()> {
+    let active_units = &current.active_units;
+    let current_unit_dir = current.root.join(SYSTEM_UNIT_DIR);
+    let new_unit_dir = toplevel.join(SYSTEM_UNIT_DIR);
+    let aliases = unit_aliases(&new_unit_dir)?;
+
+    // systemd only knows units by their canonical name, so resolve aliases before looking at the
+    // state of a unit.
+    let mut canonical_unit_name = |unit: &str| {
+        let canonical = canonical_unit_name(unit, &aliases);
+        if canonical != unit {
+            plan.unit_aliases
+                .insert(unit.to_string(), canonical.clone());
+        }
+        canonical
+    };
+    let restart_requests = restart_requests
+        .lines()
+        .map(&mut canonical_unit_name)
+        .collect::<Vec<_>>();
+    let reload_requests = reload_requests
+        .lines()
+        .map(&mut canonical_unit_name)
+        .collect::<Vec<_>>();
+
+    for unit in &restart_requests {
+        let unit = unit.as_str();
+        let new_unit_file = new_unit_dir.join(unit);
+        let (base_unit, base_name) = base_unit_names(unit, &[&current_unit_dir, &new_unit_dir])?;
+        let new_base_unit_file = new_unit_dir.join(&base_unit);
+
+        // Start units if they were not active previously
+        if !active_units.contains_key(unit) {
+            plan.units_to_start.insert(unit.to_string(), ());
+            continue;
+        }
+
+        handle_modified_unit(
+            &new_unit_dir,
+            &current.drop_in_dirs,
+            unit,
+            &base_name,
+            &new_unit_file,
+            &new_base_unit_file,
+            None,
+            active_units,
+            plan,
+        )?;
+    }
+
+    for unit in reload_requests {
+        if active_units.contains_key(&unit)
+            && !plan.units_to_restart.contains_key(&unit)
+            && !plan.units_to_stop.contains_key(&unit)
+        {
+            plan.units_to_reload.insert(unit, ());
+        }
+    }
+
+    plan.collapse_aliases(&aliases);
+
+    Ok(())
+}
+
+// Returns the units in `unit_dir`. Masked units and directories (like drop-ins and `.wants`) are
+// left out.
+fn unit_files(unit_dir: &Path) -> Result<HashMap<String, ()>> {
+    let mut units = HashMap::new();
+    let entries = match std::fs::read_dir(unit_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(units),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", unit_dir.display()))
+        }
+    };
+
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read {}", unit_dir.display()))?;
+        if std::fs::metadata(entry.path()).is_ok_and(|metadata| metadata.is_file()) {
+            units.insert(entry.file_name().to_string_lossy().into_owned(), ());
+        }
+    }
+
+    Ok(units)
+}
+
+// Builds a snapshot of a system running the configuration at `toplevel` with all of its units and
+// filesystems active, which is the worst case for a switch away from it.
+fn offline_snapshot(
+    toplevel: &Path,
+    fstab_units: HashMap<String, UnitInfo>,
+) -> Result<SystemSnapshot> {
+    let unit_dir = toplevel.join(SYSTEM_UNIT_DIR);
+    let mut active_units = HashMap::new();
+    for unit in unit_files(&unit_dir)?.into_keys() {
+        // Instances of templates are not known without a running system.
+        if unit.contains("@.") {
+            continue;
+        }
+        let fragment_path = format!("/{SYSTEM_UNIT_DIR}/{unit}");
+        active_units.insert(
+            unit,
+            UnitState {
+                state: "active".to_string(),
+                substate: "running".to_string(),
+                fragment_path,
+            },
+        );
+    }
+    for unit in fstab_units.keys() {
+        active_units.insert(
+            unit.clone(),
+            UnitState {
+                state: "active".to_string(),
+                substate: "mounted".to_string(),
+                fragment_path: format!("{SYSTEMD_GENERATOR_DIR}/{unit}"),
+            },
+        );
+    }
+
+    let pid1_path = toplevel.join("systemd/lib/systemd/systemd");
+    Ok(SystemSnapshot {
+        root: toplevel.to_path_buf(),
+        drop_in_dirs: DropInDirs::system(toplevel),
+        unit_dir: SYSTEM_UNIT_DIR,
+        active_units,
+        pid1_path: pid1_path.canonicalize().unwrap_or(pid1_path),
+        pending: SwitchPlan::default(),
+        fstab_units,
+    })
+}
+
+// Compares the configurations at `old` and `new` without looking at the running system.
+fn diff_configurations(
+    old: &Path,
+    new: &Path,
+    old_fstab_units: HashMap<String, UnitInfo>,
+    new_fstab_units: &HashMap<String, UnitInfo>,
+) -> Result<ConfigurationDiff> {
+    let old_unit_dir = old.join(SYSTEM_UNIT_DIR);
+    let new_unit_dir = new.join(SYSTEM_UNIT_DIR);
+    let old_units = unit_files(&old_unit_dir)?;
+    let new_units = unit_files(&new_unit_dir)?;
+
+    let mut diff = ConfigurationDiff::default();
+    for unit in sorted_units(&new_units) {
+        if !old_units.contains_key(&unit) {
+            diff.added_units.push(unit);
+        }
+    }
+    for unit in sorted_units(&old_units) {
+        if !new_units.contains_key(&unit) {
+            diff.removed_units.push(unit);
+            continue;
+        }
+
+        let old_unit_info = parse_unit(&old_unit_dir.join(&unit), &old_unit_dir.join(&unit), None)?;
+        let new_unit_info = parse_unit(&new_unit_dir.join(&unit), &new_unit_dir.join(&unit), None)?;
+        if !diff_units(&old_unit_info, &new_unit_info).is_empty() {
+            diff.changed_units.push(unit);
+        }
+    }
+
+    diff.init_interface_changed = std::fs::read_to_string(old.join("init-interface-version")).ok()
+        != std::fs::read_to_string(new.join("init-interface-version")).ok();
+
+    let new_pid1_path = new.join("systemd/lib/systemd/systemd");
+    diff.plan = plan_switch(
+        &offline_snapshot(old, old_fstab_units)?,
+        new,
+        &new_pid1_path.canonicalize().unwrap_or(new_pid1_path),
+        new_fstab_units,
+        true,
+        true,
+    )?;
+    diff.plan.reboot_required = boot_component_changes(old, new);
+
+    Ok(diff)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Job {
+    Start,
+    Restart,
+    TryRestart,
+    Reload,
+    ReloadOrRestart,
+    Stop,
+}
+
+impl std::fmt::Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Job::Start => "start",
+                Job::Restart => "restart",
+                Job::TryRestart => "try-restart",
+                Job::Reload => "reload",
+                Job::ReloadOrRestart => "reload-or-restart",
+                Job::Stop => "stop",
+            }
+        )
+    }
+}
+
+// Limits on how long to wait for systemd jobs. By default there are none, so a single hanging job
+// blocks the switch forever.
+#[derive(Debug, Default, Clone, Copy)]
+struct JobTimeouts {
+    // How long a single job may take.
+    per_job: Option<Duration>,
+    // How long all jobs of a switch may take, counted from the start of the switch (see `deadline`).
+    total: Option<Duration>,
+    // Whether jobs that did not finish in time are cancelled instead of being left to systemd.
+    cancel: bool,
+}
+
+impl JobTimeouts {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            per_job: duration_from_env("STC_JOB_TIMEOUT")?,
+            total: duration_from_env("STC_SWITCH_TIMEOUT")?,
+            cancel: std::env::var("STC_CANCEL_TIMED_OUT_JOBS").as_deref() == Ok("1"),
+        })
+    }
+
+    // When all jobs of a switch that started at `started` must have finished.
+    fn deadline(&self, started: SystemTime) -> Option<Instant> {
+        let elapsed = started.elapsed().unwrap_or_default();
+        self.total
+            .map(|total| Instant::now() + total.saturating_sub(elapsed))
+    }
+}
+
+// Returns when the switch started. That is now, unless the switch continues one that was started
+// by another process.
+fn switch_started() -> SystemTime {
+    std::env::var(SWITCH_STARTED_ENV)
+        .ok()
+        .and_then(|millis| millis.parse().ok())
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+        .unwrap_or_else(SystemTime::now)
+}
+
+// Reads a number of seconds from an environment variable. Unset or empty variables mean no value.
+fn duration_from_env(var: &str) -> Result<Option<Duration>> {
+    match std::env::var(var).as_deref() {
+        Err(_) | Ok("") => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs.parse().with_context(
+            || format!("${var} must be a number of seconds, got {secs}"),
+        )?))),
+    }
+}
+
+// Returns the jobs that have been running for longer than the per-job timeout or that are still
+// running after the deadline of the whole switch.
+fn expired_jobs<K: Clone>(
+    submitted_jobs: &HashMap<K, (String, Job, Instant)>,
+    now: Instant,
+    timeouts: &JobTimeouts,
+    deadline: Option<Instant>,
+) -> Vec<K> {
+    submitted_jobs
+        .iter()
+        .filter(|(_, (_, _, submitted))| {
+            timeouts
+                .per_job
+                .is_some_and(|per_job| now.duration_since(*submitted) >= per_job)
+                || deadline.is_some_and(|deadline| now >= deadline)
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// The exit status of a switch in which jobs timed out. A timeout takes precedence over failed
+// units, but not over a failed activation script.
+fn timed_out_exit_code(exit_code: i32) -> i32 {
+    match exit_code {
+        0 | 4 => JOB_TIMEOUT_EXIT_CODE,
+        exit_code => exit_code,
+    }
+}
+
+// The operations on a systemd manager that are needed to carry out a switch. Jobs are submitted
+// asynchronously and waited for with `block_on_jobs`.
+trait SystemdManager {
+    // Returns all units that are not inactive, keyed by their name.
+    fn active_units(&self) -> Result<HashMap<String, UnitState>>;
+
+    // Whether a unit is active or activating.
+    fn unit_is_active(&self, unit: &str) -> Result<bool>;
+
+    // The ActiveState of a unit, like `active`, `activating` or `failed`.
+    fn active_state(&self, unit: &str) -> Result<String>;
+
+    // The exit status of the main process of a service.
+    fn exec_main_status(&self, unit: &str) -> Result<i32>;
+
+    fn submit_job(&self, unit: &str, job: Job) -> Result<()>;
+
+    // Sends a signal to the main process of a unit.
+    fn kill_unit(&self, unit: &str, signal: Signal) -> Result<()>;
+
+    // The cgroup of a service relative to the root of the cgroup hierarchy, e.g.
+    // /system.slice/foo.service.
+    fn control_group(&self, unit: &str) -> Result<String>;
+
+    // The PID of the main process of a service, 0 if it has none.
+    fn main_pid(&self, unit: &str) -> Result<u32>;
+
+    // Waits until all submitted jobs have finished or timed out.
+    fn block_on_jobs(&self);
+
+    // Returns unit, job and result of every job that has finished so far.
+    fn finished_jobs(&self) -> Vec<(String, Job, String)>;
+
+    // Returns unit and job of every job that was given up on because it did not finish in time.
+    fn timed_out_jobs(&self) -> Vec<(String, Job)>;
+
+    fn reexecute(&self) -> Result<()>;
+
+    fn reload(&self) -> Result<()>;
+
+    fn reset_failed(&self) -> Result<()>;
+
+    // Waits until the manager has stopped sending events.
+    fn settle(&self);
+}
+
+fn systemd1_proxy(conn: &LocalConnection) -> Proxy<'_, &LocalConnection> {
+    conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        BUS_TIMEOUT,
+    )
+}
+
+fn login1_proxy(conn: &LocalConnection) -> Proxy<'_, &LocalConnection> {
+    conn.with_proxy(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        BUS_TIMEOUT,
+    )
+}
+
+// A systemd manager reached over D-Bus.
+struct DbusSystemdManager<'a> {
+    conn: &'a LocalConnection,
+    submitted_jobs: Rc<RefCell<HashMap<dbus::Path<'static>, (String, Job, Instant)>>>,
+    finished_jobs: Rc<RefCell<HashMap<dbus::Path<'static>, (String, Job, String)>>>,
+    timed_out_jobs: RefCell<Vec<(String, Job)>>,
+    is_reloading: Rc<RefCell<bool>>,
+    timeouts: JobTimeouts,
+    // When all jobs of the switch must have finished.
+    deadline: Option<Instant>,
+    // Where the state of the submitted jobs is published.
+    progress: Option<SwitchProgress>,
+}
+
+impl<'a> DbusSystemdManager<'a> {
+    fn new(
+        conn: &'a LocalConnection,
+        timeouts: JobTimeouts,
+        deadline: Option<Instant>,
+        progress: Option<SwitchProgress>,
+    ) -> Result<Self> {
+        let systemd = systemd1_proxy(conn);
+
+        let submitted_jobs = Rc::new(RefCell::new(HashMap::new()));
+        let finished_jobs = Rc::new(RefCell::new(HashMap::new()));
+        let is_reloading = Rc::new(RefCell::new(false));
+
+        systemd
+            .subscribe()
+            .context("Failed to subscribe to systemd dbus messages")?;
+
+        let _is_reloading = is_reloading.clone();
+        systemd
+            .match_signal(
+                move |signal: OrgFreedesktopSystemd1ManagerReloading,
+                      _: &LocalConnection,
+                      _msg: &Message| {
+                    *_is_reloading.borrow_mut() = signal.active;
+
+                    true
+                },
+            )
+            .context("Failed to add systemd Reloading match")?;
+
+        let _submitted_jobs = submitted_jobs.clone();
+        let _finished_jobs = finished_jobs.clone();
+        let _progress = progress.clone();
+        systemd
+            .match_signal(
+                move |signal: OrgFreedesktopSystemd1ManagerJobRemoved,
+                      _: &LocalConnection,
+                      _msg: &Message| {
+                    if let Some((_, old, _)) = _submitted_jobs.borrow_mut().remove(&signal.job) {
+                        if let Some(progress) = &_progress {
+                            progress.set_job_state(&signal.unit, old, &signal.result);
+                        }
+                        let mut finished_jobs = _finished_jobs.borrow_mut();
+                        finished_jobs.insert(signal.job, (signal.unit, old, signal.result));
+                    }
+
+                    true
+                },
+            )
+            .context("Failed to add systemd JobRemoved match")?;
+
+        Ok(Self {
+            conn,
+            submitted_jobs,
+            finished_jobs,
+            timed_out_jobs: RefCell::new(Vec::new()),
+            is_reloading,
+            timeouts,
+            deadline,
+            progress,
+        })
+    }
+
+    // Stops waiting for a job that did not finish in time and cancels it if requested.
+    fn time_out_job(&self, job_path: &dbus::Path<'static>) {
+        let Some((unit, job, _)) = self.submitted_jobs.borrow_mut().remove(job_path) else {
+            return;
+        };
+
+        eprintln!("timed out waiting for the {job} job of {unit}");
+        if let Some(progress) = &self.progress {
+            progress.set_job_state(&unit, job, "timeout");
+        }
+
+        if self.timeouts.cancel {
+            if let Err(err) = self
+                .conn
+                .with_proxy("org.freedesktop.systemd1", job_path, BUS_TIMEOUT)
+                .method_call::<(), _, _, _>("org.freedesktop.systemd1.Job", "Cancel", ())
+            {
+                eprintln!("Failed to cancel the {job} job of {unit}: {err}");
+            }
+        }
+
+        self.timed_out_jobs.borrow_mut().push((unit, job));
+    }
+
+    fn unit_proxy(&self, unit: &str) -> Result<Proxy<'_, &LocalConnection>> {
+        let unit_object_path = systemd1_proxy(self.conn)
+            .get_unit(unit)
+            .with_context(|| format!("Failed to get unit {unit}"))?;
+
+        Ok(self.conn.with_proxy(
+            "org.freedesktop.systemd1",
+            unit_object_path,
+            Duration::from_millis(5000),
+        ))
+    }
+
+    // Starts a transient service running `argv`, with its output written to `log_file`. The start
+    // job is waited for like any other job.
+    fn start_transient_service(
+        &self,
+        unit: &str,
+        executable: &Path,
+        argv: Vec<String>,
+        environment: Vec<String>,
+        log_file: &Path,
+    ) -> Result<()> {
+        let properties: Vec<(&str, Variant<Box<dyn RefArg>>)> = vec![
+            (
+                "Description",
+                Variant(Box::new("NixOS switch-to-configuration".to_string())),
+            ),
+            (
+                "ExecStart",
+                Variant(Box::new(vec![(
+                    executable.display().to_string(),
+                    argv,
+                    false,
+                )])),
+            ),
+            ("Environment", Variant(Box::new(environment))),
+            (
+                "StandardOutputFileToTruncate",
+                Variant(Box::new(log_file.display().to_string())),
+            ),
+            ("StandardError", Variant(Box::new("inherit".to_string()))),
+            (
+                "CollectMode",
+                Variant(Box::new("inactive-or-failed".to_string())),
+            ),
+        ];
+
+        let job_path = systemd1_proxy(self.conn)
+            .start_transient_unit(unit, "fail", properties, Vec::new())
+            .with_context(|| format!("Failed to start {unit}"))?;
+        self.submitted_jobs
+            .borrow_mut()
+            .insert(job_path, (unit.to_string(), Job::Start, Instant::now()));
+
+        Ok(())
+    }
+
+    // Waits for the Reloading signal that systemd emits once a daemon-reload or daemon-reexec has
+    // finished.
+    fn wait_for_reload(&self, what: &str) -> Result<()> {
+        let mut time_waited = Duration::from_secs(0);
+        while *self.is_reloading.borrow() {
+            _ = self
+                .conn
+                .process(DBUS_PROCESS_TIME)
+                .context("Failed to process dbus messages")?;
+            time_waited += DBUS_PROCESS_TIME;
+            if time_waited >= DAEMON_RELOAD_TIMEOUT {
+                bail!(
+                    "systemd daemon {what} failed, timeout after {:?}",
+                    DAEMON_RELOAD_TIMEOUT
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SystemdManager for DbusSystemdManager<'_> {
+    // Asks the currently running systemd instance via dbus which units are active.
+    fn active_units(&self) -> Result<HashMap<String, UnitState>> {
+        let units = systemd1_proxy(self.conn)
+            .list_units_by_patterns(Vec::new(), Vec::new())
+            .context("Failed to list systemd units")?;
+
+        Ok(units
+            .into_iter()
+            .filter_map(
+                |(
+                    id,
+                    _description,
+                    _load_state,
+                    state,
+                    substate,
+                    following,
+                    unit_path,
+                    _job_id,
+                    _job_type,
+                    _job_path,
+                )| {
+                    if following.is_empty() && state != "inactive" {
+                        let fragment_path = self
+                            .conn
+                            .with_proxy(
+                                "org.freedesktop.systemd1",
+                                unit_path,
+                                Duration::from_millis(5000),
+                            )
+                            .get("org.freedesktop.systemd1.Unit", "FragmentPath")
+                            .unwrap_or_default();
+
+                        Some((
+                            id,
+                            UnitState {
+                                state,
+                                substate,
+                                fragment_path,
+                            },
+                        ))
+                    } else {
+                        None
+                    }
+                },
+            )
+            .fold(HashMap::new(), |mut acc, (id, unit_state)| {
+                acc.insert(id, unit_state);
+
+                acc
+            }))
+    }
+
+    fn unit_is_active(&self, unit: &str) -> Result<bool> {
+        Ok(matches!(
+            self.active_state(unit)?.as_str(),
+            "active" | "activating"
+        ))
+    }
+
+    fn active_state(&self, unit: &str) -> Result<String> {
+        self.unit_proxy(unit)?
+            .get("org.freedesktop.systemd1.Unit", "ActiveState")
+            .with_context(|| format!("Failed to get ActiveState for {unit}"))
+    }
+
+    fn exec_main_status(&self, unit: &str) -> Result<i32> {
+        self.unit_proxy(unit)?
+            .get("org.freedesktop.systemd1.Service", "ExecMainStatus")
+            .with_context(|| format!("Failed to get ExecMainStatus for {unit}"))
+    }
+
+    fn control_group(&self, unit: &str) -> Result<String> {
+        self.unit_proxy(unit)?
+            .get("org.freedesktop.systemd1.Service", "ControlGroup")
+            .with_context(|| format!("Failed to get ControlGroup for {unit}"))
+    }
+
+    fn main_pid(&self, unit: &str) -> Result<u32> {
+        self.unit_proxy(unit)?
+            .get("org.freedesktop.systemd1.Service", "MainPID")
+            .with_context(|| format!("Failed to get MainPID for {unit}"))
+    }
+
+    fn submit_job(&self, unit: &str, job: Job) -> Result<()> {
+        let systemd = systemd1_proxy(self.conn);
+        let job_path = match job {
+            Job::Start => systemd.start_unit(unit, "replace"),
+            Job::Restart => systemd.restart_unit(unit, "replace"),
+            Job::TryRestart => systemd.try_restart_unit(unit, "replace"),
+            Job::Reload => systemd.reload_unit(unit, "replace"),
+            Job::ReloadOrRestart => systemd.reload_or_restart_unit(unit, "replace"),
+            Job::Stop => systemd.stop_unit(unit, "replace"),
+        }?;
+        if let Some(progress) = &self.progress {
+            progress.set_job_state(unit, job, "running");
+        }
+
+        self.submitted_jobs
+            .borrow_mut()
+            .insert(job_path, (unit.to_string(), job, Instant::now()));
+
+        Ok(())
+    }
+
+    fn kill_unit(&self, unit: &str, signal: Signal) -> Result<()> {
+        systemd1_proxy(self.conn)
+            .kill_unit(unit, "main", signal as i32)
+            .with_context(|| format!("Failed to send {signal} to {unit}"))
+    }
+
+    fn block_on_jobs(&self) {
+        while !self.submitted_jobs.borrow().is_empty() {
+            log::debug!(
+                "waiting for submitted jobs to finish, still have {} job(s)",
+                self.submitted_jobs.borrow().len()
+            );
+            _ = self.conn.process(DBUS_PROCESS_TIME);
+
+            let expired = expired_jobs(
+                &self.submitted_jobs.borrow(),
+                Instant::now(),
+                &self.timeouts,
+                self.deadline,
+            );
+            for job_path in expired {
+                self.time_out_job(&job_path);
+            }
+        }
+    }
+
+    fn finished_jobs(&self) -> Vec<(String, Job, String)> {
+        self.finished_jobs.borrow().values().cloned().collect()
+    }
+
+    fn timed_out_jobs(&self) -> Vec<(String, Job)> {
+        self.timed_out_jobs.borrow().clone()
+    }
+
+    fn reexecute(&self) -> Result<()> {
+        *self.is_reloading.borrow_mut() = true;
+        _ = systemd1_proxy(self.conn).reexecute(); // we don't get a dbus reply here
+        log::debug!("waiting for systemd restart to finish");
+        self.wait_for_reload("reexecute")
+    }
+
+    fn reload(&self) -> Result<()> {
+        *self.is_reloading.borrow_mut() = true;
+        _ = systemd1_proxy(self.conn).reload(); // we don't get a dbus reply here
+        log::debug!("waiting for systemd reload to finish");
+        self.wait_for_reload("reload")
+    }
+
+    fn reset_failed(&self) -> Result<()> {
+        systemd1_proxy(self.conn)
+            .reset_failed()
+            .context("Failed to reset failed units")
+    }
+
+    // NOTE: We want switch-to-configuration to be able to report to the user any units that failed
+    // to start or units that systemd had to restart due to having previously failed. This is
+    // inherently a race condition between how long our program takes to run and how long the unit
+    // in question takes to potentially fail. The amount of time we wait for new messages on the
+    // bus to settle is purely tuned so that this program is compatible with the Perl
+    // implementation.
+    //
+    // Wait for events from systemd to settle. process() will return true if we have received any
+    // messages on the bus.
+    fn settle(&self) {
+        let mut waited = Duration::from_millis(0);
+        let wait_interval = Duration::from_millis(250);
+        let max_wait = Duration::from_secs(90);
+        log::debug!("waiting for systemd events to settle");
+        while self.conn.process(wait_interval).unwrap_or_default() {
+            waited += wait_interval;
+            if waited >= max_wait {
+                log::debug!("timed out waiting systemd events to settle");
+                break;
+            }
+        }
+    }
+}
+
+// What a switch publishes about its progress, see `SwitchProgress`.
+#[derive(Debug)]
+struct ProgressState {
+    action: &'static str,
+    toplevel: PathBuf,
+    // "planning", then the phases of the journal, then "finished".
+    phase: String,
+    // The plan as printed by `dry-activate --json`, empty while planning.
+    plan: String,
+    // Unit, job and state of every job of the switch in the order they are planned. The state is
+    // "pending", "running" once the job was submitted, or the result of the job as reported by
+    // systemd ("done", "failed", "timeout", ...).
+    jobs: Vec<(String, String, String)>,
+    // "running", "success" or "failed".
+    result: String,
+    exit_code: i32,
+    failed_units: Vec<String>,
+}
+
+// Publishes the progress of a switch on D-Bus so that other programs can follow it without
+// parsing its output. The object is served by a thread of its own, so that its properties can be
+// read while the switch waits for the activation script. Sending the signals can't fail the
+// switch.
+#[derive(Clone)]
+struct SwitchProgress {
+    conn: Arc<SyncConnection>,
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl SwitchProgress {
+    fn publish(conn: SyncConnection, action: &Action, toplevel: &Path) -> Result<Self> {
+        let conn = Arc::new(conn);
+        let state = Arc::new(Mutex::new(ProgressState {
+            action: action.into(),
+            toplevel: toplevel.to_path_buf(),
+            phase: "planning".to_string(),
+            plan: String::new(),
+            jobs: Vec::new(),
+            result: "running".to_string(),
+            exit_code: 0,
+            failed_units: Vec::new(),
+        }));
+
+        let mut cr = Crossroads::new();
+        let iface = cr.register(
+            PROGRESS_INTERFACE,
+            |b: &mut IfaceBuilder<Arc<Mutex<ProgressState>>>| {
+                b.property("Action")
+                    .emits_changed_const()
+                    .get(|_, state| Ok(state.lock().unwrap().action.to_string()));
+                b.property("Toplevel")
+                    .emits_changed_const()
+                    .get(|_, state| Ok(state.lock().unwrap().toplevel.display().to_string()));
+                b.property("Phase")
+                    .get(|_, state| Ok(state.lock().unwrap().phase.clone()));
+                b.property("Plan")
+                    .get(|_, state| Ok(state.lock().unwrap().plan.clone()));
+                // Changes are announced by JobChanged.
+                b.property("Jobs")
+                    .emits_changed_false()
+                    .get(|_, state| Ok(state.lock().unwrap().jobs.clone()));
+                b.property("Result")
+                    .get(|_, state| Ok(state.lock().unwrap().result.clone()));
+                b.property("ExitCode")
+                    .get(|_, state| Ok(state.lock().unwrap().exit_code));
+                b.property("FailedUnits")
+                    .get(|_, state| Ok(state.lock().unwrap().failed_units.clone()));
+                b.signal::<(String,), _>("PhaseChanged", ("phase",));
+                b.signal::<(String, String, String), _>("JobChanged", ("unit", "job", "state"));
+                b.signal::<(String, i32, Vec<String>), _>(
+                    "Finished",
+                    ("result", "exit_code", "failed_units"),
+                );
+            },
+        );
+        cr.insert(PROGRESS_OBJECT_PATH, &[iface], state.clone());
+
+        let cr = Mutex::new(cr);
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, conn| {
+                _ = cr.lock().unwrap().handle_message(message, conn);
+                true
+            }),
+        );
+
+        // A switch started while this one runs, like a rollback or a switch that continues in a
+        // transient service, takes over the name.
+        let reply = conn
+            .request_name(PROGRESS_INTERFACE, true, true, true)
+            .with_context(|| format!("Failed to request {PROGRESS_INTERFACE}"))?;
+        if reply != RequestNameReply::PrimaryOwner {
+            bail!("{PROGRESS_INTERFACE} is owned by another process");
+        }
+
+        let _conn = conn.clone();
+        std::thread::spawn(move || while _conn.process(Duration::from_secs(1)).is_ok() {});
+
+        Ok(Self { conn, state })
+    }
+
+    fn emit(&self, member: &str, message: impl FnOnce(Message) -> Message) {
+        if let Ok(signal) = Message::new_signal(PROGRESS_OBJECT_PATH, PROGRESS_INTERFACE, member) {
+            _ = self.conn.send(message(signal));
+        }
+    }
+
+    fn properties_changed(&self, changed_properties: PropMap) {
+        let signal = PropertiesPropertiesChanged {
+            interface_name: PROGRESS_INTERFACE.to_string(),
+            changed_properties,
+            invalidated_properties: Vec::new(),
+        };
+        _ = self
+            .conn
+            .send(signal.to_emit_message(&PROGRESS_OBJECT_PATH.into()));
+    }
+
+    fn update_phase(&self, phase: &str) {
+        let changed = {
+            let mut state = self.state.lock().unwrap();
+            std::mem::replace(&mut state.phase, phase.to_string()) != phase
+        };
+
+        if changed {
+            self.emit("PhaseChanged", |signal| signal.append1(phase));
+            self.properties_changed(PropMap::from([(
+                "Phase".to_string(),
+                Variant(Box::new(phase.to_string()) as Box<dyn RefArg>),
+            )]));
+        }
+    }
+
+    fn set_phase(&self, phase: SwitchPhase) {
+        if let Ok(serde_json::Value::String(phase)) = serde_json::to_value(phase) {
+            self.update_phase(&phase);
+        }
+    }
+
+    // Publishes the plan and its jobs. Jobs that were already published keep their state and jobs
+    // that are done according to the journal, like when resuming, are published as done.
+    fn set_plan(&self, plan: &SwitchPlan, journal: &SwitchJournal) {
+        let plan = {
+            let mut state = self.state.lock().unwrap();
+            let document = SwitchPlanDocument {
+                version: SWITCH_PLAN_VERSION,
+                action: state.action,
+                toplevel: &state.toplevel,
+                plan,
+            };
+            let document = serde_json::to_string(&document).unwrap_or_default();
+
+            let jobs = journal
+                .units
+                .iter()
+                .map(|entry| {
+                    let job = entry.job.to_string();
+                    let job_state = state
+                        .jobs
+                        .iter()
+                        .find(|(unit, other, _)| *unit == entry.unit && *other == job)
+                        .map(|(_, _, job_state)| job_state.clone())
+                        .unwrap_or_else(|| match entry.status {
+                            JobStatus::Pending => "pending".to_string(),
+                            JobStatus::Done => "done".to_string(),
+                        });
+                    (entry.unit.clone(), job, job_state)
+                })
+                .collect();
+            state.jobs = jobs;
+            state.plan = document.clone();
+
+            document
+        };
+
+        self.properties_changed(PropMap::from([(
+            "Plan".to_string(),
+            Variant(Box::new(plan) as Box<dyn RefArg>),
+        )]));
+        self.set_phase(journal.phase);
+    }
+
+    fn set_job_state(&self, unit: &str, job: Job, job_state: &str) {
+        let job = job.to_string();
+        {
+            let mut state = self.state.lock().unwrap();
+            match state
+                .jobs
+                .iter_mut()
+                .find(|(other, other_job, _)| other == unit && *other_job == job)
+            {
+                Some((_, _, state)) => *state = job_state.to_string(),
+                None => state
+                    .jobs
+                    .push((unit.to_string(), job.clone(), job_state.to_string())),
+            }
+        }
+
+        self.emit("JobChanged", |signal| signal.append3(unit, job, job_state));
+    }
+
+    fn finish(&self, exit_code: i32, failed_units: &[String]) {
+        let result = if exit_code == 0 { "success" } else { "failed" };
+        {
+            let mut state = self.state.lock().unwrap();
+            state.result = result.to_string();
+            state.exit_code = exit_code;
+            state.failed_units = failed_units.to_vec();
+        }
+
+        self.update_phase("finished");
+        self.properties_changed(PropMap::from([
+            (
+                "Result".to_string(),
+                Variant(Box::new(result.to_string()) as Box<dyn RefArg>),
+            ),
+            (
+                "ExitCode".to_string(),
+                Variant(Box::new(exit_code) as Box<dyn RefArg>),
+            ),
+            (
+                "FailedUnits".to_string(),
+                Variant(Box::new(failed_units.to_vec()) as Box<dyn RefArg>),
+            ),
+        ]));
+        self.emit("Finished", |signal| {
+            signal.append3(result, exit_code, failed_units)
+        });
+        self.conn.channel().flush();
+    }
+}
+
+// Stops the units of the plan and waits for systemd to finish doing so.
+fn stop_units(systemd: &impl SystemdManager, plan: &SwitchPlan) {
+    if !plan.units_to_stop.is_empty() {
+        let units_to_stop_filtered = filter_units(&plan.units_to_filter, &plan.units_to_stop);
+        if !units_to_stop_filtered.is_empty() {
+            eprintln!(
+                "stopping the following units: {}",
+                sorted_units(&units_to_stop_filtered).join(", ")
+            );
+        }
+
+        for unit in plan.units_to_stop.keys() {
+            _ = systemd.submit_job(unit, Job::Stop);
+        }
+    }
+
+    // Wait for all stop jobs to finish
+    systemd.block_on_jobs();
+}
+
+// Prints how the changed units differ from their current version and what is done with them.
+fn print_unit_differences(plan: &SwitchPlan) {
+    for (unit, differences) in &plan.unit_differences {
+        eprintln!("{unit} ({}):", planned_action(plan, unit));
+        for difference in differences {
+            eprintln!("  {difference}");
+        }
+    }
+}
+
+// How the instances of a template are restarted when the template changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RestartBatch {
+    // How many instances are restarted at once (`X-RestartBatchSize=`).
+    size: usize,
+    // How long to wait between two batches (`X-RestartBatchDelay=`, a systemd time span).
+    delay: Duration,
+}
+
+// Returns the `X-RestartBatchSize=` of a unit if it is valid.
+fn restart_batch_size(unit_info: &UnitInfo) -> Option<usize> {
+    unit_value(unit_info, "Service", "X-RestartBatchSize")?
+        .parse()
+        .ok()
+        .filter(|size| *size > 0)
+}
+
+// Reads the batch settings of the template `unit`. Units without a valid batch size are restarted
+// all at once, an invalid delay is treated as no delay. Invalid values are warned about.
+fn restart_batch(unit: &str, unit_info: &UnitInfo) -> Option<RestartBatch> {
+    let size = unit_value(unit_info, "Service", "X-RestartBatchSize")?;
+    let Some(size) = restart_batch_size(unit_info) else {
+        eprintln!(
+            "warning: ignoring X-RestartBatchSize={size} of {unit}, it is not a positive number"
+        );
+        return None;
+    };
+    let delay = match unit_value(unit_info, "Service", "X-RestartBatchDelay") {
+        Some(delay) => parse_time_span(delay).unwrap_or_else(|| {
+            eprintln!(
+                "warning: ignoring X-RestartBatchDelay={delay} of {unit}, it is not a time span"
+            );
+            Duration::ZERO
+        }),
+        None => Duration::ZERO,
+    };
+
+    Some(RestartBatch { size, delay })
+}
+
+// Parses a time span the way systemd does (see systemd.time(7)), e.g. `30`, `500ms` or
+// `1min 30s`. Numbers without a unit are seconds.
+fn parse_time_span(span: &str) -> Option<Duration> {
+    let mut rest = span.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number = rest[..number_len].parse::<f64>().ok()?;
+        rest = rest[number_len..].trim_start();
+
+        let unit_len = rest
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(rest.len());
+        let unit_secs = match &rest[..unit_len] {
+            "" | "s" | "sec" | "second" | "seconds" => 1.0,
+            "us" | "usec" | "μs" => 1e-6,
+            "ms" | "msec" => 1e-3,
+            "m" | "min" | "minute" | "minutes" => 60.0,
+            "h" | "hr" | "hour" | "hours" => 60.0 * 60.0,
+            "d" | "day" | "days" => 24.0 * 60.0 * 60.0,
+            "w" | "week" | "weeks" => 7.0 * 24.0 * 60.0 * 60.0,
+            "M" | "month" | "months" => 30.44 * 24.0 * 60.0 * 60.0,
+            "y" | "year" | "years" => 365.25 * 24.0 * 60.0 * 60.0,
+            _ => return None,
+        };
+        rest = rest[unit_len..].trim_start();
+
+        total += Duration::try_from_secs_f64(number * unit_secs).ok()?;
+    }
+
+    Some(total)
+}
+
+// Restarts the instances of `template` in batches. The next batch is only restarted once all
+// instances of the previous one are active again. An instance that is still activating once its
+// restart job finished, e.g. because it crashes and is restarted automatically, is not back up. If an instance doesn't come back up, the
+// remaining instances are left alone so that they keep serving. Returns whether all instances
+// were restarted.
+fn restart_in_batches(
+    systemd: &impl SystemdManager,
+    template: &str,
+    instances: &[String],
+    batch: RestartBatch,
+) -> Result<bool> {
+    for (i, chunk) in instances.chunks(batch.size).enumerate() {
+        if i > 0 && !batch.delay.is_zero() {
+            std::thread::sleep(batch.delay);
+        }
+
+        eprintln!("restarting instances of {template}: {}", chunk.join(", "));
+        for unit in chunk {
+            if let Err(err) = systemd.submit_job(unit, Job::Restart) {
+                eprintln!("Failed to restart {unit}: {err}");
+            }
+        }
+        systemd.block_on_jobs();
+
+        let mut down = Vec::new();
+        for unit in chunk {
+            if systemd.active_state(unit)? != "active" {
+                down.push(unit.as_str());
+            }
+        }
+        if !down.is_empty() {
+            let remaining = &instances[((i + 1) * batch.size).min(instances.len())..];
+            if !remaining.is_empty() {
+                eprintln!(
+                    "warning: not restarting {} because {} did not come back up",
+                    remaining.join(", "),
+                    down.join(", ")
+                );
+            }
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Splits `units` into waves that can be restarted one after the other. A unit comes in a later wave
+// than the units it is ordered after (`After=`, or `Before=` of the other unit) or requires
+// (`Requires=`). Dependencies on units that are not restarted are ignored. Units in a dependency
+// cycle, and the units depending on them, are restarted together in the last wave.
+fn restart_waves(
+    units: &HashMap<String, ()>,
+    unit_infos: &HashMap<String, UnitInfo>,
+) -> Vec<Vec<String>> {
+    let unit_names = |unit: &str, key: &str| -> Vec<String> {
+        unit_infos
+            .get(unit)
+            .and_then(|unit_info| unit_info.get("Unit"))
+            .and_then(|section| section.get(key))
+            .into_iter()
+            .flatten()
+            .flat_map(|value| value.split_whitespace())
+            .filter(|other| *other != unit && units.contains_key(*other))
+            .map(str::to_string)
+            .collect()
+    };
+
+    // Maps every unit to the units that have to be restarted before it.
+    let mut dependencies: HashMap<String, HashMap<String, ()>> = units
+        .keys()
+        .map(|unit| (unit.clone(), HashMap::new()))
+        .collect();
+    for unit in units.keys() {
+        for other in unit_names(unit, "After")
+            .into_iter()
+            .chain(unit_names(unit, "Requires"))
+        {
+            dependencies
+                .entry(unit.clone())
+                .or_default()
+                .insert(other, ());
+        }
+        for other in unit_names(unit, "Before") {
+            dependencies
+                .entry(other)
+                .or_default()
+                .insert(unit.clone(), ());
+        }
+    }
+
+    let mut waves = Vec::new();
+    while !dependencies.is_empty() {
+        let ready = dependencies
+            .iter()
+            .filter(|(_, before)| before.keys().all(|other| !dependencies.contains_key(other)))
+            .map(|(unit, _)| (unit.clone(), ()))
+            .collect::<HashMap<_, _>>();
+        if ready.is_empty() {
+            let cycle = dependencies.into_keys().map(|unit| (unit, ())).collect();
+            let cycle = sorted_units(&cycle);
+            eprintln!(
+                "warning: the following units depend on each other in a cycle and are restarted together: {}",
+                cycle.join(", ")
+            );
+            waves.push(cycle);
+            break;
+        }
+
+        dependencies.retain(|unit, _| !ready.contains_key(unit));
+        waves.push(sorted_units(&ready));
+    }
+
+    waves
+}
+
+// Restarts sysinit-reactivation.target. Returns false if the job could not be submitted.
+fn reactivate_sysinit(systemd: &impl SystemdManager) -> bool {
+    let mut success = true;
+
+    // Restart sysinit-reactivation.target. This target only exists to restart services ordered
+    // before sysinit.target. We cannot use X-StopOnReconfiguration to restart sysinit.target
+    // because then ALL services of the system would be restarted since all normal services have a
+    // default dependency on sysinit.target. sysinit-reactivation.target ensures that services
+    // ordered BEFORE sysinit.target get re-started in the correct order. Ordering between these
+    // services is respected.
+    eprintln!("restarting {SYSINIT_REACTIVATION_TARGET}");
+    if let Err(err) = systemd.submit_job(SYSINIT_REACTIVATION_TARGET, Job::Restart) {
+        eprintln!("Failed to restart {SYSINIT_REACTIVATION_TARGET}: {err}");
+        success = false;
+    }
+
+    // Wait for the restart job of sysinit-reactivation.service to finish
+    systemd.block_on_jobs();
+
+    success
+}
+
+// Reloads, restarts and starts the units of the plan, in this order. Units are looked up in
+// `unit_dir` of the new configuration. Progress is recorded in the journal in `run_dir` if there
+// is one. Returns false if any of the jobs could not be submitted or did not finish successfully.
+fn apply_unit_changes(
+    systemd: &impl SystemdManager,
+    plan: &mut SwitchPlan,
+    unit_dir: &Path,
+    drop_in_dirs: Option<&DropInDirs>,
+    mut journal: Option<(&mut SwitchJournal, &Path)>,
+) -> Result<bool> {
+    let mut success = true;
+
+    // Before reloading we need to ensure that the units are still active. They may have been
+    // deactivated because one of their requirements got stopped. If they are inactive but should
+    // have been reloaded, the user probably expects them to be started.
+    if !plan.units_to_reload.is_empty() {
+        for (unit, _) in plan.units_to_reload.clone() {
+            if !systemd.unit_is_active(&unit)? {
+                // Figure out if we need to start the unit. We skip units that are not found in the
+                // NixOS-managed /etc/systemd/system directory (e.g. mount units that are generated
+                // from /etc/fstab).
+                if parse_unit(
+                    unit_dir.join(&unit).as_path(),
+                    unit_dir.join(&unit).as_path(),
+                    drop_in_dirs,
+                )
+                .map(|unit_info| {
+                    !parse_systemd_bool(Some(&unit_info), "Unit", "RefuseManualStart", false)
+                        || parse_systemd_bool(Some(&unit_info), "Unit", "X-OnlyManualStart", false)
+                })
+                .unwrap_or_default()
+                {
+                    plan.units_to_start.insert(unit.clone(), ());
+                }
+
+                // Don't reload the unit, reloading would fail
+                plan.units_to_reload.remove(&unit);
+            }
+        }
+
+        if let Some((journal, run_dir)) = &mut journal {
+            journal.update(plan);
+            journal.write(run_dir)?;
+        }
+    }
+
+    // Reload units that need it. This includes remounting changed mount units.
+    if !plan.units_to_reload.is_empty() {
+        let units = sorted_units(&plan.units_to_reload);
+        eprintln!("reloading the following units: {}", units.join(", "));
+
+        for unit in units {
+            if let Err(err) = systemd.submit_job(&unit, Job::Reload) {
+                eprintln!("Failed to reload {unit}: {err}");
+                success = false;
+            }
+        }
+
+        systemd.block_on_jobs();
+
+        record_finished_jobs(systemd, &mut journal)?;
+    }
+
+    // Reload units with `X-SwitchMethod=reload-or-restart`, systemd restarts them if they can't be
+    // reloaded.
+    if !plan.units_to_reload_or_restart.is_empty() {
+        let units = sorted_units(&plan.units_to_reload_or_restart);
+        eprintln!(
+            "reloading or restarting the following units: {}",
+            units.join(", ")
+        );
+
+        for unit in units {
+            if let Err(err) = systemd.submit_job(&unit, Job::ReloadOrRestart) {
+                eprintln!("Failed to reload or restart {unit}: {err}");
+                success = false;
+            }
+        }
+
+        systemd.block_on_jobs();
+
+        record_finished_jobs(systemd, &mut journal)?;
+    }
+
+    // Signal units with `X-SwitchMethod=kill-signal:<signal>`.
+    for (unit, signal) in &plan.units_to_signal {
+        eprintln!("sending {signal} to {unit}");
+        match systemd.kill_unit(unit, *signal) {
+            Ok(()) => {
+                if let Some((journal, _)) = &mut journal {
+                    journal.finish_signal(unit);
+                }
+            }
+            Err(err) => {
+                eprintln!("{err:#}");
+                success = false;
+            }
+        }
+    }
+    if !plan.units_to_signal.is_empty() {
+        record_finished_jobs(systemd, &mut journal)?;
+    }
+
+    // Restart changed services (those that have to be restarted rather than stopped and started).
+    // Units are restarted in waves so that a unit is only restarted once the units it is ordered
+    // after or requires have come back up.
+    if !plan.units_to_restart.is_empty() {
+        eprintln!(
+            "restarting the following units: {}",
+            sorted_units(&plan.units_to_restart).join(", ")
+        );
+
+        let mut unit_infos = HashMap::new();
+        // Batch settings of the templates of the restarted instances, read from the first instance.
+        let mut batches = HashMap::new();
+        // Instances of templates that are restarted in batches, keyed by instance.
+        let mut batched = HashMap::new();
+        for unit in plan.units_to_restart.keys() {
+            let (base_unit, _) = base_unit_names(unit, &[unit_dir])?;
+            if let Ok(unit_info) = parse_unit(
+                &unit_dir.join(unit),
+                &unit_dir.join(&base_unit),
+                drop_in_dirs,
+            ) {
+                if base_unit != *unit {
+                    let batch = *batches
+                        .entry(base_unit.clone())
+                        .or_insert_with(|| restart_batch(&base_unit, &unit_info));
+                    if let Some(batch) = batch {
+                        batched.insert(unit.clone(), (base_unit, batch));
+                    }
+                }
+                unit_infos.insert(unit.clone(), unit_info);
+            }
+        }
+
+        let waves = restart_waves(&plan.units_to_restart, &unit_infos);
+        for (i, units) in waves.iter().enumerate() {
+            if waves.len() > 1 {
+                log::debug!("restart wave {}: {}", i + 1, units.join(", "));
+            }
+
+            let mut templates: BTreeMap<&str, (RestartBatch, Vec<String>)> = BTreeMap::new();
+            for unit in units {
+                if let Some((template, batch)) = batched.get(unit) {
+                    templates
+                        .entry(template)
+                        .or_insert_with(|| (*batch, Vec::new()))
+                        .1
+                        .push(unit.clone());
+                } else if let Err(err) = systemd.submit_job(unit, Job::Restart) {
+                    eprintln!("Failed to restart {unit}: {err}");
+                    success = false;
+                }
+            }
+
+            systemd.block_on_jobs();
+
+            for (template, (batch, instances)) in templates {
+                if !restart_in_batches(systemd, template, &instances, batch)? {
+                    success = false;
+                }
+            }
+
+            // A resumed switch continues with the next wave.
+            record_finished_jobs(systemd, &mut journal)?;
+        }
+    }
+
+    // Restart units with `X-SwitchMethod=try-restart` that are still running. This happens after
+    // the other restarts so units that were stopped along the way stay stopped.
+    if !plan.units_to_try_restart.is_empty() {
+        let units = sorted_units(&plan.units_to_try_restart);
+        eprintln!(
+            "restarting the following units if they are running: {}",
+            units.join(", ")
+        );
+
+        for unit in units {
+            if let Err(err) = systemd.submit_job(&unit, Job::TryRestart) {
+                eprintln!("Failed to try-restart {unit}: {err}");
+                success = false;
+            }
+        }
+
+        systemd.block_on_jobs();
+
+        record_finished_jobs(systemd, &mut journal)?;
+    }
+
+    // Start all active targets, as well as changed units we stopped above. The latter is necessary
+    // because some may not be dependencies of the targets (i.e., they were manually started).
+    // Aliases have already been collapsed into the units they refer to while planning.
+    let units_to_start_filtered = filter_units(&plan.units_to_filter, &plan.units_to_start);
+    if !units_to_start_filtered.is_empty() {
+        eprintln!(
+            "starting the following units: {}",
+            sorted_units(&units_to_start_filtered).join(", ")
+        );
+    }
+
+    for unit in plan.units_to_start.keys() {
+        if let Err(err) = systemd.submit_job(unit, Job::Start) {
+            eprintln!("Failed to start {unit}: {err}");
+            success = false;
+        }
+    }
+
+    systemd.block_on_jobs();
+
+    // All jobs ran, so there is nothing left to resume.
+    if let Some((_, run_dir)) = journal {
+        SwitchJournal::remove(run_dir)?;
+    }
+
+    for (unit, job, result) in systemd.finished_jobs() {
+        match result.as_str() {
+            "timeout" | "failed" | "dependency" => {
+                eprintln!("Failed to {job} {unit}");
+                success = false;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(success)
+}
+
+// Marks the jobs that finished so far as done in the journal and writes it, if there is one.
+fn record_finished_jobs(
+    systemd: &impl SystemdManager,
+    journal: &mut Option<(&mut SwitchJournal, &Path)>,
+) -> Result<()> {
+    if let Some((journal, run_dir)) = journal {
+        journal.finish_jobs(&systemd.finished_jobs());
+        journal.write(run_dir)?;
+    }
+
+    Ok(())
+}
+
+// Restarts the units whose restart was deferred, or only `units` if it is not empty, and forgets
+// the units that were restarted successfully. Units that are no longer running are forgotten
+// without restarting them. The units are restarted in waves like during a switch, their unit files
+// are looked up in `unit_dir`. Returns false if a unit has no pending restart or failed to restart.
+fn apply_pending_restarts(
+    systemd: &impl SystemdManager,
+    pending: &mut PendingRestarts,
+    units: &[String],
+    unit_dir: &Path,
+) -> Result<bool> {
+    let mut success = true;
+
+    for unit in units {
+        if !pending.units.contains_key(unit) {
+            eprintln!("{unit} has no pending restart");
+            success = false;
+        }
+    }
+
+    let mut units_to_restart = HashMap::new();
+    for unit in pending.units.keys().cloned().collect::<Vec<_>>() {
+        if !units.is_empty() && !units.contains(&unit) {
+            continue;
+        }
+
+        if systemd.unit_is_active(&unit)? {
+            units_to_restart.insert(unit, ());
+        } else {
+            eprintln!("{unit} is not running anymore");
+            pending.units.remove(&unit);
+        }
+    }
+
+    if units_to_restart.is_empty() {
+        return Ok(success);
+    }
+
+    let failed_units = restart_units(systemd, &units_to_restart, unit_dir);
+    for unit in units_to_restart.keys() {
+        if !failed_units.contains(unit) {
+            pending.units.remove(unit);
+        }
+    }
+
+    Ok(success && failed_units.is_empty())
+}
+
+// Restarts units outside of a switch, in waves like during a switch. Their unit files are looked
+// up in `unit_dir`. Returns the units that failed to restart.
+fn restart_units(
+    systemd: &impl SystemdManager,
+    units: &HashMap<String, ()>,
+    unit_dir: &Path,
+) -> HashSet<String> {
+    eprintln!(
+        "restarting the following units: {}",
+        sorted_units(units).join(", ")
+    );
+
+    let unit_infos = units
+        .keys()
+        .filter_map(|unit| {
+            let (base_unit, _) = base_unit_names(unit, &[unit_dir]).ok()?;
+            parse_unit(&unit_dir.join(unit), &unit_dir.join(base_unit), None)
+                .ok()
+                .map(|unit_info| (unit.clone(), unit_info))
+        })
+        .collect();
+
+    let mut failed_units = HashSet::new();
+    for wave in restart_waves(units, &unit_infos) {
+        for unit in wave {
+            if let Err(err) = systemd.submit_job(&unit, Job::Restart) {
+                eprintln!("Failed to restart {unit}: {err}");
+                failed_units.insert(unit);
+            }
+        }
+
+        systemd.block_on_jobs();
+    }
+
+    // Only the last restart of a unit counts, an earlier one may have been part of the switch.
+    let mut results = HashMap::new();
+    for (unit, job, result) in systemd.finished_jobs() {
+        if job == Job::Restart && units.contains_key(&unit) {
+            results.insert(unit, result);
+        }
+    }
+    for (unit, result) in results {
+        if result != "done" {
+            eprintln!("Failed to restart {unit}");
+            failed_units.insert(unit);
+        }
+    }
+
+    failed_units
+}
+
+// Returns the store path that a file belongs to, e.g. /nix/store/...-glibc-2.40 for a library in
+// it. Files that were deleted after they were opened are marked with " (deleted)" by the kernel.
+fn store_path_of(path: &str) -> Option<&str> {
+    let path = path.strip_suffix(" (deleted)").unwrap_or(path);
+    let name = path.strip_prefix("/nix/store/")?;
+    let end = name.find('/').unwrap_or(name.len());
+    (end > 0).then(|| &path[.."/nix/store/".len() + end])
+}
+
+// Returns the store paths whose files a process has mapped into memory, including its executable.
+fn process_store_paths(proc_root: &Path, pid: &str) -> BTreeSet<String> {
+    let proc_dir = proc_root.join(pid);
+    let mut paths = BTreeSet::new();
+
+    if let Ok(exe) = std::fs::read_link(proc_dir.join("exe")) {
+        if let Some(path) = exe.to_str().and_then(store_path_of) {
+            paths.insert(path.to_string());
+        }
+    }
+
+    // The path is the last column of a mapping and may contain spaces.
+    for line in std::fs::read_to_string(proc_dir.join("maps"))
+        .unwrap_or_default()
+        .lines()
+    {
+        if let Some(path) = line
+            .find(" /nix/store/")
+            .and_then(|start| store_path_of(&line[start + 1..]))
+        {
+            paths.insert(path.to_string());
+        }
+    }
+
+    paths
+}
+
+// Returns the processes of a cgroup and of all cgroups below it.
+fn cgroup_processes(cgroup_dir: &Path) -> Vec<String> {
+    let mut pids = std::fs::read_to_string(cgroup_dir.join("cgroup.procs"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    for entry in std::fs::read_dir(cgroup_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            pids.extend(cgroup_processes(&entry.path()));
+        }
+    }
+
+    pids
+}
+
+// Returns the store paths in the closure of a configuration.
+fn store_closure(toplevel: &Path) -> Result<HashSet<String>> {
+    let nix_store = toplevel.join("sw/bin/nix-store");
+    let output = std::process::Command::new(if nix_store.exists() {
+        nix_store.as_path()
+    } else {
+        Path::new("nix-store")
+    })
+    .args(["--query", "--requisites"])
+    .arg(toplevel)
+    .output()
+    .context("Failed to run nix-store")?;
+    if !output.status.success() {
+        bail!(
+            "nix-store --query --requisites {} failed: {}",
+            toplevel.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+// Parses a unit of the configuration in `unit_dir`. Returns None for units that are not part of
+// the configuration, like transient units and units created by generators.
+fn configured_unit_info(unit_dir: &Path, unit: &str) -> Option<UnitInfo> {
+    let (base_unit, _) = base_unit_names(unit, &[unit_dir]).ok()?;
+    parse_unit(&unit_dir.join(unit), &unit_dir.join(base_unit), None).ok()
+}
+
+// Returns the running services of the configuration in `unit_dir` whose processes use store paths
+// that are not in `closure`, together with these store paths. The processes of a service are found
+// through its cgroup, so this also catches processes that were forked off the main process.
+fn stale_services(
+    systemd: &impl SystemdManager,
+    closure: &HashSet<String>,
+    unit_dir: &Path,
+    cgroup_root: &Path,
+    proc_root: &Path,
+) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let mut stale = BTreeMap::new();
+
+    for (unit, unit_state) in systemd.active_units()? {
+        if !unit.ends_with(".service") || unit_state.state != "active" {
+            continue;
+        }
+        let Some(unit_info) = configured_unit_info(unit_dir, &unit) else {
+            continue;
+        };
+
+        // The unit may have stopped in the meantime.
+        let Ok(control_group) = systemd.control_group(&unit) else {
+            continue;
+        };
+        if control_group.is_empty() {
+            continue;
+        }
+
+        // Restarting a unit only stops the processes that its `KillMode=` kills. The other ones
+        // keep running, like the builds of nix-daemon.service, which has `KillMode=process`.
+        let pids = match unit_value(&unit_info, "Service", "KillMode") {
+            Some("none") => continue,
+            Some("process") => match systemd.main_pid(&unit) {
+                Ok(pid) if pid != 0 => vec![pid.to_string()],
+                _ => continue,
+            },
+            _ => cgroup_processes(&cgroup_root.join(control_group.trim_start_matches('/'))),
+        };
+        let paths = pids
+            .iter()
+            .flat_map(|pid| process_store_paths(proc_root, pid))
+            .filter(|path| !closure.contains(path))
+            .collect::<BTreeSet<_>>();
+        if !paths.is_empty() {
+            stale.insert(unit, paths);
+        }
+    }
+
+    Ok(stale)
+}
+
+// Restarts the stale services that may be restarted by a switch. Returns false if any of them
+// failed to restart.
+fn restart_stale_services(
+    systemd: &impl SystemdManager,
+    stale: &BTreeMap<String, BTreeSet<String>>,
+    unit_dir: &Path,
+) -> bool {
+    let mut units_to_restart = HashMap::new();
+    let mut units_to_skip = Vec::new();
+    for unit in stale.keys() {
+        let unit_info = configured_unit_info(unit_dir, unit);
+        let deferred =
+            parse_systemd_bool(unit_info.as_ref(), "Service", "X-RestartDeferred", false)
+                || unit_info
+                    .as_ref()
+                    .and_then(|unit_info| unit_value(unit_info, "Service", "X-SwitchMethod"))
+                    .is_some_and(|method| matches!(method, "defer" | "none"));
+        if unit_info.is_some() && unit_may_be_stopped(unit_info.as_ref()) && !deferred {
+            units_to_restart.insert(unit.clone(), ());
+        } else {
+            units_to_skip.push(unit.as_str());
+        }
+    }
+
+    if !units_to_skip.is_empty() {
+        eprintln!(
+            "NOT restarting the following outdated units: {}",
+            units_to_skip.join(", ")
+        );
+    }
+
+    units_to_restart.is_empty() || restart_units(systemd, &units_to_restart, unit_dir).is_empty()
+}
+
+// Waits for systemd to settle after a switch and returns the units that failed and the units that
+// are active now but were not active before, both sorted.
+fn failed_and_new_units(
+    systemd: &impl SystemdManager,
+    previously_active_units: &HashMap<String, UnitState>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut failed_units = Vec::new();
+    let mut new_units = Vec::new();
+
+    systemd.settle();
+
+    for (unit, unit_state) in systemd.active_units()? {
+        if &unit_state.state == "failed" {
+            failed_units.push(unit);
+            continue;
+        }
+
+        if unit_state.substate == "auto-restart" && unit.ends_with(".service") {
+            // A unit in auto-restart substate is a failure *if* it previously failed to start
+            if systemd.exec_main_status(&unit)? != 0 {
+                failed_units.push(unit);
+                continue;
+            }
+        }
+
+        // Ignore scopes since they are not managed by this script but rather created and managed
+        // by third-party services via the systemd dbus API. This only lists units that are not
+        // failed (including ones that are in auto-restart but have not failed previously)
+        if unit_state.state != "failed"
+            && !previously_active_units.contains_key(&unit)
+            && !unit.ends_with(".scope")
+        {
+            new_units.push(unit);
+        }
+    }
+
+    failed_units.sort_by_key(|name| name.to_lowercase());
+    new_units.sort_by_key(|name| name.to_lowercase());
+
+    Ok((failed_units, new_units))
+}
+
+fn remove_file_if_exists(p: impl AsRef<Path>) -> std::io::Result<()> {
+    match std::fs::remove_file(p) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+// How switching the units of one user ended.
+#[derive(Debug, PartialEq)]
+enum UserSwitchStatus {
+    Done,
+    // The user switch exited unsuccessfully, with the reason.
+    Failed(String),
+    // The user switch was killed because it did not finish in time.
+    TimedOut,
+}
+
+#[derive(Debug)]
+struct UserSwitch {
+    user: String,
+    status: UserSwitchStatus,
+    // User units that were failed after the switch, as printed by the user switch on stdout.
+    failed_units: Vec<String>,
+}
+
+// Waits for the user switches in `children` (keyed by user name) that all run at the same time.
+// Switches that are still running after `timeout` are killed.
+fn wait_for_user_switches(
+    mut children: Vec<(String, std::process::Child)>,
+    timeout: Duration,
+) -> Vec<UserSwitch> {
+    let deadline = Instant::now() + timeout;
+    let mut user_switches = Vec::new();
+
+    while !children.is_empty() {
+        let mut i = 0;
+        while i < children.len() {
+            let child = &mut children[i].1;
+            let status = match child.try_wait() {
+                Ok(Some(status)) if status.success() => UserSwitchStatus::Done,
+                Ok(Some(status)) => UserSwitchStatus::Failed(status.to_string()),
+                Ok(None) if Instant::now() < deadline => {
+                    i += 1;
+                    continue;
+                }
+                Ok(None) => {
+                    _ = child.kill();
+                    _ = child.wait();
+                    UserSwitchStatus::TimedOut
+                }
+                Err(err) => UserSwitchStatus::Failed(err.to_string()),
+            };
+
+            let (user, mut child) = children.swap_remove(i);
+            let mut output = String::new();
+            if let Some(mut stdout) = child.stdout.take() {
+                _ = stdout.read_to_string(&mut output);
+            }
+            user_switches.push(UserSwitch {
+                user,
+                status,
+                failed_units: output.lines().map(String::from).collect(),
+            });
+        }
+
+        if !children.is_empty() {
+            std::thread::sleep(USER_SWITCH_POLL_INTERVAL);
+        }
+    }
+
+    user_switches.sort_by(|a, b| a.user.cmp(&b.user));
+    user_switches
+}
+
+/// Performs switch-to-configuration functionality for a single non-root user
+// Builds the command that switches the user manager of the user `uid`. The environment is cleared,
+// so everything the user switch needs is passed explicitly.
+fn user_switch_command(
+    myself: &Path,
+    uid: u32,
+    gid: u32,
+    home: Option<&Path>,
+    runtime_path: &str,
+    toplevel: &Path,
+    previous_toplevel: Option<&Path>,
+) -> std::process::Command {
+    let mut command = std::process::Command::new(myself);
+    command
+        .uid(uid)
+        .gid(gid)
+        .env_clear()
+        .env("XDG_RUNTIME_DIR", runtime_path)
+        .env("__NIXOS_SWITCH_TO_CONFIGURATION_PARENT_EXE", myself)
+        .env("TOPLEVEL", toplevel)
+        .stdout(std::process::Stdio::piped());
+    if let Some(home) = home {
+        command.env("HOME", home);
+    }
+    if let Some(previous_toplevel) = previous_toplevel {
+        command.env(
+            "__NIXOS_SWITCH_TO_CONFIGURATION_PREVIOUS_TOPLEVEL",
+            previous_toplevel,
+        );
+    }
+    command
+}
+
+fn do_user_switch(parent_exe: String) -> anyhow::Result<()> {
+    if Path::new(&parent_exe)
+        != Path::new("/proc/self/exe")
+            .canonicalize()
+            .context("Failed to get full path to current executable")?
+            .as_path()
+    {
+        eprintln!(
+            r#"This program is not meant to be called from outside of switch-to-configuration."#
+        );
+        die();
+    }
+
+    let dbus_conn = LocalConnection::new_session().context("Failed to open dbus connection")?;
+    let systemd = DbusSystemdManager::new(&dbus_conn, JobTimeouts::default(), None, None)?;
+    let toplevel = PathBuf::from(required_env("TOPLEVEL")?);
+
+    // Compare the user units of the previous configuration with the new ones the same way as the
+    // system units. Without a previous configuration, only the activation runs. Whether the user
+    // manager needs to be re-executed doesn't matter because it always is.
+    let mut plan = match std::env::var_os("__NIXOS_SWITCH_TO_CONFIGURATION_PREVIOUS_TOPLEVEL") {
+        Some(previous_toplevel) => plan_switch(
+            &SystemSnapshot {
+                root: PathBuf::from(previous_toplevel),
+                drop_in_dirs: DropInDirs::user(|name| std::env::var_os(name)),
+                unit_dir: USER_UNIT_DIR,
+                active_units: systemd.active_units()?,
+                pid1_path: PathBuf::new(),
+                pending: SwitchPlan::default(),
+                fstab_units: HashMap::new(),
+            },
+            &toplevel,
+            Path::new(""),
+            &HashMap::new(),
+            false,
+            false,
+        )?,
+        None => SwitchPlan::default(),
+    };
+
+    stop_units(&systemd, &plan);
+
+    // The systemd user session seems to not send a Reloaded signal, so we don't have anything to
+    // wait on here.
+    _ = systemd1_proxy(&dbus_conn).reexecute();
+
+    log::debug!("waiting for nixos activation to finish");
+    systemd
+        .submit_job("nixos-activation.service", Job::Restart)
+        .context("Failed to restart nixos-activation.service")?;
+    systemd.block_on_jobs();
+
+    // This also reports a failed nixos-activation.service.
+    let success = apply_unit_changes(
+        &systemd,
+        &mut plan,
+        &toplevel.join(USER_UNIT_DIR),
+        Some(&DropInDirs::user(|name| std::env::var_os(name))),
+        None,
+    )?;
+
+    // Report the failed units to the system switch, one per line.
+    let failed_units = systemd1_proxy(&dbus_conn)
+        .list_units_by_patterns(vec!["failed"], Vec::new())
+        .context("Failed to list failed units")?;
+    for (unit, ..) in &failed_units {
+        println!("{unit}");
+    }
+
+    if !success || !failed_units.is_empty() {
+        std::process::exit(4);
+    }
+
+    Ok(())
+}
+
+fn usage(argv0: &str) -> ! {
+    eprintln!(
+        r#"Usage: {argv0} [check|switch|boot|test|dry-activate|status|resume|soft-reboot]
+       [--json] [--rollback-on-failure] [--explain] [--soft-reboot-fallback]
+       [--restart-stale] [--specialisation <name>]
+       {argv0} diff <old-toplevel> <new-toplevel> [--json] [--explain]
+       {argv0} pending [--json | --apply [<unit>...]]
+       {argv0} history [--json] [<count>]
+check:        run pre-switch checks and exit
+switch:       make the configuration the boot default and activate now
+boot:         make the configuration the boot default
+test:         activate the configuration, but don't make it the boot default
+dry-activate: show what would be done if this configuration were activated
+status:       show the progress of a running or interrupted switch
+resume:       continue an interrupted switch to this configuration
+soft-reboot:  make the configuration the boot default and soft-reboot into it
+diff:         show what switching between two configurations would do, without root
+pending:      show the units whose restart was deferred
+history:      show the last <count> or all recorded switches, without root
+
+--json:                 with dry-activate, status, diff, history or pending without --apply,
+                        print the result as JSON on stdout
+--rollback-on-failure:  with switch or test, activate the previous configuration again if
+                        units failed
+--explain:              with switch, test, dry-activate or diff, show how changed units
+                        differ and why they are restarted or reloaded
+--soft-reboot-fallback: with switch, soft-reboot if the new init is incompatible instead of
+                        asking for a reboot
+--restart-stale:        with switch or test, restart services that still use store paths that
+                        are not part of the new configuration
+--specialisation:       with switch, boot, test, dry-activate, check or soft-reboot, use the
+                        specialisation with the given name, switch, boot and soft-reboot
+                        also make it the default boot entry
+--apply:                with pending, restart the given units or all units whose restart was
+                        deferred
+"#
+    );
+    std::process::exit(1);
+}
+
+// Reports what switching from the configuration at `old` to the one at `new` would do. This only
+// reads the two configurations and works without root or a running systemd.
+fn do_diff(old: &Path, new: &Path, options: &Options) -> Result<()> {
+    // Anyone can run this, so the generator output goes to a directory only this process can use.
+    let scratch_dir = ScratchDir::new("switch-to-configuration-diff")?;
+    let [old_fstab_units, new_fstab_units] = [old, new].map(|toplevel| {
+        generate_fstab_units(
+            &toplevel.join("systemd"),
+            &toplevel.join("etc/fstab"),
+            &scratch_dir.path.join(FSTAB_GENERATOR_DIR),
+        )
+    });
+    let diff = diff_configurations(old, new, old_fstab_units?, &new_fstab_units?)?;
+
+    if options.json {
+        let document = ConfigurationDiffDocument {
+            version: SWITCH_PLAN_VERSION,
+            old,
+            new,
+            diff: &diff,
+        };
+
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &document)
+            .context("Failed to serialize configuration diff")?;
+        writeln!(&mut stdout).context("Failed to write configuration diff")?;
+
+        return Ok(());
+    }
+
+    let plan = &diff.plan;
+    if diff.init_interface_changed {
+        println!("the init interface changed, switching needs a reboot");
+    }
+
+    if !diff.added_units.is_empty() {
+        println!("added units: {}", diff.added_units.join(", "));
+    }
+
+    if !diff.removed_units.is_empty() {
+        println!("removed units: {}", diff.removed_units.join(", "));
+    }
+
+    for unit in &diff.changed_units {
+        println!("changed unit {unit} ({})", planned_action(plan, unit));
+        if options.explain {
+            for difference in plan.unit_differences.get(unit).into_iter().flatten() {
+                println!("  {difference}");
+            }
+        }
+    }
+
+    for mount in &plan.mounts {
+        println!(
+            "mount {} ({}): {}",
+            mount.mountpoint,
+            mount.unit,
+            planned_action(plan, &mount.unit)
+        );
+    }
+
+    for device in &plan.swaps_to_stop {
+        println!("swap device {device}: stop");
+    }
+
+    for change in &plan.swaps_to_reapply {
+        println!(
+            "swap device {}: change options from {} to {}",
+            change.device, change.old_options, change.new_options
+        );
+    }
+
+    for device in &plan.swaps_to_start {
+        println!("swap device {device}: start");
+    }
+
+    if plan.restart_systemd {
+        println!("systemd would be re-executed");
+    }
+
+    for change in &plan.reboot_required {
+        println!("{} changed, this needs a reboot", change.component);
+    }
+
+    Ok(())
+}
+
+/// Performs switch-to-configuration functionality for the entire system
+fn do_system_switch(action: Action, options: Options) -> anyhow::Result<()> {
+    log::debug!("Performing system switch");
+
+    let out = PathBuf::from(required_env("OUT")?);
+    let toplevel = PathBuf::from(required_env("TOPLEVEL")?);
+    let distro_id = required_env("DISTRO_ID")?;
+    let pre_switch_check = required_env("PRE_SWITCH_CHECK")?;
+    let install_bootloader = required_env("INSTALL_BOOTLOADER")?;
+    let locale_archive = required_env("LOCALE_ARCHIVE")?;
+    let new_systemd = PathBuf::from(required_env("SYSTEMD")?);
+    // The time limit of the whole switch counts from here.
+    let started = switch_started();
+    let timeouts = JobTimeouts::from_env()?;
+    let deadline = timeouts.deadline(started);
+    let log_level = if std::env::var("STC_DEBUG").is_ok() {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    let action = &action;
+    log::debug!("Using action {:?}", action);
+
+    // A switch that continues in a transient service. The checks, the bootloader installation and
+    // the sync were already done before it was detached.
+    let detached = std::env::var(DETACHED_SWITCH_ENV).is_ok();
+
+    // The action that is to be performed (like switch, boot, test, dry-activate) Also exposed via
+    // environment variable from now on
+    std::env::set_var("NIXOS_ACTION", Into::<&'static str>::into(action));
+
+    // Passed on to the detached switch and the specialisation.
+    std::env::set_var(
+        SWITCH_STARTED_ENV,
+        started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string(),
+    );
+
+    // Expose the locale archive as an environment variable for systemctl and the activation script
+    if !locale_archive.is_empty() {
+        std::env::set_var("LOCALE_ARCHIVE", locale_archive);
+    }
+
+    let os_release = parse_os_release().context("Failed to parse os-release")?;
+
+    let distro_id_re = Regex::new(format!("^\"?{distro_id}\"?$").as_str())
+        .context("Invalid regex for distro ID")?;
+
+    // This is a NixOS installation if it has /etc/NIXOS or a proper /etc/os-release.
+    if !Path::new("/etc/NIXOS").is_file()
+        && !os_release
+            .get("ID")
+            .map(|id| distro_id_re.is_match(id))
+            .unwrap_or_default()
+    {
+        eprintln!("This is not a NixOS installation!");
+        die();
+    }
+
+    // The specialisation takes the lock and does everything else itself.
+    if let Some(name) = &options.specialisation {
+        return switch_to_specialisation(&toplevel, name, action);
+    }
+
+    let run_dir = Path::new(RUN_DIR);
+    std::fs::create_dir_all(run_dir).context("Failed to create /run/nixos directory")?;
+    let perms = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(run_dir, perms)
+        .context("Failed to set permissions on /run/nixos directory")?;
+
+    log::debug!("Creating lock file /run/nixos/switch-to-configuration.lock");
+    let Ok(lock) = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open("/run/nixos/switch-to-configuration.lock")
+    else {
+        eprintln!("Could not open lock");
+        die();
+    };
+
+    log::debug!("Acquiring lock on file /run/nixos/switch-to-configuration.lock");
+    // A detached switch waits for the switch that started it to release the lock.
+    let switch_lock = Flock::lock(
+        lock,
+        if detached {
+            FlockArg::LockExclusive
+        } else {
+            FlockArg::LockExclusiveNonblock
+        },
+    );
+
+    // Looking at the journal doesn't need the lock, not getting it means that a switch is running.
+    if *action == Action::Status {
+        return print_switch_status(run_dir, switch_lock.is_err(), options.json);
+    }
+
+    if *action == Action::Pending && !options.apply {
+        return print_pending_restarts(run_dir, options.json);
+    }
+
+    let Ok(switch_lock) = switch_lock else {
+        eprintln!("Could not acquire lock");
+        die();
+    };
+
+    if syslog::init(Facility::LOG_USER, log_level, Some("nixos")).is_err() {
+        bail!("Failed to initialize logger");
+    }
+
+    // Deferred restarts are applied to the running configuration, nothing is switched.
+    if *action == Action::Pending {
+        let dbus_conn = LocalConnection::new_system().context("Failed to open dbus connection")?;
+        let systemd = DbusSystemdManager::new(&dbus_conn, timeouts, deadline, None)?;
+
+        let mut pending_restarts = PendingRestarts::read(run_dir)?;
+        let success = apply_pending_restarts(
+            &systemd,
+            &mut pending_restarts,
+            &options.units,
+            &Path::new("/").join(SYSTEM_UNIT_DIR),
+        )?;
+        pending_restarts.write(run_dir)?;
+
+        std::process::exit(if success { 0 } else { 4 });
+    }
+
+    // The checks already passed before the switch that is resumed was started.
+    if *action != Action::Resume
+        && !detached
+        && std::env::var("NIXOS_NO_CHECK")
+            .as_deref()
+            .unwrap_or_default()
+            != "1"
+    {
+        do_pre_switch_check(&pre_switch_check, &toplevel, action)?;
+        log::debug!("Done performing pre-switch checks");
+    }
+
+    if *action == Action::Check {
+        return Ok(());
+    }
+
+    let mut guard = SwitchGuard::new(action, &toplevel, run_dir);
+
+    // Remember what we are switching away from so that we can go back to it.
+    let previous_toplevel =
+        if options.rollback_on_failure && matches!(action, Action::Switch | Action::Test) {
+            Path::new("/run/current-system").canonicalize().ok()
+        } else {
+            None
+        };
+    let previous_specialisation = match &previous_toplevel {
+        Some(previous_toplevel) => RunningSpecialisation::read(run_dir, previous_toplevel)?,
+        None => None,
+    };
+
+    // Install or update the bootloader. The specialisation that becomes the default boot entry is
+    // only recorded once that worked.
+    if matches!(action, Action::Switch | Action::Boot | Action::SoftReboot) && !detached {
+        match std::env::var_os(SPECIALISATION_OF_ENV) {
+            Some(parent) => {
+                let name = std::env::var(SPECIALISATION_ENV).ok().filter(|_| {
+                    bootloader_selects_specialisation(
+                        &std::env::var("BOOTLOADER_ID").unwrap_or_default(),
+                    )
+                });
+                do_install_bootloader(&install_bootloader, Path::new(&parent), name.as_deref())?;
+                record_boot_specialisation(name.as_deref())?;
+            }
+            None => {
+                let name = boot_specialisation(action, &toplevel);
+                do_install_bootloader(&install_bootloader, &toplevel, name.as_deref())?;
+                // Switching to a configuration itself forgets the recorded specialisation.
+                if matches!(action, Action::Switch | Action::SoftReboot) {
+                    record_boot_specialisation(None)?;
+                }
+            }
+        }
+        log::debug!("Done performing bootloader installation");
+    }
+
+    // Just in case the new configuration hangs the system, do a sync now.
+    if !detached
+        && std::env::var("NIXOS_NO_SYNC")
+            .as_deref()
+            .unwrap_or_default()
+            != "1"
+    {
+        let fd = nix::fcntl::open("/nix/store", OFlag::O_NOCTTY, Mode::S_IROTH)
+            .context("Failed to open /nix/store")?;
+        nix::unistd::syncfs(fd).context("Failed to sync /nix/store")?;
+    }
+
+    if *action == Action::Boot {
+        guard.exit(None, 0);
+    }
+
+    if *action == Action::SoftReboot {
+        do_soft_reboot(&out, &toplevel, run_dir)?;
+        guard.exit(None, 0);
+    }
+
+    let current_init_interface_version =
+        std::fs::read_to_string("/run/current-system/init-interface-version").unwrap_or_default();
+
+    let new_init_interface_version =
+        std::fs::read_to_string(toplevel.join("init-interface-version"))
+            .context("File init-interface-version should exist")?;
+
+    // Check if we can activate the new configuration.
+    if current_init_interface_version != new_init_interface_version {
+        // The bootloader already points at the new configuration, so it can be soft-rebooted into
+        // right away.
+        if *action == Action::Switch && options.soft_reboot_fallback {
+            eprintln!("the new NixOS configuration has an incompatible ‘init’, soft-rebooting");
+            do_soft_reboot(&out, &toplevel, run_dir)?;
+            guard.exit(None, 0);
+        }
+
+        eprintln!(
+            r#"Warning: the new NixOS configuration has an ‘init’ that is
+incompatible with the current configuration.  The new configuration
+won't take effect until you reboot the system.
+"#
+        );
+        guard.exit(None, 100);
+    }
+
+    // Ignore SIGHUP so that we're not killed if we're running on (say) virtual console 1 and we
+    // restart the "tty1" unit.
+    let handler = SigHandler::Handler(handle_sigpipe);
+    unsafe { signal::signal(Signal::SIGPIPE, handler) }.context("Failed to set SIGPIPE handler")?;
+
+    // Other programs can follow the switch on D-Bus. Not being able to publish it doesn't fail the
+    // switch, e.g. the bus policy of the running system may not allow it yet.
+    let progress = if *action == Action::DryActivate {
+        None
+    } else {
+        match SyncConnection::new_system()
+            .map_err(anyhow::Error::from)
+            .and_then(|conn| SwitchProgress::publish(conn, action, &toplevel))
+        {
+            Ok(progress) => Some(progress),
+            Err(err) => {
+                log::warn!("unable to publish the progress of the switch on D-Bus: {err:#}");
+                None
+            }
+        }
+    };
+
+    guard.progress = progress.clone();
+
+    let dbus_conn = LocalConnection::new_system().context("Failed to open dbus connection")?;
+    let systemd = DbusSystemdManager::new(&dbus_conn, timeouts, deadline, progress.clone())?;
+    let logind = login1_proxy(&dbus_conn);
+
+    let interrupted = SwitchJournal::read(run_dir)?;
+
+    let current = SystemSnapshot {
+        root: PathBuf::from("/"),
+        drop_in_dirs: DropInDirs::system(Path::new("/")),
+        unit_dir: SYSTEM_UNIT_DIR,
+        active_units: systemd.active_units()?,
+        pid1_path: Path::new("/proc/1/exe")
+            .canonicalize()
+            .unwrap_or_else(|_| PathBuf::from("/unknown")),
+        pending: pending_jobs(&interrupted, run_dir),
+        fstab_units: read_fstab_units(Path::new(SYSTEMD_GENERATOR_DIR), Path::new("/etc/fstab"))?,
+    };
+
+    let reboot_required = boot_component_changes(Path::new(BOOTED_SYSTEM), &toplevel);
+
+    let (mut plan, mut journal) = if *action == Action::Resume {
+        let Some(journal) = interrupted else {
+            eprintln!("there is no interrupted switch to resume");
+            guard.exit(None, 0);
+        };
+
+        // Only the configuration that was being switched to knows how to finish the switch.
+        if journal.toplevel != toplevel {
+            bail!(
+                "the interrupted switch was to {}, run {}/bin/switch-to-configuration resume instead",
+                journal.toplevel.display(),
+                journal.toplevel.display()
+            );
+        }
+
+        std::env::set_var("NIXOS_ACTION", &journal.action);
+        eprintln!(
+            "resuming {} to {} after phase: {}",
+            journal.action,
+            toplevel.display(),
+            journal.phase
+        );
+        log::info!(
+            "resuming switch to system configuration {}",
+            toplevel.display()
+        );
+
+        guard.journal_written = true;
+
+        (journal.pending_plan(), journal)
+    } else {
+        let Ok(new_pid1_path) = new_systemd.join("lib/systemd/systemd").canonicalize() else {
+            guard.die();
+        };
+
+        // A broken fstab doesn't prevent the switch, mounts and swaps are left alone instead.
+        let new_fstab_units = match generate_fstab_units(
+            &new_systemd,
+            &toplevel.join("etc/fstab"),
+            &run_dir.join(FSTAB_GENERATOR_DIR),
+        ) {
+            Ok(new_fstab_units) => new_fstab_units,
+            Err(err) => {
+                eprintln!(
+                    "warning: unable to generate the mount units of the new configuration, not changing any mounts or swaps: {err:#}"
+                );
+                current.fstab_units.clone()
+            }
+        };
+
+        let mut plan = plan_switch(
+            &current,
+            &toplevel,
+            &new_pid1_path,
+            &new_fstab_units,
+            std::env::var("STC_DISPLAY_ALL_UNITS").as_deref() == Ok("1"),
+            options.explain,
+        )?;
+        plan.reboot_required = reboot_required.clone();
+
+        // Show dry-run actions.
+        if *action == Action::DryActivate {
+            if !options.json {
+                for device in &plan.swaps_to_stop {
+                    eprintln!("would stop swap device: {device}");
+                }
+
+                for change in &plan.swaps_to_reapply {
+                    eprintln!(
+                        "would change options of swap device {} from {} to {}",
+                        change.device, change.old_options, change.new_options
+                    );
+                }
+
+                for device in &plan.swaps_to_start {
+                    eprintln!("would start swap device: {device}");
+                }
+
+                let units_to_stop_filtered =
+                    filter_units(&plan.units_to_filter, &plan.units_to_stop);
+                if !units_to_stop_filtered.is_empty() {
+                    eprintln!(
+                        "would stop the following units: {}",
+                        sorted_units(&units_to_stop_filtered).join(", ")
+                    );
+                }
+
+                if !plan.units_to_skip.is_empty() {
+                    eprintln!(
+                        "would NOT stop the following changed units: {}",
+                        sorted_units(&plan.units_to_skip).join(", ")
+                    );
+                }
+            }
+
+            eprintln!("would activate the configuration...");
+            let mut dry_activate = std::process::Command::new(out.join("dry-activate"));
+            dry_activate.arg(&out);
+            if options.json {
+                // Keep stdout reserved for the plan.
+                dry_activate.stdout(std::io::stderr());
+            }
+            _ = dry_activate.spawn().map(|mut child| child.wait());
+
+            // Handle the activation script requesting the restart or reload of a unit.
+
+            if std::fs::exists(DRY_RESTART_BY_ACTIVATION_LIST_FILE)?
+                || std::fs::exists(DRY_RELOAD_BY_ACTIVATION_LIST_FILE)?
+            {
+                eprintln!("WARN: restarting or reloading systemd units from the activation script is deprecated and will be removed in NixOS 26.11.");
+            }
+
+            handle_activation_requests(
+                &mut plan,
+                &current,
+                &toplevel,
+                &std::fs::read_to_string(DRY_RESTART_BY_ACTIVATION_LIST_FILE).unwrap_or_default(),
+                &std::fs::read_to_string(DRY_RELOAD_BY_ACTIVATION_LIST_FILE).unwrap_or_default(),
+            )?;
+
+            remove_file_if_exists(DRY_RESTART_BY_ACTIVATION_LIST_FILE).with_context(|| {
+                format!("Failed to remove {DRY_RESTART_BY_ACTIVATION_LIST_FILE}")
+            })?;
+            remove_file_if_exists(DRY_RELOAD_BY_ACTIVATION_LIST_FILE).with_context(|| {
+                format!("Failed to remove {DRY_RELOAD_BY_ACTIVATION_LIST_FILE}")
+            })?;
+
+            if options.json {
+                let document = SwitchPlanDocument {
+                    version: SWITCH_PLAN_VERSION,
+                    action: action.into(),
+                    toplevel: &toplevel,
+                    plan: &plan,
+                };
+
+                let mut stdout = std::io::stdout().lock();
+                serde_json::to_writer_pretty(&mut stdout, &document)
+                    .context("Failed to serialize switch plan")?;
+                writeln!(&mut stdout).context("Failed to write switch plan")?;
+
+                std::process::exit(0);
+            }
+
+            for (alias, unit) in &plan.unit_aliases {
+                eprintln!("would treat {alias} as an alias of {unit}");
+            }
+
+            print_unit_differences(&plan);
+
+            if plan.restart_systemd {
+                eprintln!("would restart systemd");
+            }
+
+            if !plan.reboot_required.is_empty() {
+                eprintln!(
+                    "would need a reboot to use the new {}",
+                    component_names(&plan.reboot_required)
+                );
+            }
+
+            if !plan.units_to_reload.is_empty() {
+                eprintln!(
+                    "would reload the following units: {}",
+                    sorted_units(&plan.units_to_reload).join(", ")
+                );
+            }
+
+            if !plan.units_to_reload_or_restart.is_empty() {
+                eprintln!(
+                    "would reload or restart the following units: {}",
+                    sorted_units(&plan.units_to_reload_or_restart).join(", ")
+                );
+            }
+
+            for (unit, signal) in &plan.units_to_signal {
+                eprintln!("would send {signal} to {unit}");
+            }
+
+            if !plan.units_to_restart.is_empty() {
+                eprintln!(
+                    "would restart the following units: {}",
+                    sorted_units(&plan.units_to_restart).join(", ")
+                );
+            }
+
+            if !plan.units_to_try_restart.is_empty() {
+                eprintln!(
+                    "would restart the following units if they are running: {}",
+                    sorted_units(&plan.units_to_try_restart).join(", ")
+                );
+            }
+
+            for (unit, reason) in &plan.units_to_defer {
+                eprintln!("would defer the restart of {unit} ({reason})");
+            }
+
+            let units_to_start_filtered = filter_units(&plan.units_to_filter, &plan.units_to_start);
+            if !units_to_start_filtered.is_empty() {
+                eprintln!(
+                    "would start the following units: {}",
+                    sorted_units(&units_to_start_filtered).join(", ")
+                );
+            }
+
+            std::process::exit(0);
+        }
+
+        // Record what needs to be done so that an interrupted switch can be continued.
+        let journal = SwitchJournal::new(
+            &toplevel,
+            Path::new("/run/current-system").canonicalize().ok(),
+            action,
+            &plan,
+        );
+        journal.write(run_dir)?;
+        guard.journal_written = true;
+        for file in [
+            LEGACY_START_LIST_FILE,
+            LEGACY_RESTART_LIST_FILE,
+            LEGACY_RELOAD_LIST_FILE,
+        ] {
+            let p = run_dir.join(file);
+            remove_file_if_exists(&p)
+                .with_context(|| format!("Failed to remove {}", p.display()))?;
+        }
+
+        log::info!("switching to system configuration {}", toplevel.display());
+
+        (plan, journal)
+    };
+
+    if let Some(progress) = &progress {
+        progress.set_plan(&plan, &journal);
+    }
+
+    // Stopping the SSH connection or the session running the switch would kill it halfway, so it
+    // continues in a transient service instead.
+    if !detached && std::env::var("STC_NO_DETACH").as_deref() != Ok("1") {
+        let endangering_units = endangering_units(
+            &plan,
+            &ancestor_units(Path::new(PROC_ROOT), std::process::id()),
+            std::env::var_os("SSH_CONNECTION").is_some(),
+        );
+        if !endangering_units.is_empty() {
+            return detach_switch(
+                &systemd,
+                &toplevel,
+                run_dir,
+                &endangering_units,
+                &mut guard,
+                switch_lock,
+            );
+        }
+    }
+
+    let mut exit_code = 0;
+    let mut user_switches = Vec::new();
+
+    if journal.phase < SwitchPhase::UnitsStopped {
+        // Swap entries that disappeared are turned off. Can't use "systemctl stop" here because
+        // systemd has lots of alias units that prevent a stop from actually calling "swapoff".
+        for device in &plan.swaps_to_stop {
+            eprintln!("stopping swap device: {device}");
+            if let Err(err) = swap_off(device) {
+                eprintln!("Failed to stop swapping to {device}: {err}");
+            }
+        }
+
+        // For the same reason, swap devices with changed options are turned off and on again by hand.
+        for change in &plan.swaps_to_reapply {
+            eprintln!(
+                "changing options of swap device {} to {}",
+                change.device, change.new_options
+            );
+            if let Err(err) = swap_off(&change.device)
+                .and_then(|_| swap_on(&change.device, swap_flags(&change.new_options)))
+            {
+                eprintln!(
+                    "Failed to change options of swap device {}: {err}",
+                    change.device
+                );
+            }
+        }
+
+        print_unit_differences(&plan);
+
+        stop_units(&systemd, &plan);
+
+        if !plan.units_to_skip.is_empty() {
+            eprintln!(
+                "NOT restarting the following changed units: {}",
+                sorted_units(&plan.units_to_skip).join(", "),
+            );
+        }
+
+        for (unit, reason) in &plan.units_to_defer {
+            eprintln!("deferring the restart of {unit} ({reason})");
+        }
+
+        // Record the deferred units before anything else can go wrong.
+        let mut pending_restarts = PendingRestarts::read(run_dir)?;
+        pending_restarts.update(&plan, &toplevel);
+        pending_restarts.write(run_dir)?;
+
+        journal.finish_jobs(&systemd.finished_jobs());
+        journal.reach(SwitchPhase::UnitsStopped, run_dir)?;
+        if let Some(progress) = &progress {
+            progress.set_phase(journal.phase);
+        }
+    }
+
+    if journal.phase < SwitchPhase::Activated {
+        if *action != Action::Resume {
+            let parent = std::env::var_os(SPECIALISATION_OF_ENV).map(PathBuf::from);
+            let name = std::env::var(SPECIALISATION_ENV).ok();
+            RunningSpecialisation::record(
+                run_dir,
+                &toplevel,
+                parent.as_deref().zip(name.as_deref()),
+            )?;
+        }
+
+        // Activate the new configuration (i.e., update /etc, make accounts, and so on).
+        eprintln!("activating the configuration...");
+        match std::process::Command::new(out.join("activate"))
+            .arg(&out)
+            .spawn()
+            .map(|mut child| child.wait())
+        {
+            Ok(Ok(status)) if status.success() => {}
+            Err(_) => {
+                // allow toplevel to not have an activation script
+            }
+            _ => {
+                eprintln!("Failed to run activate script");
+                exit_code = 2;
+            }
+        }
+
+        if std::fs::exists(RESTART_BY_ACTIVATION_LIST_FILE)?
+            || std::fs::exists(RELOAD_BY_ACTIVATION_LIST_FILE)?
+        {
+            eprintln!("WARN: restarting or reloading systemd units from the activation script is deprecated and will be removed in NixOS 26.11.");
+        }
+
+        // Handle the activation script requesting the restart or reload of a unit.
+        handle_activation_requests(
+            &mut plan,
+            &current,
+            &toplevel,
+            &std::fs::read_to_string(RESTART_BY_ACTIVATION_LIST_FILE).unwrap_or_default(),
+            &std::fs::read_to_string(RELOAD_BY_ACTIVATION_LIST_FILE).unwrap_or_default(),
+        )?;
+        journal.update(&plan);
+        if let Some(progress) = &progress {
+            progress.set_plan(&plan, &journal);
+        }
+
+        for (alias, unit) in &plan.unit_aliases {
+            eprintln!("treating {alias} as an alias of {unit}");
+        }
+
+        // We can remove the files now because they have been propagated to the other restart/reload
+        // files
+        remove_file_if_exists(RESTART_BY_ACTIVATION_LIST_FILE)
+            .with_context(|| format!("Failed to remove {RESTART_BY_ACTIVATION_LIST_FILE}"))?;
+        remove_file_if_exists(RELOAD_BY_ACTIVATION_LIST_FILE)
+            .with_context(|| format!("Failed to remove {RELOAD_BY_ACTIVATION_LIST_FILE}"))?;
+
+        journal.reach(SwitchPhase::Activated, run_dir)?;
+        if let Some(progress) = &progress {
+            progress.set_phase(journal.phase);
+        }
+    }
+
+    if journal.phase < SwitchPhase::SystemdReloaded {
+        // Restart systemd if necessary. Note that this is done using the current version of systemd,
+        // just in case the new one has trouble communicating with the running pid 1.
+        if plan.restart_systemd {
+            eprintln!("restarting systemd...");
+            systemd.reexecute()?;
+        }
+
+        // Forget about previously failed services.
+        systemd.reset_failed()?;
+
+        // Make systemd reload its units.
+        systemd.reload()?;
+
+        // Reload user units. The switches of all users run at the same time so that a hanging
+        // user session doesn't hold up the others.
+        let users = match logind.list_users() {
+            Err(err) => {
+                eprintln!("Unable to list users with logind: {err}");
+                guard.die();
+            }
+            Ok(users) => users,
+        };
+
+        let mut children = Vec::new();
+        for (uid, name, user_dbus_path) in users {
+            let proxy = dbus_conn.with_proxy(
+                "org.freedesktop.login1",
+                &user_dbus_path,
+                Duration::from_millis(5000),
+            );
+            let gid: u32 = proxy
+                .get("org.freedesktop.login1.User", "GID")
+                .with_context(|| format!("Failed to get GID for {name}"))?;
+
+            let runtime_path: String = proxy
+                .get("org.freedesktop.login1.User", "RuntimePath")
+                .with_context(|| format!("Failed to get runtime directory for {name}"))?;
+
+            eprintln!("reloading user units for {name}...");
+            let myself = Path::new("/proc/self/exe")
+                .canonicalize()
+                .context("Failed to get full path to /proc/self/exe")?;
+
+            // The user switch reads the user's drop-ins from its home directory.
+            let home = User::from_uid(Uid::from_raw(uid))
+                .with_context(|| format!("Failed to look up the home directory of {name}"))?
+                .map(|user| user.dir);
+
+            log::debug!("Performing user switch for {name}");
+            let child = user_switch_command(
+                &myself,
+                uid,
+                gid,
+                home.as_deref(),
+                &runtime_path,
+                &toplevel,
+                journal.previous_toplevel.as_deref(),
+            )
+            .spawn()
+            .with_context(|| format!("Failed to spawn user activation for {name}"))?;
+            children.push((name, child));
+        }
+
+        user_switches = wait_for_user_switches(
+            children,
+            duration_from_env("STC_USER_SWITCH_TIMEOUT")?.unwrap_or(DEFAULT_USER_SWITCH_TIMEOUT),
+        );
+
+        journal.reach(SwitchPhase::SystemdReloaded, run_dir)?;
+        if let Some(progress) = &progress {
+            progress.set_phase(journal.phase);
+        }
+    }
+
+    // Whether units or jobs failed, as opposed to other parts of the switch.
+    let mut units_failed = false;
+
+    let sysinit_reactivated = reactivate_sysinit(&systemd);
+    if !apply_unit_changes(
+        &systemd,
+        &mut plan,
+        &toplevel.join(SYSTEM_UNIT_DIR),
+        Some(&current.drop_in_dirs),
+        Some((&mut journal, run_dir)),
+    )? || !sysinit_reactivated
+    {
+        exit_code = 4;
+        units_failed = true;
+    }
+
+    // The activation script may have restarted units whose restart was deferred before.
+    let mut pending_restarts = PendingRestarts::read(run_dir)?;
+    pending_restarts.update(&plan, &toplevel);
+    pending_restarts.write(run_dir)?;
+    if !pending_restarts.units.is_empty() {
+        eprintln!(
+            "the following units still need a restart: {}",
+            pending_restarts
+                .units
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    // Services whose units didn't change may still run code of the previous configuration, e.g.
+    // because a library they use was updated.
+    if std::env::var("STC_SKIP_STALE_CHECK").as_deref() != Ok("1") {
+        match store_closure(&toplevel).and_then(|closure| {
+            stale_services(
+                &systemd,
+                &closure,
+                &toplevel.join(SYSTEM_UNIT_DIR),
+                Path::new(CGROUP_ROOT),
+                Path::new(PROC_ROOT),
+            )
+        }) {
+            Ok(stale) if !stale.is_empty() => {
+                eprintln!(
+                    "warning: the following units use store paths that are not part of the new configuration:"
+                );
+                for (unit, paths) in &stale {
+                    eprintln!(
+                        "  {unit}: {}",
+                        paths.iter().cloned().collect::<Vec<_>>().join(", ")
+                    );
+                }
+
+                if options.restart_stale
+                    && !restart_stale_services(&systemd, &stale, &toplevel.join(SYSTEM_UNIT_DIR))
+                {
+                    exit_code = 4;
+                    units_failed = true;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("warning: unable to look for outdated units: {err:#}"),
+        }
+    }
+
+    // The new kernel and initrd can't be switched to, tell the user and tools managing the machine.
+    RebootRequired::record(
+        run_dir,
+        Path::new(BOOTED_SYSTEM),
+        &toplevel,
+        &reboot_required,
+    )?;
+    if !reboot_required.is_empty() {
+        eprintln!(
+            "notice: a reboot is required to use the new {}",
+            component_names(&reboot_required)
+        );
+        log::warn!(
+            "system configuration {} needs a reboot to use its {}",
+            toplevel.display(),
+            component_names(&reboot_required)
+        );
+    }
+
+    // Print failed and new units.
+    let (failed_units, new_units) = failed_and_new_units(&systemd, &current.active_units)?;
+
+    if !new_units.is_empty() {
+        eprintln!(
+            "the following new units were started: {}",
+            new_units.join(", ")
+        );
+    }
+
+    if !failed_units.is_empty() {
+        eprintln!(
+            "warning: the following units failed: {}",
+            failed_units.join(", ")
+        );
+        _ = std::process::Command::new(new_systemd.join("bin/systemctl"))
+            .arg("status")
+            .arg("--no-pager")
+            .arg("--full")
+            .args(&failed_units)
+            .spawn()
+            .map(|mut child| child.wait());
 
-    let _systemd_is_reloading = systemd_is_reloading.clone();
-    let reloading_token = systemd
-        .match_signal(
-            move |signal: OrgFreedesktopSystemd1ManagerReloading,
-                  _: &LocalConnection,
-                  _msg: &Message| {
-                *_systemd_is_reloading.borrow_mut() = signal.active;
+        exit_code = 4;
+        units_failed = true;
+    }
 
-                true
-            },
-        )
-        .context("Failed to add systemd Reloading match")?;
-
-    let _submitted_jobs = submitted_jobs.clone();
-    let _finished_jobs = finished_jobs.clone();
-    let job_removed_token = systemd
-        .match_signal(
-            move |signal: OrgFreedesktopSystemd1ManagerJobRemoved,
-                  _: &LocalConnection,
-                  _msg: &Message| {
-                if let Some(old) = _submitted_jobs.borrow_mut().remove(&signal.job) {
-                    let mut finished_jobs = _finished_jobs.borrow_mut();
-                    finished_jobs.insert(signal.job, (signal.unit, old, signal.result));
+    let timed_out_jobs = systemd.timed_out_jobs();
+    if !timed_out_jobs.is_empty() {
+        eprintln!(
+            "warning: the following jobs did not finish in time: {}",
+            timed_out_jobs
+                .iter()
+                .map(|(unit, job)| format!("{unit} ({job})"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        exit_code = timed_out_exit_code(exit_code);
+        units_failed = true;
+    }
+
+    // Problems in user sessions are reported but don't roll the system back.
+    for user_switch in &user_switches {
+        if !user_switch.failed_units.is_empty() {
+            eprintln!(
+                "warning: the following user units of {} failed: {}",
+                user_switch.user,
+                user_switch.failed_units.join(", ")
+            );
+        }
+
+        match &user_switch.status {
+            UserSwitchStatus::Done => {}
+            UserSwitchStatus::Failed(reason) => {
+                eprintln!(
+                    "warning: switching the user units of {} failed ({reason})",
+                    user_switch.user
+                );
+                if exit_code == 0 {
+                    exit_code = 4;
                 }
+            }
+            UserSwitchStatus::TimedOut => {
+                eprintln!(
+                    "warning: switching the user units of {} did not finish in time",
+                    user_switch.user
+                );
+                exit_code = timed_out_exit_code(exit_code);
+            }
+        }
+    }
 
-                true
-            },
-        )
-        .context("Failed to add systemd JobRemoved match")?;
+    // Recorded before rolling back, which records a switch of its own.
+    guard.finish(Some(&journal), &failed_units, exit_code);
 
-    let current_active_units = get_active_units(&systemd)?;
+    if units_failed {
+        match previous_toplevel {
+            Some(previous_toplevel)
+                if toplevel.canonicalize().ok().as_ref() != Some(&previous_toplevel) =>
+            {
+                eprintln!(
+                    "switch failed, rolling back to {}...",
+                    previous_toplevel.display()
+                );
+                log::warn!(
+                    "switching to system configuration {} failed, rolling back to {}",
+                    toplevel.display(),
+                    previous_toplevel.display()
+                );
 
-    let template_unit_re = Regex::new(r"^(.*)@[^\.]*\.(.*)$")
-        .context("Invalid regex for matching systemd template units")?;
-    let unit_name_re = Regex::new(r"^(.*)\.[[:lower:]]*$")
-        .context("Invalid regex for matching systemd unit names")?;
+                // The previous configuration needs to take the lock itself.
+                drop(switch_lock);
 
-    for (unit, unit_state) in &current_active_units {
-        // Don't touch units not explicitly written by NixOS (e.g. units created by generators in
-        // /run/systemd/generator*)
-        if !unit_state
-            .proxy
-            .get("org.freedesktop.systemd1.Unit", "FragmentPath")
-            .map(|fragment_path: String| fragment_path.starts_with("/etc/systemd/system"))
-            .unwrap_or_default()
-        {
-            continue;
+                match do_rollback(&previous_toplevel, action, previous_specialisation.as_ref()) {
+                    Ok(status) if status.success() => {
+                        eprintln!("rolled back to {}", previous_toplevel.display());
+                        log::info!(
+                            "rolled back to system configuration {}",
+                            previous_toplevel.display()
+                        );
+                    }
+                    Ok(status) => {
+                        eprintln!(
+                            "rollback to {} failed ({status})",
+                            previous_toplevel.display()
+                        );
+                        log::error!(
+                            "rollback to system configuration {} failed ({status})",
+                            previous_toplevel.display()
+                        );
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            "rollback to {} failed: {err:#}",
+                            previous_toplevel.display()
+                        );
+                        log::error!(
+                            "rollback to system configuration {} failed: {err:#}",
+                            previous_toplevel.display()
+                        );
+                    }
+                }
+            }
+            Some(_) => eprintln!("not rolling back, the previous configuration is the same"),
+            None => {}
         }
+    }
+
+    if exit_code == 0 {
+        log::info!(
+            "finished switching to system configuration {}",
+            toplevel.display()
+        );
+    } else {
+        log::error!(
+            "switching to system configuration {} failed (status {})",
+            toplevel.display(),
+            exit_code
+        );
+    }
 
-        let current_unit_file = Path::new("/etc/systemd/system").join(unit);
-        let new_unit_file = toplevel.join("etc/systemd/system").join(unit);
+    std::process::exit(exit_code);
+}
 
-        let mut base_unit = unit.clone();
-        let mut current_base_unit_file = current_unit_file.clone();
-        let mut new_base_unit_file = new_unit_file.clone();
+fn main() -> anyhow::Result<()> {
+    if let Ok(unit) = std::env::var(DETACHED_SWITCH_UNIT_ENV) {
+        return do_detached_switch(unit);
+    }
 
-        // Detect template instances
-        if let Some((Some(template_name), Some(template_instance))) =
-            template_unit_re.captures(unit).map(|captures| {
-                (
-                    captures.get(1).map(|c| c.as_str()),
-                    captures.get(2).map(|c| c.as_str()),
-                )
-            })
-        {
-            if !current_unit_file.exists() && !new_unit_file.exists() {
-                base_unit = format!("{template_name}@.{template_instance}");
-                current_base_unit_file = Path::new("/etc/systemd/system").join(&base_unit);
-                new_base_unit_file = toplevel.join("etc/systemd/system").join(&base_unit);
+    match std::env::var("__NIXOS_SWITCH_TO_CONFIGURATION_PARENT_EXE").ok() {
+        Some(parent_exe) => do_user_switch(parent_exe),
+        None => {
+            let mut args = std::env::args();
+            let argv0 = args.next().ok_or(anyhow!("no argv[0]"))?;
+            let argv0 = argv0
+                .split(std::path::MAIN_SEPARATOR_STR)
+                .last()
+                .unwrap_or("switch-to-configuration");
+
+            let Some(Ok(action)) = args.next().map(|a| Action::from_str(&a)) else {
+                usage(argv0);
+            };
+
+            let mut options = Options::default();
+            let mut toplevels = Vec::new();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--json" => options.json = true,
+                    "--rollback-on-failure" => options.rollback_on_failure = true,
+                    "--explain" => options.explain = true,
+                    "--soft-reboot-fallback" => options.soft_reboot_fallback = true,
+                    "--restart-stale" => options.restart_stale = true,
+                    "--specialisation" => match args.next() {
+                        Some(name) => options.specialisation = Some(name),
+                        None => usage(argv0),
+                    },
+                    "--apply" if action == Action::Pending => options.apply = true,
+                    toplevel if action == Action::Diff && !toplevel.starts_with("--") => {
+                        toplevels.push(PathBuf::from(toplevel))
+                    }
+                    unit if options.apply && !unit.starts_with("--") => {
+                        options.units.push(unit.to_string())
+                    }
+                    count if action == Action::History && options.count.is_none() => {
+                        match count.parse() {
+                            Ok(count) => options.count = Some(count),
+                            Err(_) => usage(argv0),
+                        }
+                    }
+                    _ => usage(argv0),
+                }
+            }
+
+            // The other actions don't print anything that could be JSON.
+            let prints_json = match action {
+                Action::DryActivate | Action::Status | Action::Diff | Action::History => true,
+                Action::Pending => !options.apply,
+                _ => false,
+            };
+            if options.json && !prints_json {
+                usage(argv0);
             }
+
+            if action == Action::Diff {
+                let [old, new] = toplevels.as_slice() else {
+                    usage(argv0);
+                };
+                return do_diff(old, new, &options);
+            }
+
+            if action == Action::History {
+                return print_switch_history(
+                    Path::new(SWITCH_HISTORY_FILE),
+                    options.count,
+                    options.json,
+                );
+            }
+
+            if unsafe { nix::libc::geteuid() } == 0 {
+                do_system_switch(action, options)
+            } else {
+                bail!("{} must be run as the root user", argv0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::{BTreeMap, HashMap},
+        path::Path,
+        time::Duration,
+    };
+
+    #[test]
+    fn read_fstab_units() {
+        let tmp = tempfile::tempdir().unwrap();
+        for (unit, contents) in [
+            (
+                "data.mount",
+                "# Automatically generated by systemd-fstab-generator\n\n[Unit]\nSourcePath=/etc/fstab\n\n[Mount]\nWhat=/dev/sda1\nWhere=/data\nType=ext4\n",
+            ),
+            (
+                "dev-sda2.swap",
+                "[Unit]\nSourcePath=/etc/fstab\n\n[Swap]\nWhat=/dev/sda2\nPriority=5\n",
+            ),
+            // Created by another generator
+            (
+                "boot.automount",
+                "[Unit]\nSourcePath=/dev/sda\n\n[Automount]\nWhere=/boot\n",
+            ),
+            ("local-fs.target.requires/data.mount", "[Mount]\n"),
+        ] {
+            write_file(&tmp.path().join(unit), contents);
+        }
+
+        let units = super::read_fstab_units(tmp.path(), Path::new("/etc/fstab")).unwrap();
+        let mut names = units.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["data.mount", "dev-sda2.swap"]);
+        assert_eq!(
+            super::unit_value(&units["data.mount"], "Mount", "Where"),
+            Some("/data")
+        );
+        assert_eq!(
+            super::swap_options(&units["dev-sda2.swap"]),
+            "defaults,pri=5"
+        );
+
+        assert!(
+            super::read_fstab_units(&tmp.path().join("missing"), Path::new("/etc/fstab"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn filter_units() {
+        assert_eq!(
+            super::filter_units(&HashMap::from([]), &HashMap::from([])),
+            HashMap::from([])
+        );
+
+        assert_eq!(
+            super::filter_units(
+                &HashMap::from([("foo".to_string(), ())]),
+                &HashMap::from([("foo".to_string(), ()), ("bar".to_string(), ())])
+            ),
+            HashMap::from([("bar".to_string(), ())])
+        );
+    }
+
+    #[test]
+    fn drop_in_dir_names() {
+        let mut names = Vec::new();
+        super::drop_in_dir_names("foo-bar-baz.service", &mut names);
+        assert_eq!(
+            names,
+            [
+                "foo-bar-baz.service.d",
+                "foo-bar-.service.d",
+                "foo-.service.d"
+            ]
+        );
+
+        let mut names = Vec::new();
+        super::drop_in_dir_names("container-getty@1.service", &mut names);
+        assert_eq!(
+            names,
+            [
+                "container-getty@1.service.d",
+                "container-getty@.service.d",
+                "container-.service.d"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unit_drop_ins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let unit_dir = tmp.path().join("etc/systemd/system");
+        let unit_file = unit_dir.join("foo-bar@x.service");
+        let base_unit_file = unit_dir.join("foo-bar@.service");
+
+        write_file(
+            &base_unit_file,
+            "[Service]\nExecStart=/bin\nEnvironment=BASE=1\n",
+        );
+        for (drop_in, contents) in [
+            // Applied in lexical order of the file names, regardless of the directory
+            ("service.d/10-all.conf", "[Service]\nNice=1\n"),
+            ("foo-.service.d/20-prefix.conf", "[Service]\nNice=2\n"),
+            ("foo-bar@.service.d/30-template.conf", "[Service]\nNice=3\n"),
+            (
+                "foo-bar@x.service.d/05-instance.conf",
+                "[Service]\nEnvironment=INSTANCE=1\n",
+            ),
+            // Masked by the drop-in of the instance
+            ("service.d/40-masked.conf", "[Service]\nUser=nobody\n"),
+            (
+                "foo-bar@x.service.d/40-masked.conf",
+                "[Service]\nUser=root\n",
+            ),
+        ] {
+            write_file(&unit_dir.join(drop_in), contents);
         }
 
-        let mut base_name = base_unit.as_str();
-        if let Some(Some(new_base_name)) = unit_name_re
-            .captures(&base_unit)
-            .map(|capture| capture.get(1).map(|first| first.as_str()))
+        // Control drop-ins take precedence over the configuration, runtime drop-ins don't.
+        write_file(
+            &tmp.path()
+                .join("etc/systemd/system.control/foo-bar@x.service.d/50-cpu.conf"),
+            "[Service]\nCPUWeight=10\n",
+        );
+        write_file(
+            &unit_dir.join("foo-bar@.service.d/50-cpu.conf"),
+            "[Service]\nCPUWeight=20\n",
+        );
+        write_file(
+            &tmp.path()
+                .join("run/systemd/system/service.d/30-template.conf"),
+            "[Service]\nNice=4\n",
+        );
+        // Directories are searched one after the other, so the top-level drop-ins of the
+        // configuration mask the unit's own runtime drop-ins.
+        write_file(
+            &unit_dir.join("service.d/60-limit.conf"),
+            "[Service]\nLimitNOFILE=4096\n",
+        );
+        write_file(
+            &tmp.path()
+                .join("run/systemd/system/foo-bar@x.service.d/60-limit.conf"),
+            "[Service]\nLimitNOFILE=1024\n",
+        );
+
+        let unit_info = super::parse_unit(
+            &unit_file,
+            &base_unit_file,
+            Some(&super::DropInDirs::system(tmp.path())),
+        )
+        .unwrap();
+        let service = &unit_info["Service"];
+        assert_eq!(service["Nice"], ["1", "2", "3"]);
+        assert_eq!(service["Environment"], ["BASE=1", "INSTANCE=1"]);
+        assert_eq!(service["User"], ["root"]);
+        assert_eq!(service["CPUWeight"], ["10"]);
+        assert_eq!(service["LimitNOFILE"], ["4096"]);
+
+        let unit_info = super::parse_unit(&unit_file, &base_unit_file, None).unwrap();
+        assert_eq!(unit_info["Service"]["CPUWeight"], ["20"]);
+    }
+
+    // Compares two units the same way switch-to-configuration does.
+    fn compare(
+        current_unit: &super::UnitInfo,
+        new_unit: &super::UnitInfo,
+    ) -> super::UnitComparison {
+        super::compare_differences(&super::diff_units(current_unit, new_unit))
+    }
+
+    #[test]
+    fn compare_units() {
         {
-            base_name = new_base_name;
+            assert!(
+                compare(&HashMap::from([]), &HashMap::from([])) == super::UnitComparison::Equal
+            );
+
+            assert!(
+                compare(
+                    &HashMap::from([("Unit".to_string(), HashMap::from([]))]),
+                    &HashMap::from([])
+                ) == super::UnitComparison::Equal
+            );
+
+            assert!(
+                compare(
+                    &HashMap::from([(
+                        "Unit".to_string(),
+                        HashMap::from([(
+                            "X-Reload-Triggers".to_string(),
+                            vec!["foobar".to_string()]
+                        )])
+                    )]),
+                    &HashMap::from([])
+                ) == super::UnitComparison::Equal
+            );
         }
 
-        if current_base_unit_file.exists()
-            && (unit_state.state == "active" || unit_state.state == "activating")
         {
-            if new_base_unit_file
-                .canonicalize()
-                .map(|full_path| full_path == Path::new("/dev/null"))
-                .unwrap_or(true)
-            {
-                let current_unit_info = parse_unit(&current_unit_file, &current_base_unit_file)?;
-                if parse_systemd_bool(Some(&current_unit_info), "Unit", "X-StopOnRemoval", true) {
-                    _ = units_to_stop.insert(unit.to_string(), ());
-                }
-            } else if unit.ends_with(".target") {
-                let new_unit_info = parse_unit(&new_unit_file, &new_base_unit_file)?;
+            assert!(
+                compare(
+                    &HashMap::from([("foobar".to_string(), HashMap::from([]))]),
+                    &HashMap::from([])
+                ) == super::UnitComparison::UnequalNeedsRestart
+            );
 
-                // Cause all active target units to be restarted below. This should start most
-                // changed units we stop here as well as any new dependencies (including new mounts
-                // and swap devices).  FIXME: the suspend target is sometimes active after the
-                // system has resumed, which probably should not be the case.  Just ignore it.
-                if !(matches!(
-                    unit.as_str(),
-                    "suspend.target" | "hibernate.target" | "hybrid-sleep.target"
-                ) || parse_systemd_bool(
-                    Some(&new_unit_info),
-                    "Unit",
-                    "RefuseManualStart",
-                    false,
-                ) || parse_systemd_bool(
-                    Some(&new_unit_info),
-                    "Unit",
-                    "X-OnlyManualStart",
-                    false,
-                )) {
-                    units_to_start.insert(unit.to_string(), ());
-                    record_unit(START_LIST_FILE, unit);
-                    // Don't spam the user with target units that always get started.
-                    if std::env::var("STC_DISPLAY_ALL_UNITS").as_deref() != Ok("1") {
-                        units_to_filter.insert(unit.to_string(), ());
-                    }
-                }
+            assert!(
+                compare(
+                    &HashMap::from([(
+                        "Mount".to_string(),
+                        HashMap::from([("Options".to_string(), vec![])])
+                    )]),
+                    &HashMap::from([(
+                        "Mount".to_string(),
+                        HashMap::from([("Options".to_string(), vec!["ro".to_string()])])
+                    )])
+                ) == super::UnitComparison::UnequalNeedsReload
+            );
+        }
+
+        {
+            assert!(
+                compare(
+                    &HashMap::from([]),
+                    &HashMap::from([(
+                        "Unit".to_string(),
+                        HashMap::from([(
+                            "X-Reload-Triggers".to_string(),
+                            vec!["foobar".to_string()]
+                        )])
+                    )])
+                ) == super::UnitComparison::UnequalNeedsReload
+            );
+
+            assert!(
+                compare(
+                    &HashMap::from([(
+                        "Unit".to_string(),
+                        HashMap::from([(
+                            "X-Reload-Triggers".to_string(),
+                            vec!["foobar".to_string()]
+                        )])
+                    )]),
+                    &HashMap::from([(
+                        "Unit".to_string(),
+                        HashMap::from([(
+                            "X-Reload-Triggers".to_string(),
+                            vec!["barfoo".to_string()]
+                        )])
+                    )])
+                ) == super::UnitComparison::UnequalNeedsReload
+            );
 
-                // Stop targets that have X-StopOnReconfiguration set. This is necessary to respect
-                // dependency orderings involving targets: if unit X starts after target Y and
-                // target Y starts after unit Z, then if X and Z have both changed, then X should
-                // be restarted after Z.  However, if target Y is in the "active" state, X and Z
-                // will be restarted at the same time because X's dependency on Y is already
-                // satisfied.  Thus, we need to stop Y first. Stopping a target generally has no
-                // effect on other units (unless there is a PartOf dependency), so this is just a
-                // bookkeeping thing to get systemd to do the right thing.
-                if parse_systemd_bool(
-                    Some(&new_unit_info),
-                    "Unit",
-                    "X-StopOnReconfiguration",
-                    false,
-                ) {
-                    units_to_stop.insert(unit.to_string(), ());
-                }
-            } else {
-                let current_unit_info = parse_unit(&current_unit_file, &current_base_unit_file)?;
-                let new_unit_info = parse_unit(&new_unit_file, &new_base_unit_file)?;
-                match compare_units(&current_unit_info, &new_unit_info) {
-                    UnitComparison::UnequalNeedsRestart => {
-                        handle_modified_unit(
-                            &toplevel,
-                            unit,
-                            base_name,
-                            &new_unit_file,
-                            &new_base_unit_file,
-                            Some(&new_unit_info),
-                            &current_active_units,
-                            &mut units_to_stop,
-                            &mut units_to_start,
-                            &mut units_to_reload,
-                            &mut units_to_restart,
-                            &mut units_to_skip,
-                        )?;
-                    }
-                    UnitComparison::UnequalNeedsReload if !units_to_restart.contains_key(unit) => {
-                        units_to_reload.insert(unit.clone(), ());
-                        record_unit(RELOAD_LIST_FILE, unit);
-                    }
-                    _ => {}
-                }
-            }
+            assert!(
+                compare(
+                    &HashMap::from([(
+                        "Mount".to_string(),
+                        HashMap::from([("Type".to_string(), vec!["ext4".to_string()])])
+                    )]),
+                    &HashMap::from([(
+                        "Mount".to_string(),
+                        HashMap::from([("Type".to_string(), vec!["btrfs".to_string()])])
+                    )])
+                ) == super::UnitComparison::UnequalNeedsRestart
+            );
         }
     }
 
-    // Compare the previous and new fstab to figure out which filesystems need a remount or need to
-    // be unmounted. New filesystems are mounted automatically by starting local-fs.target.
-    //
-    // TODO: We should probably not reimplement some of the logic of systemd-fstab-generator, and
-    // instead only consume and act upon the mount/automount units created from that generator.
-    let (current_filesystems, current_swaps) = std::fs::read_to_string("/etc/fstab")
-        .map(|fstab| parse_fstab(std::io::Cursor::new(fstab)))
-        .unwrap_or_default();
-    let (new_filesystems, new_swaps) = std::fs::read_to_string(toplevel.join("etc/fstab"))
-        .map(|fstab| parse_fstab(std::io::Cursor::new(fstab)))
-        .unwrap_or_default();
+    #[test]
+    fn switch_plan_json() {
+        let plan = super::SwitchPlan {
+            units_to_start: HashMap::from([
+                ("b.service".to_string(), ()),
+                ("A.service".to_string(), ()),
+            ]),
+            swaps_to_stop: vec!["/dev/sda2".to_string()],
+            mounts: vec![super::MountChange {
+                mountpoint: "/home".to_string(),
+                unit: "home.mount".to_string(),
+                action: super::MountAction::Reload,
+            }],
+            restart_systemd: true,
+            ..Default::default()
+        };
 
-    for (mountpoint, current_filesystem) in current_filesystems {
-        let current_is_automount = current_filesystem.options.contains("x-systemd.automount");
-        let mount_unit = format!("{}.mount", libsystemd::unit::escape_path(&mountpoint));
-        let automount_unit = format!("{}.automount", libsystemd::unit::escape_path(&mountpoint));
+        let json = serde_json::to_value(super::SwitchPlanDocument {
+            version: super::SWITCH_PLAN_VERSION,
+            action: (&super::Action::DryActivate).into(),
+            toplevel: Path::new("/nix/store/foo-nixos-system"),
+            plan: &plan,
+        })
+        .unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["action"], "dry-activate");
+        assert_eq!(
+            json["units-to-start"],
+            serde_json::json!(["A.service", "b.service"])
+        );
+        assert_eq!(json["units-to-stop"], serde_json::json!([]));
+        assert_eq!(json["units-filtered"], serde_json::json!([]));
+        assert_eq!(json["mounts"][0]["action"], "reload");
+        assert_eq!(json["swaps-to-stop"][0], "/dev/sda2");
+        assert_eq!(json["restart-systemd"], true);
+    }
 
-        if let Some(new_filesystem) = new_filesystems.get(&mountpoint) {
-            if current_filesystem.fs_type != new_filesystem.fs_type
-                || current_filesystem.device != new_filesystem.device
-            {
-                if matches!(mountpoint.as_str(), "/" | "/nix") {
-                    if current_filesystem.options != new_filesystem.options {
-                        // Mount options changes, so remount it.
-                        units_to_reload.insert(mount_unit.to_string(), ());
-                        record_unit(RELOAD_LIST_FILE, &mount_unit)
-                    } else {
-                        // Don't unmount / or /nix if the device changed
-                        units_to_skip.insert(mount_unit, ());
-                    }
-                } else {
-                    // Filesystem type or device changed, so unmount and mount it.
-                    units_to_restart.insert(mount_unit.to_string(), ());
-                    record_unit(RESTART_LIST_FILE, &mount_unit);
-                }
-            } else if current_filesystem.options != new_filesystem.options {
-                // Mount options changes, so remount it.
-                units_to_reload.insert(mount_unit.clone(), ());
-                record_unit(RELOAD_LIST_FILE, &mount_unit)
-            }
-        } else {
-            // Filesystem entry disappeared, so unmount it. Stopping the automount unit also stops
-            // the mount unit.
-            if current_is_automount {
-                units_to_stop.insert(automount_unit, ());
-            } else {
-                units_to_stop.insert(mount_unit, ());
-            }
-        }
+    // A systemd manager that keeps its state in memory. Jobs finish as soon as they are submitted
+    // and are remembered in submission order.
+    #[derive(Default)]
+    struct InMemorySystemdManager {
+        units: RefCell<HashMap<String, super::UnitState>>,
+        jobs: RefCell<Vec<(String, super::Job)>>,
+        signals: RefCell<Vec<(String, nix::sys::signal::Signal)>>,
+        // Units that fail whenever they are started or restarted.
+        failing_units: HashMap<String, ()>,
+        // Units that keep crashing and being restarted by systemd after they were started.
+        crash_looping_units: HashMap<String, ()>,
+        main_pids: HashMap<String, u32>,
     }
 
-    // Also handles swap devices.
-    for (device, _) in current_swaps {
-        if !new_swaps.contains_key(&device) {
-            // Swap entry disappeared, so turn it off.  Can't use "systemctl stop" here because
-            // systemd has lots of alias units that prevent a stop from actually calling "swapoff".
-            if *action == Action::DryActivate {
-                eprintln!("would stop swap device: {}", &device);
+    impl InMemorySystemdManager {
+        fn job_result(&self, unit: &str) -> &'static str {
+            if self.failing_units.contains_key(unit) {
+                "failed"
             } else {
-                eprintln!("stopping swap device: {}", &device);
-                let c_device = std::ffi::CString::new(device.clone())
-                    .context("failed to convert device to cstring")?;
-                if unsafe { nix::libc::swapoff(c_device.as_ptr()) } != 0 {
-                    let err = std::io::Error::last_os_error();
-                    eprintln!("Failed to stop swapping to {device}: {err}");
-                }
+                "done"
             }
         }
-        // FIXME: update swap options (i.e. its priority).
-    }
-
-    // Should we have systemd re-exec itself?
-    let current_pid1_path = Path::new("/proc/1/exe")
-        .canonicalize()
-        .unwrap_or_else(|_| PathBuf::from("/unknown"));
-    let current_systemd_system_config = Path::new("/etc/systemd/system.conf")
-        .canonicalize()
-        .unwrap_or_else(|_| PathBuf::from("/unknown"));
-    let Ok(new_pid1_path) = new_systemd.join("lib/systemd/systemd").canonicalize() else {
-        die();
-    };
-    let new_systemd_system_config = toplevel
-        .join("etc/systemd/system.conf")
-        .canonicalize()
-        .unwrap_or_else(|_| PathBuf::from("/unknown"));
-
-    let restart_systemd = current_pid1_path != new_pid1_path
-        || current_systemd_system_config != new_systemd_system_config;
-
-    let units_to_stop_filtered = filter_units(&units_to_filter, &units_to_stop);
 
-    // Show dry-run actions.
-    if *action == Action::DryActivate {
-        if !units_to_stop_filtered.is_empty() {
-            let mut units = units_to_stop_filtered
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!("would stop the following units: {}", units.join(", "));
+        // The units a certain kind of job was submitted for, sorted.
+        fn jobs_of(&self, job: super::Job) -> Vec<String> {
+            let mut units = self
+                .jobs
+                .borrow()
+                .iter()
+                .filter(|(_, j)| *j == job)
+                .map(|(unit, _)| unit.clone())
+                .collect::<Vec<_>>();
+            units.sort();
+            units
         }
+    }
 
-        if !units_to_skip.is_empty() {
-            let mut units = units_to_skip
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!(
-                "would NOT stop the following changed units: {}",
-                units.join(", ")
-            );
+    impl super::SystemdManager for InMemorySystemdManager {
+        fn active_units(&self) -> anyhow::Result<HashMap<String, super::UnitState>> {
+            Ok(self.units.borrow().clone())
         }
 
-        eprintln!("would activate the configuration...");
-        _ = std::process::Command::new(out.join("dry-activate"))
-            .arg(&out)
-            .spawn()
-            .map(|mut child| child.wait());
+        fn unit_is_active(&self, unit: &str) -> anyhow::Result<bool> {
+            Ok(self
+                .units
+                .borrow()
+                .get(unit)
+                .is_some_and(|state| matches!(state.state.as_str(), "active" | "activating")))
+        }
 
-        // Handle the activation script requesting the restart or reload of a unit.
+        fn active_state(&self, unit: &str) -> anyhow::Result<String> {
+            Ok(self
+                .units
+                .borrow()
+                .get(unit)
+                .map(|state| state.state.clone())
+                .unwrap_or_else(|| "inactive".to_string()))
+        }
 
-        if std::fs::exists(DRY_RESTART_BY_ACTIVATION_LIST_FILE)?
-            || std::fs::exists(DRY_RELOAD_BY_ACTIVATION_LIST_FILE)?
-        {
-            eprintln!("WARN: restarting or reloading systemd units from the activation script is deprecated and will be removed in NixOS 26.11.");
+        fn exec_main_status(&self, _unit: &str) -> anyhow::Result<i32> {
+            Ok(0)
         }
 
-        for unit in std::fs::read_to_string(DRY_RESTART_BY_ACTIVATION_LIST_FILE)
-            .unwrap_or_default()
-            .lines()
-        {
-            let current_unit_file = Path::new("/etc/systemd/system").join(unit);
-            let new_unit_file = toplevel.join("etc/systemd/system").join(unit);
-            let mut base_unit = unit.to_string();
-            let mut new_base_unit_file = new_unit_file.clone();
-
-            // Detect template instances.
-            if let Some((Some(template_name), Some(template_instance))) =
-                template_unit_re.captures(unit).map(|captures| {
-                    (
-                        captures.get(1).map(|c| c.as_str()),
-                        captures.get(2).map(|c| c.as_str()),
-                    )
-                })
-            {
-                if !current_unit_file.exists() && !new_unit_file.exists() {
-                    base_unit = format!("{template_name}@.{template_instance}");
-                    new_base_unit_file = toplevel.join("etc/systemd/system").join(&base_unit);
+        fn submit_job(&self, unit: &str, job: super::Job) -> anyhow::Result<()> {
+            let mut units = self.units.borrow_mut();
+            match job {
+                super::Job::Stop => _ = units.remove(unit),
+                super::Job::Start
+                | super::Job::Restart
+                | super::Job::ReloadOrRestart
+                | super::Job::TryRestart => {
+                    if job == super::Job::TryRestart && !units.contains_key(unit) {
+                        return Ok(());
+                    }
+                    let state = match self.job_result(unit) {
+                        "done" if self.crash_looping_units.contains_key(unit) => "activating",
+                        "done" => "active",
+                        _ => "failed",
+                    };
+                    units.insert(unit.to_string(), unit_state(state, unit));
                 }
+                super::Job::Reload => {}
             }
+            self.jobs.borrow_mut().push((unit.to_string(), job));
 
-            let mut base_name = base_unit.as_str();
-            if let Some(Some(new_base_name)) = unit_name_re
-                .captures(&base_unit)
-                .map(|capture| capture.get(1).map(|first| first.as_str()))
-            {
-                base_name = new_base_name;
-            }
-
-            // Start units if they were not active previously
-            if !current_active_units.contains_key(unit) {
-                units_to_start.insert(unit.to_string(), ());
-                continue;
-            }
+            Ok(())
+        }
 
-            handle_modified_unit(
-                &toplevel,
-                unit,
-                base_name,
-                &new_unit_file,
-                &new_base_unit_file,
-                None,
-                &current_active_units,
-                &mut units_to_stop,
-                &mut units_to_start,
-                &mut units_to_reload,
-                &mut units_to_restart,
-                &mut units_to_skip,
-            )?;
+        fn kill_unit(&self, unit: &str, signal: nix::sys::signal::Signal) -> anyhow::Result<()> {
+            self.signals.borrow_mut().push((unit.to_string(), signal));
+            Ok(())
         }
 
-        remove_file_if_exists(DRY_RESTART_BY_ACTIVATION_LIST_FILE)
-            .with_context(|| format!("Failed to remove {DRY_RESTART_BY_ACTIVATION_LIST_FILE}"))?;
+        fn control_group(&self, unit: &str) -> anyhow::Result<String> {
+            Ok(format!("/system.slice/{unit}"))
+        }
 
-        for unit in std::fs::read_to_string(DRY_RELOAD_BY_ACTIVATION_LIST_FILE)
-            .unwrap_or_default()
-            .lines()
-        {
-            if current_active_units.contains_key(unit)
-                && !units_to_restart.contains_key(unit)
-                && !units_to_stop.contains_key(unit)
-            {
-                units_to_reload.insert(unit.to_string(), ());
-                record_unit(RELOAD_LIST_FILE, unit);
-            }
+        fn main_pid(&self, unit: &str) -> anyhow::Result<u32> {
+            Ok(self.main_pids.get(unit).copied().unwrap_or_default())
         }
 
-        remove_file_if_exists(DRY_RELOAD_BY_ACTIVATION_LIST_FILE)
-            .with_context(|| format!("Failed to remove {DRY_RELOAD_BY_ACTIVATION_LIST_FILE}"))?;
+        fn block_on_jobs(&self) {}
 
-        if restart_systemd {
-            eprintln!("would restart systemd");
+        fn finished_jobs(&self) -> Vec<(String, super::Job, String)> {
+            self.jobs
+                .borrow()
+                .iter()
+                .map(|(unit, job)| (unit.clone(), *job, self.job_result(unit).to_string()))
+                .collect()
         }
 
-        if !units_to_reload.is_empty() {
-            let mut units = units_to_reload
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!("would reload the following units: {}", units.join(", "));
+        fn timed_out_jobs(&self) -> Vec<(String, super::Job)> {
+            Vec::new()
         }
 
-        if !units_to_restart.is_empty() {
-            let mut units = units_to_restart
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!("would restart the following units: {}", units.join(", "));
+        fn reexecute(&self) -> anyhow::Result<()> {
+            Ok(())
         }
 
-        let units_to_start_filtered = filter_units(&units_to_filter, &units_to_start);
-        if !units_to_start_filtered.is_empty() {
-            let mut units = units_to_start_filtered
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!("would start the following units: {}", units.join(", "));
+        fn reload(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn reset_failed(&self) -> anyhow::Result<()> {
+            Ok(())
         }
 
-        std::process::exit(0);
+        fn settle(&self) {}
+    }
+
+    fn unit_state(state: &str, unit: &str) -> super::UnitState {
+        super::UnitState {
+            state: state.to_string(),
+            substate: "running".to_string(),
+            fragment_path: format!("/etc/systemd/system/{unit}"),
+        }
     }
 
-    log::info!("switching to system configuration {}", toplevel.display());
+    fn write_file(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
 
-    if !units_to_stop.is_empty() {
-        if !units_to_stop_filtered.is_empty() {
-            let mut units = units_to_stop_filtered
-                .keys()
-                .map(String::as_str)
-                .collect::<Vec<&str>>();
-            units.sort_by_key(|name| name.to_lowercase());
-            eprintln!("stopping the following units: {}", units.join(", "));
+    #[test]
+    fn plan_and_apply_switch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+        let run_dir = tmp.path().join("run");
+        std::fs::create_dir(&run_dir).unwrap();
+
+        for (unit, current_contents, new_contents) in [
+            // Stopped and started again
+            (
+                "stop-start.service",
+                "[Service]\nExecStart=/old\n",
+                Some("[Service]\nExecStart=/new\n"),
+            ),
+            // Restarted
+            (
+                "restart.service",
+                "[Service]\nX-StopIfChanged=false\nExecStart=/old\n",
+                Some("[Service]\nX-StopIfChanged=false\nExecStart=/new\n"),
+            ),
+            // Reloaded
+            (
+                "reload.service",
+                "[Unit]\nX-Reload-Triggers=old\n[Service]\nExecStart=/bin\n",
+                Some("[Unit]\nX-Reload-Triggers=new\n[Service]\nExecStart=/bin\n"),
+            ),
+            // Skipped
+            (
+                "skip.service",
+                "[Service]\nX-RestartIfChanged=false\nExecStart=/old\n",
+                Some("[Service]\nX-RestartIfChanged=false\nExecStart=/new\n"),
+            ),
+            // Untouched
+            (
+                "unchanged.service",
+                "[Service]\nExecStart=/bin\n",
+                Some("[Service]\nExecStart=/bin\n"),
+            ),
+            // Removed
+            ("removed.service", "[Service]\nExecStart=/bin\n", None),
+            // Always started, but not shown
+            ("multi-user.target", "[Unit]\n", Some("[Unit]\n")),
+        ] {
+            write_file(
+                &current.join("etc/systemd/system").join(unit),
+                current_contents,
+            );
+            if let Some(new_contents) = new_contents {
+                write_file(&new.join("etc/systemd/system").join(unit), new_contents);
+            }
         }
 
-        for unit in units_to_stop.keys() {
-            if let Ok(job_path) = systemd.stop_unit(unit, "replace") {
-                let mut j = submitted_jobs.borrow_mut();
-                j.insert(job_path.to_owned(), Job::Stop);
-            };
+        let current_fstab_units = parse_units(&[
+            (
+                "data.mount",
+                "[Mount]\nWhat=/dev/sda1\nWhere=/data\nType=ext4\n",
+            ),
+            ("dev-sda2.swap", "[Swap]\nWhat=/dev/sda2\n"),
+        ]);
+        let new_fstab_units = parse_units(&[(
+            "data.mount",
+            "[Mount]\nWhat=/dev/sda1\nWhere=/data\nType=ext4\nOptions=noatime\n",
+        )]);
+
+        let mut active_units = [
+            "stop-start.service",
+            "restart.service",
+            "reload.service",
+            "skip.service",
+            "unchanged.service",
+            "removed.service",
+            "multi-user.target",
+        ]
+        .into_iter()
+        .map(|unit| (unit.to_string(), unit_state("active", unit)))
+        .collect::<HashMap<_, _>>();
+        for unit in ["data.mount", "dev-sda2.swap"] {
+            active_units.insert(
+                unit.to_string(),
+                super::UnitState {
+                    fragment_path: format!("/run/systemd/generator/{unit}"),
+                    ..unit_state("active", unit)
+                },
+            );
         }
 
-        block_on_jobs(&dbus_conn, &submitted_jobs);
-    }
+        let snapshot = super::SystemSnapshot {
+            drop_in_dirs: super::DropInDirs::system(&current),
+            root: current,
+            unit_dir: super::SYSTEM_UNIT_DIR,
+            active_units: active_units.clone(),
+            pid1_path: "/nix/store/systemd/lib/systemd/systemd".into(),
+            pending: super::SwitchPlan::default(),
+            fstab_units: current_fstab_units,
+        };
 
-    if !units_to_skip.is_empty() {
-        let mut units = units_to_skip
-            .keys()
-            .map(String::as_str)
-            .collect::<Vec<&str>>();
-        units.sort_by_key(|name| name.to_lowercase());
-        eprintln!(
-            "NOT restarting the following changed units: {}",
-            units.join(", "),
+        let mut plan = super::plan_switch(
+            &snapshot,
+            &new,
+            Path::new("/nix/store/systemd/lib/systemd/systemd"),
+            &new_fstab_units,
+            false,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            super::sorted_units(&plan.units_to_stop),
+            ["removed.service", "stop-start.service"]
         );
-    }
+        assert_eq!(
+            super::sorted_units(&plan.units_to_start),
+            ["multi-user.target", "stop-start.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_restart),
+            ["restart.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload),
+            ["data.mount", "reload.service"]
+        );
+        assert_eq!(super::sorted_units(&plan.units_to_skip), ["skip.service"]);
+        assert_eq!(
+            super::sorted_units(&plan.units_to_filter),
+            ["multi-user.target"]
+        );
+        assert_eq!(plan.swaps_to_stop, ["/dev/sda2"]);
+        assert_eq!(plan.mounts.len(), 1);
+        assert_eq!(plan.mounts[0].action, super::MountAction::Reload);
+        assert_eq!(
+            plan.unit_differences.keys().collect::<Vec<_>>(),
+            [
+                "data.mount",
+                "reload.service",
+                "restart.service",
+                "skip.service",
+                "stop-start.service"
+            ]
+        );
+        assert_eq!(
+            plan.unit_differences["reload.service"],
+            [super::UnitDifference {
+                section: "Unit".to_string(),
+                key: Some("X-Reload-Triggers".to_string()),
+                old: Some(vec!["old".to_string()]),
+                new: Some(vec!["new".to_string()]),
+                rule: super::DifferenceRule::ReloadTriggers,
+            }]
+        );
+        assert_eq!(
+            plan.unit_differences["data.mount"][0].to_string(),
+            "[Mount] Options: (unset) -> noatime (reload, only mount options changed)"
+        );
+        assert!(!plan.restart_systemd);
 
-    // Wait for all stop jobs to finish
-    block_on_jobs(&dbus_conn, &submitted_jobs);
+        let systemd = InMemorySystemdManager {
+            units: RefCell::new(active_units.clone()),
+            failing_units: HashMap::from([("restart.service".to_string(), ())]),
+            ..Default::default()
+        };
 
-    let mut exit_code = 0;
+        let mut journal = super::SwitchJournal::new(&new, None, &super::Action::Test, &plan);
+        journal.write(&run_dir).unwrap();
+
+        super::stop_units(&systemd, &plan);
+        assert!(super::reactivate_sysinit(&systemd));
+        assert!(!super::apply_unit_changes(
+            &systemd,
+            &mut plan,
+            &new.join(super::SYSTEM_UNIT_DIR),
+            Some(&snapshot.drop_in_dirs),
+            Some((&mut journal, &run_dir)),
+        )
+        .unwrap());
 
-    // Activate the new configuration (i.e., update /etc, make accounts, and so on).
-    eprintln!("activating the configuration...");
-    match std::process::Command::new(out.join("activate"))
-        .arg(&out)
-        .spawn()
-        .map(|mut child| child.wait())
-    {
-        Ok(Ok(status)) if status.success() => {}
-        Err(_) => {
-            // allow toplevel to not have an activation script
-        }
-        _ => {
-            eprintln!("Failed to run activate script");
-            exit_code = 2;
-        }
-    }
+        // Stop jobs come first, then sysinit is reactivated before units are reloaded, restarted
+        // and started.
+        let jobs = systemd.jobs.borrow().clone();
+        assert!(jobs[..2].iter().all(|(_, job)| *job == super::Job::Stop));
+        assert_eq!(
+            jobs[2],
+            (
+                super::SYSINIT_REACTIVATION_TARGET.to_string(),
+                super::Job::Restart
+            )
+        );
+        assert_eq!(
+            systemd.jobs_of(super::Job::Reload),
+            ["data.mount", "reload.service"]
+        );
+        assert_eq!(
+            systemd.jobs_of(super::Job::Start),
+            ["multi-user.target", "stop-start.service"]
+        );
+        assert_eq!(jobs.last().unwrap().1, super::Job::Start);
 
-    if std::fs::exists(RESTART_BY_ACTIVATION_LIST_FILE)?
-        || std::fs::exists(RELOAD_BY_ACTIVATION_LIST_FILE)?
-    {
-        eprintln!("WARN: restarting or reloading systemd units from the activation script is deprecated and will be removed in NixOS 26.11.");
+        let (failed, new_units) = super::failed_and_new_units(&systemd, &active_units).unwrap();
+        assert_eq!(failed, ["restart.service"]);
+        assert_eq!(new_units, [super::SYSINIT_REACTIVATION_TARGET]);
+        assert!(!run_dir.join(super::SWITCH_JOURNAL_FILE).exists());
     }
 
-    // Handle the activation script requesting the restart or reload of a unit.
-    for unit in std::fs::read_to_string(RESTART_BY_ACTIVATION_LIST_FILE)
-        .unwrap_or_default()
-        .lines()
-    {
-        let new_unit_file = toplevel.join("etc/systemd/system").join(unit);
-        let mut base_unit = unit.to_string();
-        let mut new_base_unit_file = new_unit_file.clone();
-
-        // Detect template instances.
-        if let Some((Some(template_name), Some(template_instance))) =
-            template_unit_re.captures(unit).map(|captures| {
-                (
-                    captures.get(1).map(|c| c.as_str()),
-                    captures.get(2).map(|c| c.as_str()),
-                )
-            })
-        {
-            if !new_unit_file.exists() {
-                base_unit = format!("{template_name}@.{template_instance}");
-                new_base_unit_file = toplevel.join("etc/systemd/system").join(&base_unit);
-            }
+    #[test]
+    fn switch_journal() {
+        let run_dir = tempfile::tempdir().unwrap();
+        let run_dir = run_dir.path();
+        assert!(super::SwitchJournal::read(run_dir).unwrap().is_none());
+
+        let mut plan = super::SwitchPlan {
+            units_to_stop: HashMap::from([("old.service".to_string(), ())]),
+            units_to_restart: HashMap::from([
+                ("a.service".to_string(), ()),
+                ("c.service".to_string(), ()),
+            ]),
+            units_to_start: HashMap::from([("b.service".to_string(), ())]),
+            units_to_try_restart: HashMap::from([("try.service".to_string(), ())]),
+            units_to_reload_or_restart: HashMap::from([("reload.service".to_string(), ())]),
+            units_to_signal: BTreeMap::from([(
+                "signal.service".to_string(),
+                nix::sys::signal::Signal::SIGHUP,
+            )]),
+            units_to_defer: BTreeMap::from([(
+                "deferred.service".to_string(),
+                "unit changed".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let mut journal = super::SwitchJournal::new(
+            Path::new("/nix/store/new"),
+            Some("/nix/store/old".into()),
+            &super::Action::Switch,
+            &plan,
+        );
+        // Completion is tracked per unit, the switch was interrupted while restarting c.service.
+        journal.finish_jobs(&[
+            (
+                "old.service".to_string(),
+                super::Job::Stop,
+                "done".to_string(),
+            ),
+            (
+                "a.service".to_string(),
+                super::Job::Restart,
+                "done".to_string(),
+            ),
+            (
+                "b.service".to_string(),
+                super::Job::Restart,
+                "done".to_string(),
+            ),
+        ]);
+        journal
+            .reach(super::SwitchPhase::UnitsStopped, run_dir)
+            .unwrap();
+
+        // Units requested by the activation script are added, units that disappeared are dropped.
+        plan.units_to_restart
+            .insert("requested.service".to_string(), ());
+        plan.units_to_start.clear();
+        journal.update(&plan);
+        journal.write(run_dir).unwrap();
+
+        let read = super::SwitchJournal::read(run_dir).unwrap().unwrap();
+        assert_eq!(read.toplevel, Path::new("/nix/store/new"));
+        assert_eq!(
+            read.previous_toplevel.as_deref(),
+            Some(Path::new("/nix/store/old"))
+        );
+        assert_eq!(read.action, "switch");
+        assert_eq!(read.phase, super::SwitchPhase::UnitsStopped);
+        assert!(read.pending(super::Job::Stop).is_empty());
+        assert_eq!(
+            super::sorted_units(&read.pending(super::Job::Restart)),
+            ["c.service", "requested.service"]
+        );
+        assert!(read.pending(super::Job::Start).is_empty());
+
+        let mut pending = read.pending_plan();
+        assert!(pending.units_to_stop.is_empty());
+        assert_eq!(pending.units_to_restart.len(), 2);
+        assert_eq!(pending.units_to_signal, plan.units_to_signal);
+        assert_eq!(pending.units_to_defer, plan.units_to_defer);
+
+        journal.finish_signal("signal.service");
+        assert!(journal.pending_plan().units_to_signal.is_empty());
+
+        // A switch that follows the interrupted one picks up all of its pending jobs, but doesn't
+        // defer units that it restarts anyway.
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+        for toplevel in [&current, &new] {
+            write_file(
+                &toplevel
+                    .join(super::SYSTEM_UNIT_DIR)
+                    .join("deferred.service"),
+                &format!("[Service]\nExecStart={}\n", toplevel.display()),
+            );
         }
+        pending.units_to_stop.insert("stop.service".to_string(), ());
+        let plan = super::plan_switch(
+            &super::SystemSnapshot {
+                pending,
+                ..snapshot(&current, &["deferred.service"])
+            },
+            &new,
+            Path::new("/nix/store/systemd/lib/systemd/systemd"),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            super::sorted_units(&plan.units_to_stop),
+            ["deferred.service", "stop.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_restart),
+            ["c.service", "requested.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_try_restart),
+            ["try.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload_or_restart),
+            ["reload.service"]
+        );
+        assert_eq!(plan.units_to_signal.len(), 1);
+        assert!(plan.units_to_defer.is_empty());
 
-        let mut base_name = base_unit.as_str();
-        if let Some(Some(new_base_name)) = unit_name_re
-            .captures(&base_unit)
-            .map(|capture| capture.get(1).map(|first| first.as_str()))
-        {
-            base_name = new_base_name;
-        }
+        super::SwitchJournal::remove(run_dir).unwrap();
+        assert!(super::SwitchJournal::read(run_dir).unwrap().is_none());
+    }
 
-        // Start units if they were not active previously
-        if !current_active_units.contains_key(unit) {
-            units_to_start.insert(unit.to_string(), ());
-            record_unit(START_LIST_FILE, unit);
-            continue;
+    #[test]
+    fn switch_journal_version_mismatch() {
+        let run_dir = tempfile::tempdir().unwrap();
+        write_file(
+            &run_dir.path().join(super::SWITCH_JOURNAL_FILE),
+            r#"{"version": 999, "units": []}"#,
+        );
+        assert!(super::SwitchJournal::read(run_dir.path())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn scratch_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let first = super::ScratchDir::new("stc-test").unwrap();
+        let second = super::ScratchDir::new("stc-test").unwrap();
+        assert_ne!(first.path, second.path);
+        let metadata = std::fs::metadata(&first.path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+
+        let path = first.path.clone();
+        write_file(&path.join("fstab-generator/normal/data.mount"), "[Mount]\n");
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn diff_configurations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let old = tmp.path().join("old");
+        let new = tmp.path().join("new");
+        for (toplevel, value) in [(&old, "old"), (&new, "new")] {
+            let unit_dir = toplevel.join("etc/systemd/system");
+            write_file(
+                &unit_dir.join("same.service"),
+                "[Service]\nExecStart=/bin/same\n",
+            );
+            write_file(
+                &unit_dir.join("changed.service"),
+                &format!("[Service]\nExecStart=/bin/{value}\n"),
+            );
+            write_file(
+                &unit_dir.join("reload.service"),
+                &format!("[Unit]\nX-Reload-Triggers={value}\n[Service]\nExecStart=/bin/reload\n"),
+            );
+            write_file(&unit_dir.join(format!("{value}.service")), "[Service]\n");
+            write_file(&toplevel.join("init-interface-version"), "systemd 2\n");
         }
+        write_file(&new.join("etc/systemd/system.conf"), "[Manager]\n");
 
-        handle_modified_unit(
-            &toplevel,
-            unit,
-            base_name,
-            &new_unit_file,
-            &new_base_unit_file,
-            None,
-            &current_active_units,
-            &mut units_to_stop,
-            &mut units_to_start,
-            &mut units_to_reload,
-            &mut units_to_restart,
-            &mut units_to_skip,
-        )?;
+        let diff = super::diff_configurations(&old, &new, HashMap::new(), &HashMap::new()).unwrap();
+        assert_eq!(diff.added_units, ["new.service"]);
+        assert_eq!(diff.removed_units, ["old.service"]);
+        assert_eq!(diff.changed_units, ["changed.service", "reload.service"]);
+        assert!(!diff.init_interface_changed);
+        assert_eq!(
+            super::planned_action(&diff.plan, "changed.service"),
+            "stop and start"
+        );
+        assert_eq!(
+            super::planned_action(&diff.plan, "reload.service"),
+            "reload"
+        );
+        assert_eq!(super::planned_action(&diff.plan, "old.service"), "stop");
+        assert!(diff.plan.unit_differences.contains_key("changed.service"));
+        // The new configuration has a system.conf the old one lacks.
+        assert!(diff.plan.restart_systemd);
     }
 
-    // We can remove the file now because it has been propagated to the other restart/reload files
-    remove_file_if_exists(RESTART_BY_ACTIVATION_LIST_FILE)
-        .with_context(|| format!("Failed to remove {RESTART_BY_ACTIVATION_LIST_FILE}"))?;
-
-    for unit in std::fs::read_to_string(RELOAD_BY_ACTIVATION_LIST_FILE)
-        .unwrap_or_default()
-        .lines()
-    {
-        if current_active_units.contains_key(unit)
-            && !units_to_restart.contains_key(unit)
-            && !units_to_stop.contains_key(unit)
-        {
-            units_to_reload.insert(unit.to_string(), ());
-            record_unit(RELOAD_LIST_FILE, unit);
+    // Builds a snapshot of a system rooted at `root` on which the given units are active.
+    fn snapshot(root: &Path, active_units: &[&str]) -> super::SystemSnapshot {
+        super::SystemSnapshot {
+            root: root.to_path_buf(),
+            drop_in_dirs: super::DropInDirs::system(root),
+            unit_dir: super::SYSTEM_UNIT_DIR,
+            active_units: active_units
+                .iter()
+                .map(|unit| (unit.to_string(), unit_state("active", unit)))
+                .collect(),
+            pid1_path: "/nix/store/systemd/lib/systemd/systemd".into(),
+            pending: super::SwitchPlan::default(),
+            fstab_units: HashMap::new(),
         }
     }
 
-    // We can remove the file now because it has been propagated to the other reload file
-    remove_file_if_exists(RELOAD_BY_ACTIVATION_LIST_FILE)
-        .with_context(|| format!("Failed to remove {RELOAD_BY_ACTIVATION_LIST_FILE}"))?;
+    // Parses the given unit files, keyed by unit name.
+    fn parse_units(units: &[(&str, &str)]) -> HashMap<String, super::UnitInfo> {
+        units
+            .iter()
+            .map(|(unit, contents)| {
+                let mut unit_info = HashMap::new();
+                super::parse_systemd_ini(&mut unit_info, contents.as_bytes()).unwrap();
+                (unit.to_string(), unit_info)
+            })
+            .collect()
+    }
 
-    // Restart systemd if necessary. Note that this is done using the current version of systemd,
-    // just in case the new one has trouble communicating with the running pid 1.
-    if restart_systemd {
-        eprintln!("restarting systemd...");
-        *systemd_is_reloading.borrow_mut() = true;
-        _ = systemd.reexecute(); // we don't get a dbus reply here
+    #[test]
+    fn restart_waves() {
+        let units = [
+            "backend.service",
+            "db.service",
+            "frontend.service",
+            "other.service",
+        ]
+        .map(|unit| (unit.to_string(), ()))
+        .into();
+        let unit_infos = parse_units(&[
+            (
+                "frontend.service",
+                "[Unit]\nAfter=network.target backend.service\n",
+            ),
+            ("backend.service", "[Unit]\nRequires=db.service\n"),
+            ("db.service", "[Unit]\nBefore=multi-user.target\n"),
+        ]);
+        assert_eq!(
+            super::restart_waves(&units, &unit_infos),
+            [
+                vec!["db.service", "other.service"],
+                vec!["backend.service"],
+                vec!["frontend.service"],
+            ]
+        );
 
-        log::debug!("waiting for systemd restart to finish");
+        // `Before=` orders the other unit after this one, cycles end up in the last wave.
+        let unit_infos = parse_units(&[
+            ("db.service", "[Unit]\nBefore=backend.service\n"),
+            ("backend.service", "[Unit]\nAfter=frontend.service\n"),
+            ("frontend.service", "[Unit]\nAfter=backend.service\n"),
+        ]);
+        assert_eq!(
+            super::restart_waves(&units, &unit_infos),
+            [
+                vec!["db.service", "other.service"],
+                vec!["backend.service", "frontend.service"],
+            ]
+        );
+    }
 
-        let mut reexec_time_waited = Duration::from_secs(0);
-        while *systemd_is_reloading.borrow() {
-            _ = dbus_conn
-                .process(DBUS_PROCESS_TIME)
-                .context("Failed to process dbus messages")?;
-            reexec_time_waited += DBUS_PROCESS_TIME;
-            if reexec_time_waited >= DAEMON_RELOAD_TIMEOUT {
-                anyhow::bail!(
-                    "systemd daemon reexecute failed, timeout after {:?}",
-                    DAEMON_RELOAD_TIMEOUT
-                );
+    #[test]
+    fn restart_in_batches() {
+        let unit_infos = parse_units(&[
+            (
+                "worker@.service",
+                "[Service]\nX-RestartBatchSize=2\nX-RestartBatchDelay=0\n",
+            ),
+            (
+                "slow@.service",
+                "[Service]\nX-RestartBatchSize=1\nX-RestartBatchDelay=1min 500ms\n",
+            ),
+            (
+                "typo@.service",
+                "[Service]\nX-RestartBatchSize=1\nX-RestartBatchDelay=5 minuets\n",
+            ),
+            ("invalid@.service", "[Service]\nX-RestartBatchSize=0\n"),
+        ]);
+        let batch =
+            super::restart_batch("worker@.service", &unit_infos["worker@.service"]).unwrap();
+        assert_eq!(
+            batch,
+            super::RestartBatch {
+                size: 2,
+                delay: Duration::ZERO
             }
+        );
+        assert_eq!(
+            super::restart_batch("slow@.service", &unit_infos["slow@.service"])
+                .unwrap()
+                .delay,
+            Duration::from_millis(60_500)
+        );
+        assert_eq!(
+            super::restart_batch("typo@.service", &unit_infos["typo@.service"])
+                .unwrap()
+                .delay,
+            Duration::ZERO
+        );
+        assert!(
+            super::restart_batch("invalid@.service", &unit_infos["invalid@.service"]).is_none()
+        );
+
+        for (span, expected) in [
+            ("30", Some(Duration::from_secs(30))),
+            ("1.5s", Some(Duration::from_millis(1500))),
+            ("250 ms", Some(Duration::from_millis(250))),
+            ("1h 2min3s", Some(Duration::from_secs(3723))),
+            ("2 weeks", Some(Duration::from_secs(14 * 24 * 60 * 60))),
+            ("", None),
+            ("infinity", None),
+            ("-5s", None),
+            ("1.2.3s", None),
+        ] {
+            assert_eq!(super::parse_time_span(span), expected, "{span}");
         }
+
+        let instances = (1..=5)
+            .map(|i| format!("worker@{i}.service"))
+            .collect::<Vec<_>>();
+        let systemd = InMemorySystemdManager::default();
+        assert!(super::restart_in_batches(&systemd, "worker@.service", &instances, batch).unwrap());
+        assert_eq!(systemd.jobs_of(super::Job::Restart), instances);
+
+        // The rolling restart stops at the first batch that doesn't come back up.
+        let systemd = InMemorySystemdManager {
+            failing_units: HashMap::from([("worker@3.service".to_string(), ())]),
+            ..Default::default()
+        };
+        assert!(
+            !super::restart_in_batches(&systemd, "worker@.service", &instances, batch).unwrap()
+        );
+        assert_eq!(
+            systemd.jobs_of(super::Job::Restart),
+            instances[..4].to_vec()
+        );
+
+        // An instance that is stuck activating after its restart is not back up either.
+        let systemd = InMemorySystemdManager {
+            crash_looping_units: HashMap::from([("worker@1.service".to_string(), ())]),
+            ..Default::default()
+        };
+        assert!(
+            !super::restart_in_batches(&systemd, "worker@.service", &instances, batch).unwrap()
+        );
+        assert_eq!(
+            systemd.jobs_of(super::Job::Restart),
+            instances[..2].to_vec()
+        );
     }
 
-    // Forget about previously failed services.
-    systemd
-        .reset_failed()
-        .context("Failed to reset failed units")?;
-
-    // Make systemd reload its units.
-    *systemd_is_reloading.borrow_mut() = true;
-    _ = systemd.reload(); // we don't get a dbus reply here
-    log::debug!("waiting for systemd reload to finish");
-
-    let mut reload_time_waited = Duration::from_secs(0);
-    while *systemd_is_reloading.borrow() {
-        _ = dbus_conn
-            .process(DBUS_PROCESS_TIME)
-            .context("Failed to process dbus messages")?;
-        reload_time_waited += DBUS_PROCESS_TIME;
-        if reload_time_waited >= DAEMON_RELOAD_TIMEOUT {
-            anyhow::bail!(
-                "systemd daemon reload failed, timeout after {:?}",
-                DAEMON_RELOAD_TIMEOUT
+    #[test]
+    fn wait_for_user_switches() {
+        let spawn = |script: &str| {
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+        let children = vec![
+            ("hung".to_string(), spawn("exec sleep 10")),
+            ("alice".to_string(), spawn("true")),
+            (
+                "bob".to_string(),
+                spawn("echo a.service; echo b.service; exit 4"),
+            ),
+        ];
+
+        let start = std::time::Instant::now();
+        let user_switches = super::wait_for_user_switches(children, Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(
+            user_switches
+                .iter()
+                .map(|user_switch| user_switch.user.as_str())
+                .collect::<Vec<_>>(),
+            ["alice", "bob", "hung"]
+        );
+        assert_eq!(user_switches[0].status, super::UserSwitchStatus::Done);
+        assert!(matches!(
+            user_switches[1].status,
+            super::UserSwitchStatus::Failed(_)
+        ));
+        assert_eq!(user_switches[1].failed_units, ["a.service", "b.service"]);
+        assert_eq!(user_switches[2].status, super::UserSwitchStatus::TimedOut);
+    }
+
+    #[test]
+    fn plan_user_switch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+        for (unit, current_contents, new_contents) in [
+            (
+                "changed.service",
+                "[Service]\nExecStart=a\n",
+                "[Service]\nExecStart=b\n",
+            ),
+            (
+                "reload.service",
+                "[Service]\nExecStart=a\nX-ReloadIfChanged=true\n",
+                "[Service]\nExecStart=b\nX-ReloadIfChanged=true\n",
+            ),
+            (
+                "skip.service",
+                "[Service]\nExecStart=a\nX-RestartIfChanged=false\n",
+                "[Service]\nExecStart=b\nX-RestartIfChanged=false\n",
+            ),
+            ("removed.service", "[Service]\nExecStart=a\n", ""),
+            (
+                "overridden.service",
+                "[Service]\nExecStart=a\n",
+                "[Service]\nExecStart=b\n",
+            ),
+        ] {
+            write_file(
+                &current.join(super::USER_UNIT_DIR).join(unit),
+                current_contents,
             );
+            if !new_contents.is_empty() {
+                write_file(&new.join(super::USER_UNIT_DIR).join(unit), new_contents);
+            }
         }
-    }
+        // System units with the same name are not looked at.
+        write_file(
+            &new.join(super::SYSTEM_UNIT_DIR).join("removed.service"),
+            "[Service]\n",
+        );
+        // Drop-ins of the user manager apply, those of the system manager don't. The user switch
+        // finds them through the environment it is started with.
+        let home = tmp.path().join("home");
+        write_file(
+            &home.join(".config/systemd/user/overridden.service.d/override.conf"),
+            "[Service]\nX-RestartIfChanged=false\n",
+        );
+        write_file(
+            &current.join("run/systemd/system.control/changed.service.d/override.conf"),
+            "[Service]\nX-RestartIfChanged=false\n",
+        );
 
-    dbus_conn
-        .remove_match(reloading_token)
-        .context("Failed to cleanup systemd Reloading match")?;
+        let units = [
+            "changed.service",
+            "reload.service",
+            "skip.service",
+            "removed.service",
+            "overridden.service",
+        ];
+        let command = super::user_switch_command(
+            Path::new("/proc/self/exe"),
+            1000,
+            100,
+            Some(&home),
+            "/run/user/1000",
+            &new,
+            Some(&current),
+        );
+        let env = command
+            .get_envs()
+            .filter_map(|(name, value)| Some((name.to_str()?.to_string(), value?.to_owned())))
+            .collect::<HashMap<_, _>>();
+        let drop_in_dirs = super::DropInDirs::user(|name| env.get(name).cloned());
+        assert_eq!(
+            drop_in_dirs.before,
+            [
+                home.join(".config/systemd/user.control"),
+                Path::new("/run/user/1000/systemd/user.control").to_path_buf(),
+                Path::new("/run/user/1000/systemd/transient").to_path_buf(),
+                home.join(".config/systemd/user"),
+            ]
+        );
 
-    // Reload user units
-    match logind.list_users() {
-        Err(err) => {
-            eprintln!("Unable to list users with logind: {err}");
-            die();
-        }
-        Ok(users) => {
-            for (uid, name, user_dbus_path) in users {
-                let proxy = dbus_conn.with_proxy(
-                    "org.freedesktop.login1",
-                    &user_dbus_path,
-                    Duration::from_millis(5000),
-                );
-                let gid: u32 = proxy
-                    .get("org.freedesktop.login1.User", "GID")
-                    .with_context(|| format!("Failed to get GID for {name}"))?;
+        let user_snapshot = super::SystemSnapshot {
+            unit_dir: super::USER_UNIT_DIR,
+            drop_in_dirs,
+            active_units: units
+                .iter()
+                .map(|unit| {
+                    (
+                        unit.to_string(),
+                        super::UnitState {
+                            fragment_path: format!("/etc/systemd/user/{unit}"),
+                            ..unit_state("active", unit)
+                        },
+                    )
+                })
+                .collect(),
+            ..snapshot(&current, &[])
+        };
 
-                let runtime_path: String = proxy
-                    .get("org.freedesktop.login1.User", "RuntimePath")
-                    .with_context(|| format!("Failed to get runtime directory for {name}"))?;
+        let plan = super::plan_switch(
+            &user_snapshot,
+            &new,
+            Path::new(""),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            super::sorted_units(&plan.units_to_stop),
+            ["changed.service", "removed.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_start),
+            ["changed.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload),
+            ["reload.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_skip),
+            ["overridden.service", "skip.service"]
+        );
 
-                eprintln!("reloading user units for {name}...");
-                let myself = Path::new("/proc/self/exe")
-                    .canonicalize()
-                    .context("Failed to get full path to /proc/self/exe")?;
+        // Units of the system manager are left to the system switch.
+        let user_snapshot = super::SystemSnapshot {
+            unit_dir: super::USER_UNIT_DIR,
+            ..snapshot(&current, &units)
+        };
+        let plan = super::plan_switch(
+            &user_snapshot,
+            &new,
+            Path::new(""),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(plan.units_to_stop.is_empty());
+    }
 
-                log::debug!("Performing user switch for {name}");
-                std::process::Command::new(&myself)
-                    .uid(uid)
-                    .gid(gid)
-                    .env_clear()
-                    .env("XDG_RUNTIME_DIR", runtime_path)
-                    .env("__NIXOS_SWITCH_TO_CONFIGURATION_PARENT_EXE", &myself)
-                    .spawn()
-                    .with_context(|| format!("Failed to spawn user activation for {name}"))?
-                    .wait()
-                    .with_context(|| format!("Failed to run user activation for {name}"))?;
-            }
+    #[test]
+    fn plan_switch_methods() {
+        assert_eq!(
+            "kill-signal:SIGHUP".parse::<super::SwitchMethod>().unwrap(),
+            super::SwitchMethod::KillSignal(nix::sys::signal::Signal::SIGHUP)
+        );
+        assert_eq!(
+            "stop-start".parse::<super::SwitchMethod>().unwrap(),
+            super::SwitchMethod::StopStart
+        );
+        assert!("kill-signal:SIGFOO".parse::<super::SwitchMethod>().is_err());
+        assert!("restart-later".parse::<super::SwitchMethod>().is_err());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+        let units = [
+            ("restart.service", "restart"),
+            ("stop-start.service", "stop-start"),
+            ("reload.service", "reload"),
+            ("try-restart.service", "try-restart"),
+            ("reload-or-restart.service", "reload-or-restart"),
+            ("hup.service", "kill-signal:SIGHUP"),
+            ("defer.service", "defer"),
+            ("none.service", "none"),
+            // Invalid methods are ignored, so X-StopIfChanged applies.
+            ("invalid.service", "sometimes"),
+        ];
+        for (unit, method) in units {
+            write_file(
+                &current.join(super::SYSTEM_UNIT_DIR).join(unit),
+                &format!("[Service]\nExecStart=a\nX-SwitchMethod={method}\n"),
+            );
+            // X-SwitchMethod takes precedence over the other flags.
+            write_file(
+                &new.join(super::SYSTEM_UNIT_DIR).join(unit),
+                &format!(
+                    "[Service]\nExecStart=b\nX-SwitchMethod={method}\nX-ReloadIfChanged=true\n"
+                ),
+            );
         }
+        let units = units.map(|(unit, _)| unit);
+
+        let mut plan = super::plan_switch(
+            &snapshot(&current, &units),
+            &new,
+            Path::new(""),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            super::sorted_units(&plan.units_to_restart),
+            ["restart.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_stop),
+            ["stop-start.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload),
+            ["invalid.service", "reload.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_try_restart),
+            ["try-restart.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload_or_restart),
+            ["reload-or-restart.service"]
+        );
+        assert_eq!(
+            plan.units_to_signal,
+            BTreeMap::from([("hup.service".to_string(), nix::sys::signal::Signal::SIGHUP)])
+        );
+        assert_eq!(
+            plan.units_to_defer,
+            BTreeMap::from([(
+                "defer.service".to_string(),
+                "changed [Service] ExecStart, [Service] X-ReloadIfChanged".to_string()
+            )])
+        );
+        assert_eq!(super::sorted_units(&plan.units_to_skip), ["none.service"]);
+        assert_eq!(super::planned_action(&plan, "hup.service"), "signal");
+
+        // try-restart leaves units alone that were stopped in the meantime.
+        let systemd = InMemorySystemdManager::default();
+        assert!(super::apply_unit_changes(
+            &systemd,
+            &mut plan,
+            &new.join(super::SYSTEM_UNIT_DIR),
+            None,
+            None
+        )
+        .unwrap());
+        assert!(systemd.jobs_of(super::Job::TryRestart).is_empty());
+        assert_eq!(
+            systemd.jobs_of(super::Job::ReloadOrRestart),
+            ["reload-or-restart.service"]
+        );
+        assert_eq!(
+            *systemd.signals.borrow(),
+            [("hup.service".to_string(), nix::sys::signal::Signal::SIGHUP)]
+        );
+        assert!(!systemd
+            .jobs
+            .borrow()
+            .iter()
+            .any(|(unit, _)| unit == "defer.service" || unit == "none.service"));
     }
 
-    // Restart sysinit-reactivation.target. This target only exists to restart services ordered
-    // before sysinit.target. We cannot use X-StopOnReconfiguration to restart sysinit.target
-    // because then ALL services of the system would be restarted since all normal services have a
-    // default dependency on sysinit.target. sysinit-reactivation.target ensures that services
-    // ordered BEFORE sysinit.target get re-started in the correct order. Ordering between these
-    // services is respected.
-    eprintln!("restarting {SYSINIT_REACTIVATION_TARGET}");
-    match systemd.restart_unit(SYSINIT_REACTIVATION_TARGET, "replace") {
-        Ok(job_path) => {
-            let mut jobs = submitted_jobs.borrow_mut();
-            jobs.insert(job_path, Job::Restart);
-        }
-        Err(err) => {
-            eprintln!("Failed to restart {SYSINIT_REACTIVATION_TARGET}: {err}");
-            exit_code = 4;
+    #[test]
+    fn pending_restarts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+        let run_dir = tmp.path().join("run");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        for unit in ["db.service", "broker.service"] {
+            write_file(
+                &current.join(super::SYSTEM_UNIT_DIR).join(unit),
+                "[Service]\nExecStart=a\nX-RestartDeferred=true\n",
+            );
+            write_file(
+                &new.join(super::SYSTEM_UNIT_DIR).join(unit),
+                "[Service]\nExecStart=b\nX-RestartDeferred=true\n",
+            );
         }
-    }
+        write_file(
+            &new.join(super::SYSTEM_UNIT_DIR).join("broker.service"),
+            "[Unit]\nAfter=db.service\n[Service]\nExecStart=b\nX-RestartDeferred=true\n",
+        );
 
-    // Wait for the restart job of sysinit-reactivation.service to finish
-    block_on_jobs(&dbus_conn, &submitted_jobs);
+        let plan = super::plan_switch(
+            &snapshot(&current, &["db.service", "broker.service"]),
+            &new,
+            Path::new(""),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(plan.units_to_stop.is_empty());
+        assert!(plan.units_to_restart.is_empty());
 
-    // Before reloading we need to ensure that the units are still active. They may have been
-    // deactivated because one of their requirements got stopped. If they are inactive but should
-    // have been reloaded, the user probably expects them to be started.
-    if !units_to_reload.is_empty() {
-        for (unit, _) in units_to_reload.clone() {
-            if !unit_is_active(&dbus_conn, &unit)? {
-                // Figure out if we need to start the unit. We skip units that are not found in the
-                // NixOS-managed /etc/systemd/system directory (e.g. mount units that are generated
-                // from /etc/fstab).
-                if parse_unit(
-                    toplevel.join("etc/systemd/system").join(&unit).as_path(),
-                    toplevel.join("etc/systemd/system").join(&unit).as_path(),
-                )
-                .map(|unit_info| {
-                    !parse_systemd_bool(Some(&unit_info), "Unit", "RefuseManualStart", false)
-                        || parse_systemd_bool(Some(&unit_info), "Unit", "X-OnlyManualStart", false)
-                })
-                .unwrap_or_default()
-                {
-                    units_to_start.insert(unit.clone(), ());
-                    record_unit(START_LIST_FILE, &unit);
-                }
+        let mut pending = super::PendingRestarts::read(&run_dir).unwrap();
+        pending.update(&plan, &new);
+        pending.write(&run_dir).unwrap();
 
-                // Don't reload the unit, reloading would fail
-                units_to_reload.remove(&unit);
-                unrecord_unit(RELOAD_LIST_FILE, &unit);
+        // A later switch keeps the configuration that deferred the restart first.
+        let mut pending = super::PendingRestarts::read(&run_dir).unwrap();
+        pending.update(&plan, Path::new("/nix/store/later-nixos-system"));
+        assert_eq!(
+            pending.units["broker.service"],
+            super::PendingRestart {
+                reason: "changed [Service] ExecStart, [Unit] After".to_string(),
+                toplevel: new.clone(),
             }
-        }
-    }
+        );
+        assert_eq!(
+            pending.units["db.service"].reason,
+            "changed [Service] ExecStart"
+        );
 
-    // Reload units that need it. This includes remounting changed mount units.
-    if !units_to_reload.is_empty() {
-        let mut units = units_to_reload
-            .keys()
-            .map(String::as_str)
-            .collect::<Vec<&str>>();
-        units.sort_by_key(|name| name.to_lowercase());
-        eprintln!("reloading the following units: {}", units.join(", "));
+        // Switches that restart a unit forget about it.
+        let mut restart_plan = super::SwitchPlan::default();
+        restart_plan
+            .units_to_restart
+            .insert("db.service".to_string(), ());
+        pending.update(&restart_plan, &new);
+        assert_eq!(pending.units.keys().collect::<Vec<_>>(), ["broker.service"]);
+
+        pending.update(&plan, &new);
+        let systemd = InMemorySystemdManager {
+            units: RefCell::new(HashMap::from([
+                ("db.service".to_string(), unit_state("active", "db.service")),
+                (
+                    "broker.service".to_string(),
+                    unit_state("active", "broker.service"),
+                ),
+            ])),
+            failing_units: HashMap::from([("broker.service".to_string(), ())]),
+            ..Default::default()
+        };
+        assert!(!super::apply_pending_restarts(
+            &systemd,
+            &mut pending,
+            &["unknown.service".to_string()],
+            &new.join(super::SYSTEM_UNIT_DIR),
+        )
+        .unwrap());
+        assert!(systemd.jobs.borrow().is_empty());
+
+        // Units are restarted in dependency order and only forgotten once they came back up.
+        assert!(!super::apply_pending_restarts(
+            &systemd,
+            &mut pending,
+            &[],
+            &new.join(super::SYSTEM_UNIT_DIR),
+        )
+        .unwrap());
+        assert_eq!(
+            systemd
+                .jobs
+                .borrow()
+                .iter()
+                .map(|(unit, _)| unit.as_str())
+                .collect::<Vec<_>>(),
+            ["db.service", "broker.service"]
+        );
+        assert_eq!(pending.units.keys().collect::<Vec<_>>(), ["broker.service"]);
+
+        // Units that stopped running don't need a restart anymore.
+        systemd.units.borrow_mut().clear();
+        assert!(super::apply_pending_restarts(
+            &systemd,
+            &mut pending,
+            &[],
+            &new.join(super::SYSTEM_UNIT_DIR),
+        )
+        .unwrap());
+        assert!(pending.units.is_empty());
+    }
 
-        for unit in units {
-            match systemd.reload_unit(unit, "replace") {
-                Ok(job_path) => {
-                    submitted_jobs
-                        .borrow_mut()
-                        .insert(job_path.clone(), Job::Reload);
-                }
-                Err(err) => {
-                    eprintln!("Failed to reload {unit}: {err}");
-                    exit_code = 4;
-                }
-            }
+    #[test]
+    fn stale_services() {
+        assert_eq!(
+            super::store_path_of("/nix/store/abc-glibc-2.40/lib/libc.so.6 (deleted)"),
+            Some("/nix/store/abc-glibc-2.40")
+        );
+        assert_eq!(
+            super::store_path_of("/nix/store/abc-foo (deleted)"),
+            Some("/nix/store/abc-foo")
+        );
+        assert_eq!(super::store_path_of("/nix/store/"), None);
+        assert_eq!(super::store_path_of("/usr/lib/libc.so.6"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let cgroup_root = tmp.path().join("cgroup");
+        let proc_root = tmp.path().join("proc");
+        let unit_dir = tmp.path().join("unit-dir");
+        for (cgroup, pids) in [
+            ("system.slice/old.service", "10\n"),
+            // Forked processes may live in a child cgroup.
+            ("system.slice/old-child.service/worker", "20\n"),
+            ("system.slice/current.service", "30\n"),
+            ("system.slice/deferred.service", "40\n"),
+            ("system.slice/run-u1.service", "50\n"),
+            // A build started by the daemon.
+            ("system.slice/nix-daemon.service", "60\n70\n"),
+        ] {
+            write_file(&cgroup_root.join(cgroup).join("cgroup.procs"), pids);
+        }
+        for (pid, exe, maps) in [
+            (
+                "10",
+                "/nix/store/aaa-old/bin/old",
+                "7f00-7f01 r-xp 00000000 00:1f 123 /nix/store/bbb-glibc-old/lib/libc.so.6\n",
+            ),
+            (
+                "20",
+                "/nix/store/ccc-current/bin/current",
+                "7f00-7f01 r-xp 00000000 00:1f 456 /nix/store/ddd-lib with space/lib/a.so (deleted)\n7f02-7f03 rw-p 00000000 00:00 0 [heap]\n",
+            ),
+            ("30", "/nix/store/ccc-current/bin/current", ""),
+            ("40", "/nix/store/aaa-old/bin/old", ""),
+            ("50", "/nix/store/aaa-old/bin/old", ""),
+            ("60", "/nix/store/ccc-current/bin/nix-daemon", ""),
+            ("70", "/nix/store/eee-bash/bin/bash", ""),
+        ] {
+            write_file(&proc_root.join(pid).join("maps"), maps);
+            std::os::unix::fs::symlink(exe, proc_root.join(pid).join("exe")).unwrap();
+        }
+        for (unit, contents) in [
+            ("old.service", "[Service]\n"),
+            ("old-child.service", "[Service]\n"),
+            ("current.service", "[Service]\n"),
+            ("deferred.service", "[Service]\nX-RestartDeferred=true\n"),
+            ("nix-daemon.service", "[Service]\nKillMode=process\n"),
+        ] {
+            write_file(&unit_dir.join(unit), contents);
         }
 
-        block_on_jobs(&dbus_conn, &submitted_jobs);
+        // run-u1.service is a transient unit without a unit file in the configuration.
+        let units = [
+            "old.service",
+            "old-child.service",
+            "current.service",
+            "deferred.service",
+            "run-u1.service",
+            "nix-daemon.service",
+            "old.socket",
+        ];
+        let systemd = InMemorySystemdManager {
+            units: RefCell::new(
+                units
+                    .iter()
+                    .map(|unit| (unit.to_string(), unit_state("active", unit)))
+                    .collect(),
+            ),
+            main_pids: HashMap::from([("nix-daemon.service".to_string(), 60)]),
+            ..Default::default()
+        };
+        let closure = ["/nix/store/ccc-current".to_string()].into();
 
-        remove_file_if_exists(RELOAD_LIST_FILE)
-            .with_context(|| format!("Failed to remove {RELOAD_LIST_FILE}"))?;
-    }
+        let stale =
+            super::stale_services(&systemd, &closure, &unit_dir, &cgroup_root, &proc_root).unwrap();
+        assert_eq!(
+            stale,
+            BTreeMap::from([
+                (
+                    "deferred.service".to_string(),
+                    ["/nix/store/aaa-old".to_string()].into()
+                ),
+                (
+                    "old-child.service".to_string(),
+                    ["/nix/store/ddd-lib with space".to_string()].into()
+                ),
+                (
+                    "old.service".to_string(),
+                    [
+                        "/nix/store/aaa-old".to_string(),
+                        "/nix/store/bbb-glibc-old".to_string()
+                    ]
+                    .into()
+                ),
+            ])
+        );
 
-    // Restart changed services (those that have to be restarted rather than stopped and started).
-    if !units_to_restart.is_empty() {
-        let mut units = units_to_restart
-            .keys()
-            .map(String::as_str)
-            .collect::<Vec<&str>>();
-        units.sort_by_key(|name| name.to_lowercase());
-        eprintln!("restarting the following units: {}", units.join(", "));
+        // Units whose restart is deferred are left alone.
+        assert!(super::restart_stale_services(&systemd, &stale, &unit_dir));
+        assert_eq!(
+            systemd.jobs_of(super::Job::Restart),
+            ["old-child.service", "old.service"]
+        );
+    }
 
-        for unit in units {
-            match systemd.restart_unit(unit, "replace") {
-                Ok(job_path) => {
-                    let mut jobs = submitted_jobs.borrow_mut();
-                    jobs.insert(job_path, Job::Restart);
-                }
-                Err(err) => {
-                    eprintln!("Failed to restart {unit}: {err}");
-                    exit_code = 4;
-                }
-            }
+    #[test]
+    fn reboot_required() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        let booted = tmp.path().join("booted");
+        let new = tmp.path().join("new");
+        let run_dir = tmp.path().join("run");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        for path in [
+            "linux-a/bzImage",
+            "linux-b/bzImage",
+            "initrd-a/initrd",
+            "modules-a",
+        ] {
+            write_file(&store.join(path).join("marker"), "");
         }
+        for (toplevel, kernel) in [(&booted, "linux-a"), (&new, "linux-b")] {
+            std::fs::create_dir_all(toplevel).unwrap();
+            std::os::unix::fs::symlink(store.join(kernel).join("bzImage"), toplevel.join("kernel"))
+                .unwrap();
+            std::os::unix::fs::symlink(store.join("initrd-a/initrd"), toplevel.join("initrd"))
+                .unwrap();
+        }
+        std::os::unix::fs::symlink(store.join("modules-a"), booted.join("kernel-modules")).unwrap();
+        write_file(&booted.join("kernel-params"), "quiet");
+        write_file(&new.join("kernel-params"), "quiet");
 
-        block_on_jobs(&dbus_conn, &submitted_jobs);
+        let changes = super::boot_component_changes(&booted, &new);
+        assert_eq!(
+            changes,
+            [
+                super::BootComponentChange {
+                    component: "kernel".to_string(),
+                    old: Some(store.join("linux-a/bzImage").display().to_string()),
+                    new: Some(store.join("linux-b/bzImage").display().to_string()),
+                },
+                super::BootComponentChange {
+                    component: "kernel-modules".to_string(),
+                    old: Some(store.join("modules-a").display().to_string()),
+                    new: None,
+                },
+            ]
+        );
+        assert!(super::boot_component_changes(&booted, &booted).is_empty());
 
-        remove_file_if_exists(RESTART_LIST_FILE)
-            .with_context(|| format!("Failed to remove {RESTART_LIST_FILE}"))?;
-    }
+        super::RebootRequired::record(&run_dir, &booted, &new, &changes).unwrap();
+        let reboot_required = super::RebootRequired::read(&run_dir).unwrap().unwrap();
+        assert_eq!(reboot_required.toplevel, new);
+        assert_eq!(reboot_required.components, changes);
+        assert_eq!(
+            super::component_names(&reboot_required.components),
+            "kernel, kernel-modules"
+        );
 
-    // Start all active targets, as well as changed units we stopped above. The latter is necessary
-    // because some may not be dependencies of the targets (i.e., they were manually started).
-    // FIXME: detect units that are symlinks to other units.  We shouldn't start both at the same
-    // time because we'll get a "Failed to add path to set" error from systemd.
-    let units_to_start_filtered = filter_units(&units_to_filter, &units_to_start);
-    if !units_to_start_filtered.is_empty() {
-        let mut units = units_to_start_filtered
-            .keys()
-            .map(String::as_str)
-            .collect::<Vec<&str>>();
-        units.sort_by_key(|name| name.to_lowercase());
-        eprintln!("starting the following units: {}", units.join(", "));
+        // Switching back to the booted configuration doesn't need a reboot anymore.
+        super::RebootRequired::record(&run_dir, &booted, &booted, &[]).unwrap();
+        assert!(super::RebootRequired::read(&run_dir).unwrap().is_none());
     }
 
-    for unit in units_to_start.keys() {
-        match systemd.start_unit(unit, "replace") {
-            Ok(job_path) => {
-                let mut jobs = submitted_jobs.borrow_mut();
-                jobs.insert(job_path, Job::Start);
-            }
-            Err(err) => {
-                eprintln!("Failed to start {unit}: {err}");
-                exit_code = 4;
-            }
-        }
-    }
+    #[test]
+    fn switch_history() {
+        let tmp = tempfile::tempdir().unwrap();
+        let history = tmp.path().join("log/switch-history.jsonl");
 
-    block_on_jobs(&dbus_conn, &submitted_jobs);
+        assert_eq!(
+            super::format_timestamp(std::time::UNIX_EPOCH),
+            "1970-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            super::format_timestamp(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            "2023-11-14T22:13:20Z"
+        );
 
-    remove_file_if_exists(START_LIST_FILE)
-        .with_context(|| format!("Failed to remove {START_LIST_FILE}"))?;
+        let mut plan = super::SwitchPlan::default();
+        plan.units_to_stop.insert("a.service".to_string(), ());
+        plan.units_to_start.insert("a.service".to_string(), ());
+        plan.units_to_restart.insert("c.service".to_string(), ());
+        plan.units_to_try_restart
+            .insert("b.service".to_string(), ());
+        plan.units_to_reload_or_restart
+            .insert("d.service".to_string(), ());
+        plan.restart_systemd = true;
+        let journal = super::SwitchJournal::new(
+            Path::new("/nix/store/new"),
+            Some(Path::new("/nix/store/old").to_path_buf()),
+            &super::Action::Switch,
+            &plan,
+        );
+        let start = super::SwitchStart {
+            // 2000-02-29T00:00:00Z
+            time: std::time::UNIX_EPOCH + Duration::from_secs(951_782_400),
+            action: "switch".to_string(),
+            previous_toplevel: None,
+            toplevel: Path::new("/nix/store/new").to_path_buf(),
+        };
 
-    for (unit, job, result) in finished_jobs.borrow().values() {
-        match result.as_str() {
-            "timeout" | "failed" | "dependency" => {
-                eprintln!("Failed to {job} {unit}");
-                exit_code = 4;
-            }
-            _ => {}
-        }
+        let record = start.finish(Some(&journal), &["c.service".to_string()], 4);
+        assert_eq!(record.started, "2000-02-29T00:00:00Z");
+        assert_eq!(
+            record.previous_toplevel.as_deref(),
+            Some(Path::new("/nix/store/old"))
+        );
+        assert_eq!(record.units_stopped, ["a.service"]);
+        assert_eq!(record.units_started, ["a.service"]);
+        assert_eq!(record.units_restarted, ["b.service", "c.service"]);
+        assert_eq!(record.units_reloaded, ["d.service"]);
+        assert_eq!(record.failed_units, ["c.service"]);
+        assert!(record.restart_systemd);
+        assert_eq!(record.exit_code, 4);
+
+        // A boot doesn't touch any units.
+        let boot = start.finish(None, &[], 0);
+        assert!(boot.units_stopped.is_empty() && !boot.restart_systemd);
+
+        assert!(super::read_switch_history(&history).unwrap().is_empty());
+        record.append(&history).unwrap();
+        // A record cut off halfway is skipped.
+        let mut contents = std::fs::read_to_string(&history).unwrap();
+        contents.push_str("{\"version\":1,\"start\n");
+        std::fs::write(&history, contents).unwrap();
+        boot.append(&history).unwrap();
+        let records = [record, boot];
+        assert_eq!(super::read_switch_history(&history).unwrap(), records);
+
+        // A switch that returns an error is recorded with the jobs of the journal it left behind.
+        let run_dir = tmp.path().join("run");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        journal.write(&run_dir).unwrap();
+        let mut guard = super::SwitchGuard::new(
+            &super::Action::Switch,
+            Path::new("/nix/store/new"),
+            &run_dir,
+        );
+        guard.history = history.clone();
+        guard.journal_written = true;
+        // The history grew too large and is rotated before the record is appended.
+        let mut contents = std::fs::read_to_string(&history).unwrap();
+        contents.push_str(&"\n".repeat(super::SWITCH_HISTORY_MAX_SIZE as usize));
+        std::fs::write(&history, contents).unwrap();
+        drop(guard);
+        assert!(super::rotated_history(&history).exists());
+        let read = super::read_switch_history(&history).unwrap();
+        assert_eq!(read[..2], records);
+        assert_eq!(read[2].exit_code, 1);
+        assert_eq!(read[2].units_stopped, ["a.service"]);
+
+        // Dry runs are not recorded.
+        let mut guard = super::SwitchGuard::new(
+            &super::Action::DryActivate,
+            Path::new("/nix/store/new"),
+            &run_dir,
+        );
+        guard.history = history.clone();
+        drop(guard);
+        assert_eq!(super::read_switch_history(&history).unwrap().len(), 3);
     }
 
-    dbus_conn
-        .remove_match(job_removed_token)
-        .context("Failed to cleanup systemd job match")?;
-
-    // Print failed and new units.
-    let mut failed_units = Vec::new();
-    let mut new_units = Vec::new();
+    #[test]
+    fn switch_progress() {
+        use dbus::{
+            blocking::stdintf::org_freedesktop_dbus::Properties, channel::MatchingReceiver,
+        };
+        use std::io::BufRead;
+
+        // A bus of our own, so that the test doesn't need the system bus.
+        let tmp = tempfile::tempdir().unwrap();
+        let config = tmp.path().join("bus.conf");
+        write_file(
+            &config,
+            &format!(
+                r#"<busconfig>
+  <type>session</type>
+  <listen>unix:path={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*"/>
+    <allow receive_sender="*"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                tmp.path().join("bus").display()
+            ),
+        );
+        let mut daemon = std::process::Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .arg("--nofork")
+            .arg("--print-address")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut address = String::new();
+        std::io::BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let connect = || {
+            let mut channel = dbus::channel::Channel::open_private(address.trim()).unwrap();
+            channel.register().unwrap();
+            channel
+        };
 
-    // NOTE: We want switch-to-configuration to be able to report to the user any units that failed
-    // to start or units that systemd had to restart due to having previously failed. This is
-    // inherently a race condition between how long our program takes to run and how long the unit
-    // in question takes to potentially fail. The amount of time we wait for new messages on the
-    // bus to settle is purely tuned so that this program is compatible with the Perl
-    // implementation.
-    //
-    // Wait for events from systemd to settle. process() will return true if we have received any
-    // messages on the bus.
-    let mut waited = Duration::from_millis(0);
-    let wait_interval = Duration::from_millis(250);
-    let max_wait = Duration::from_secs(90);
-    log::debug!("waiting for systemd events to settle");
-    while dbus_conn.process(wait_interval).unwrap_or_default() {
-        waited += wait_interval;
-        if waited >= max_wait {
-            log::debug!("timed out waiting systemd events to settle");
-            break;
+        let progress = super::SwitchProgress::publish(
+            connect().into(),
+            &super::Action::Switch,
+            Path::new("/nix/store/new"),
+        )
+        .unwrap();
+
+        let client = dbus::blocking::LocalConnection::from(connect());
+        let signals = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let rule = dbus::message::MatchRule::new()
+            .with_type(dbus::MessageType::Signal)
+            .with_path(super::PROGRESS_OBJECT_PATH);
+        client.add_match_no_cb(&rule.match_str()).unwrap();
+        let _signals = signals.clone();
+        client.start_receive(
+            rule,
+            Box::new(move |message: dbus::Message, _| {
+                let member = message.member().unwrap().to_string();
+                // The first argument, or the names of the changed properties.
+                let detail = match message.read2::<String, dbus::arg::PropMap>() {
+                    Ok((_, changed)) => {
+                        let mut names = changed.into_keys().collect::<Vec<_>>();
+                        names.sort();
+                        names.join(" ")
+                    }
+                    Err(_) => message.get1::<String>().unwrap_or_default(),
+                };
+                _signals.borrow_mut().push(format!("{member} {detail}"));
+                true
+            }),
+        );
+        let proxy = client.with_proxy(
+            super::PROGRESS_INTERFACE,
+            super::PROGRESS_OBJECT_PATH,
+            Duration::from_secs(5),
+        );
+        let get =
+            |property: &str| -> String { proxy.get(super::PROGRESS_INTERFACE, property).unwrap() };
+        assert_eq!(get("Action"), "switch");
+        assert_eq!(get("Phase"), "planning");
+        assert_eq!(get("Result"), "running");
+
+        let mut plan = super::SwitchPlan::default();
+        plan.units_to_stop.insert("a.service".to_string(), ());
+        plan.units_to_restart.insert("b.service".to_string(), ());
+        let journal = super::SwitchJournal::new(
+            Path::new("/nix/store/new"),
+            None,
+            &super::Action::Switch,
+            &plan,
+        );
+        progress.set_plan(&plan, &journal);
+        progress.set_phase(super::SwitchPhase::UnitsStopped);
+        progress.set_job_state("a.service", super::Job::Stop, "done");
+        progress.set_job_state("b.service", super::Job::Restart, "running");
+        progress.set_job_state("b.service", super::Job::Restart, "failed");
+        progress.finish(4, &["b.service".to_string()]);
+
+        let document: serde_json::Value = serde_json::from_str(&get("Plan")).unwrap();
+        assert_eq!(document["toplevel"], "/nix/store/new");
+        assert_eq!(document["units-to-stop"], serde_json::json!(["a.service"]));
+        let jobs: Vec<(String, String, String)> =
+            proxy.get(super::PROGRESS_INTERFACE, "Jobs").unwrap();
+        assert_eq!(
+            jobs,
+            [
+                (
+                    "a.service".to_string(),
+                    "stop".to_string(),
+                    "done".to_string()
+                ),
+                (
+                    "b.service".to_string(),
+                    "restart".to_string(),
+                    "failed".to_string()
+                ),
+            ]
+        );
+        assert_eq!(get("Phase"), "finished");
+        assert_eq!(get("Result"), "failed");
+        let exit_code: i32 = proxy.get(super::PROGRESS_INTERFACE, "ExitCode").unwrap();
+        assert_eq!(exit_code, 4);
+
+        while !signals
+            .borrow()
+            .iter()
+            .any(|signal| signal.starts_with("Finished"))
+        {
+            client.process(Duration::from_secs(5)).unwrap();
         }
-    }
+        assert_eq!(
+            *signals.borrow(),
+            [
+                "PropertiesChanged Plan",
+                "PhaseChanged planned",
+                "PropertiesChanged Phase",
+                "PhaseChanged units-stopped",
+                "PropertiesChanged Phase",
+                "JobChanged a.service",
+                "JobChanged b.service",
+                "JobChanged b.service",
+                "PhaseChanged finished",
+                "PropertiesChanged Phase",
+                "PropertiesChanged ExitCode FailedUnits Result",
+                "Finished failed",
+            ]
+        );
 
-    let new_active_units = get_active_units(&systemd)?;
+        // A switch that returns an error still announces that it finished.
+        let mut guard = super::SwitchGuard::new(
+            &super::Action::Test,
+            Path::new("/nix/store/new"),
+            tmp.path(),
+        );
+        guard.history = tmp.path().join("switch-history.jsonl");
+        guard.progress = Some(
+            super::SwitchProgress::publish(
+                connect().into(),
+                &super::Action::Test,
+                Path::new("/nix/store/new"),
+            )
+            .unwrap(),
+        );
+        assert_eq!(get("Result"), "running");
+        drop(guard);
+        assert_eq!(get("Result"), "failed");
+        let exit_code: i32 = proxy.get(super::PROGRESS_INTERFACE, "ExitCode").unwrap();
+        assert_eq!(exit_code, 1);
+
+        daemon.kill().unwrap();
+        daemon.wait().unwrap();
+    }
 
-    for (unit, unit_state) in new_active_units {
-        if &unit_state.state == "failed" {
-            failed_units.push(unit);
-            continue;
+    #[test]
+    fn endangering_units() {
+        let tmp = tempfile::tempdir().unwrap();
+        let proc_root = tmp.path();
+        for (pid, cgroup, stat) in [
+            (
+                "300",
+                "0::/user.slice/user-1000.slice/session-2.scope\n",
+                "300 (switch-to-conf) S 200 300 0",
+            ),
+            (
+                "200",
+                "0::/user.slice/user-1000.slice/session-2.scope\n",
+                "200 (sudo (x) y) S 100 200 0",
+            ),
+            (
+                "100",
+                "0::/system.slice/sshd.service\n",
+                "100 (sshd) S 1 100 100",
+            ),
+        ] {
+            write_file(&proc_root.join(pid).join("cgroup"), cgroup);
+            write_file(&proc_root.join(pid).join("stat"), stat);
         }
 
-        if unit_state.substate == "auto-restart" && unit.ends_with(".service") {
-            // A unit in auto-restart substate is a failure *if* it previously failed to start
-            let unit_object_path = systemd
-                .get_unit(&unit)
-                .with_context(|| format!("Failed to get unit info for {unit}"))?;
-            let exec_main_status: i32 = dbus_conn
-                .with_proxy(
-                    "org.freedesktop.systemd1",
-                    unit_object_path,
-                    Duration::from_millis(5000),
-                )
-                .get("org.freedesktop.systemd1.Service", "ExecMainStatus")
-                .with_context(|| format!("Failed to get ExecMainStatus for {unit}"))?;
+        let ancestor_units = super::ancestor_units(proc_root, 300);
+        assert_eq!(
+            ancestor_units.iter().collect::<Vec<_>>(),
+            [
+                "session-2.scope",
+                "sshd.service",
+                "system.slice",
+                "user-1000.slice",
+                "user.slice"
+            ]
+        );
 
-            if exec_main_status != 0 {
-                failed_units.push(unit);
-                continue;
-            }
+        let mut plan = super::SwitchPlan::default();
+        for unit in ["nginx.service", "network-addresses-eth0.service"] {
+            plan.units_to_restart.insert(unit.to_string(), ());
         }
+        assert!(super::endangering_units(&plan, &ancestor_units, false).is_empty());
+        assert_eq!(
+            super::endangering_units(&plan, &ancestor_units, true),
+            ["network-addresses-eth0.service"]
+        );
 
-        // Ignore scopes since they are not managed by this script but rather created and managed
-        // by third-party services via the systemd dbus API. This only lists units that are not
-        // failed (including ones that are in auto-restart but have not failed previously)
-        if unit_state.state != "failed"
-            && !current_active_units.contains_key(&unit)
-            && !unit.ends_with(".scope")
-        {
-            new_units.push(unit);
-        }
-    }
+        // Restarting sshd.service keeps the sessions that run in their own scope.
+        let mut restart_sshd = super::SwitchPlan::default();
+        restart_sshd
+            .units_to_restart
+            .insert("sshd.service".to_string(), ());
+        let session_units = ["session-2.scope".to_string()].into();
+        assert!(super::endangering_units(&restart_sshd, &session_units, true).is_empty());
 
-    if !new_units.is_empty() {
-        new_units.sort_by_key(|name| name.to_lowercase());
-        eprintln!(
-            "the following new units were started: {}",
-            new_units.join(", ")
+        plan.units_to_stop.insert("sshd.service".to_string(), ());
+        plan.units_to_start.insert("sshd.service".to_string(), ());
+        assert_eq!(
+            super::endangering_units(&plan, &ancestor_units, false),
+            ["sshd.service"]
         );
     }
 
-    if !failed_units.is_empty() {
-        failed_units.sort_by_key(|name| name.to_lowercase());
-        eprintln!(
-            "warning: the following units failed: {}",
-            failed_units.join(", ")
+    #[test]
+    fn detached_switch_environment() {
+        let vars = [
+            ("TOPLEVEL", "/nix/store/new"),
+            ("SSH_CONNECTION", "10.0.0.1 50000 10.0.0.2 22"),
+            ("STC_JOB_TIMEOUT", "60"),
+            ("SSH_AUTH_SOCK", "/tmp/ssh-agent"),
+            ("GITHUB_TOKEN", "secret"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(
+            super::detached_switch_environment(vars.into_iter(), "switch.service"),
+            [
+                "TOPLEVEL=/nix/store/new",
+                "SSH_CONNECTION=10.0.0.1 50000 10.0.0.2 22",
+                "STC_JOB_TIMEOUT=60",
+                "__NIXOS_SWITCH_TO_CONFIGURATION_DETACHED_UNIT=switch.service",
+            ]
         );
-        _ = std::process::Command::new(new_systemd.join("bin/systemctl"))
-            .arg("status")
-            .arg("--no-pager")
-            .arg("--full")
-            .args(failed_units)
-            .spawn()
-            .map(|mut child| child.wait());
-
-        exit_code = 4;
     }
 
-    if exit_code == 0 {
-        log::info!(
-            "finished switching to system configuration {}",
-            toplevel.display()
+    #[test]
+    fn resolve_specialisation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let toplevel = tmp.path();
+        for (name, files) in [
+            (
+                "gaming",
+                &[
+                    "nixos-version",
+                    "init-interface-version",
+                    "bin/switch-to-configuration",
+                ][..],
+            ),
+            ("broken", &["init-interface-version"][..]),
+            (
+                "unswitchable",
+                &["nixos-version", "init-interface-version"][..],
+            ),
+        ] {
+            for file in files {
+                write_file(
+                    &toplevel.join("specialisation").join(name).join(file),
+                    "25.11",
+                );
+            }
+        }
+
+        assert_eq!(
+            super::resolve_specialisation(toplevel, "gaming").unwrap(),
+            toplevel.join("specialisation/gaming")
         );
-    } else {
-        log::error!(
-            "switching to system configuration {} failed (status {})",
-            toplevel.display(),
-            exit_code
+        assert_eq!(
+            super::resolve_specialisation(toplevel, "office")
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "{} has no specialisation office, available specialisations: broken, gaming, unswitchable",
+                toplevel.display()
+            )
         );
+        assert!(super::resolve_specialisation(toplevel, "broken").is_err());
+        assert!(super::resolve_specialisation(toplevel, "unswitchable").is_err());
+        assert!(super::resolve_specialisation(toplevel, "../specialisation/gaming").is_err());
     }
 
-    std::process::exit(exit_code);
-}
-
-fn main() -> anyhow::Result<()> {
-    match std::env::var("__NIXOS_SWITCH_TO_CONFIGURATION_PARENT_EXE").ok() {
-        Some(parent_exe) => do_user_switch(parent_exe),
-        None => {
-            let mut args = std::env::args();
-            let argv0 = args.next().ok_or(anyhow!("no argv[0]"))?;
-            let argv0 = argv0
-                .split(std::path::MAIN_SEPARATOR_STR)
-                .last()
-                .unwrap_or("switch-to-configuration");
-
-            let Some(Ok(action)) = args.next().map(|a| Action::from_str(&a)) else {
-                usage(argv0);
-            };
-
-            if unsafe { nix::libc::geteuid() } == 0 {
-                do_system_switch(action)
-            } else {
-                bail!("{} must be run as the root user", argv0);
-            }
-        }
+    #[test]
+    fn running_specialisation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let run_dir = tmp.path();
+        let parent = Path::new("/nix/store/parent");
+        let gaming = Path::new("/nix/store/gaming");
+
+        assert!(super::bootloader_selects_specialisation("systemd-boot"));
+        assert!(!super::bootloader_selects_specialisation("grub"));
+
+        super::RunningSpecialisation::record(run_dir, gaming, Some((parent, "gaming"))).unwrap();
+        let running = super::RunningSpecialisation::read(run_dir, gaming)
+            .unwrap()
+            .unwrap();
+        assert_eq!(running.parent, parent);
+        assert_eq!(running.name, "gaming");
+        // Only a rollback to the specialisation itself switches to it as a specialisation.
+        assert!(super::RunningSpecialisation::read(run_dir, parent)
+            .unwrap()
+            .is_none());
+
+        super::RunningSpecialisation::record(run_dir, parent, None).unwrap();
+        assert!(super::RunningSpecialisation::read(run_dir, gaming)
+            .unwrap()
+            .is_none());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
 
     #[test]
-    fn parse_fstab() {
-        {
-            let (filesystems, swaps) = super::parse_fstab(std::io::Cursor::new(""));
-            assert!(filesystems.is_empty());
-            assert!(swaps.is_empty());
+    fn plan_socket_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let current = tmp.path().join("current");
+        let new = tmp.path().join("new");
+
+        for (unit, current_contents, new_contents) in [
+            (
+                "web.socket",
+                "[Socket]\nListenStream=80\n",
+                "[Socket]\nListenStream=8080\n",
+            ),
+            (
+                "web.service",
+                "[Service]\nExecStart=/bin\n",
+                "[Service]\nExecStart=/bin\n",
+            ),
+            (
+                "conn.socket",
+                "[Socket]\nAccept=yes\nListenStream=22\n",
+                "[Socket]\nAccept=yes\nListenStream=2222\n",
+            ),
+            (
+                "conn@.service",
+                "[Service]\nExecStart=/bin\n",
+                "[Service]\nExecStart=/bin\n",
+            ),
+            (
+                "db.socket",
+                "[Socket]\nService=database.service\nListenStream=5432\n",
+                "[Socket]\nService=database.service\nListenStream=5433\n",
+            ),
+            (
+                "database.service",
+                "[Service]\nX-RestartIfChanged=false\nExecStart=/bin\n",
+                "[Service]\nX-RestartIfChanged=false\nExecStart=/bin\n",
+            ),
+        ] {
+            write_file(
+                &current.join("etc/systemd/system").join(unit),
+                current_contents,
+            );
+            write_file(&new.join("etc/systemd/system").join(unit), new_contents);
         }
 
-        {
-            let (filesystems, swaps) = super::parse_fstab(std::io::Cursor::new(
-                r#"\
-invalid
-                    "#,
-            ));
-            assert!(filesystems.is_empty());
-            assert!(swaps.is_empty());
-        }
+        let plan = super::plan_switch(
+            &snapshot(
+                &current,
+                &[
+                    "web.socket",
+                    "web.service",
+                    "conn.socket",
+                    "conn@0-1.service",
+                    "db.socket",
+                    "database.service",
+                ],
+            ),
+            &new,
+            Path::new("/nix/store/systemd/lib/systemd/systemd"),
+            &HashMap::new(),
+            false,
+            false,
+        )
+        .unwrap();
 
-        {
-            let (filesystems, swaps) = super::parse_fstab(std::io::Cursor::new(
-                r#"\
-# This is a generated file.  Do not edit!
-#
-# To make changes, edit the fileSystems and swapDevices NixOS options
-# in your /etc/nixos/configuration.nix file.
-#
-# <file system> <mount point>   <type>  <options>       <dump>  <pass>
-
-# Filesystems.
-/dev/mapper/root / btrfs x-initrd.mount,compress=zstd,noatime,defaults 0 0
-/dev/disk/by-partlabel/BOOT /boot vfat x-systemd.automount 0 2
-/dev/disk/by-partlabel/home /home ext4 defaults 0 2
-/dev/mapper/usr /nix/.ro-store erofs x-initrd.mount,ro 0 2
-
-
-# Swap devices.
-                    "#,
-            ));
-            assert_eq!(filesystems.len(), 4);
-            assert_eq!(swaps.len(), 0);
-            let home_fs = filesystems.get("/home").unwrap();
-            assert_eq!(home_fs.fs_type, "ext4");
-            assert_eq!(home_fs.device, "/dev/disk/by-partlabel/home");
-            assert_eq!(home_fs.options, "defaults");
-        }
+        assert_eq!(
+            super::sorted_units(&plan.units_to_stop),
+            ["conn.socket", "web.service", "web.socket"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_start),
+            ["conn.socket", "web.socket"]
+        );
+        assert_eq!(super::sorted_units(&plan.units_to_skip), ["db.socket"]);
+        assert!(plan.units_to_restart.is_empty());
     }
 
     #[test]
-    fn filter_units() {
+    fn swap_flags() {
+        assert_eq!(super::swap_flags("defaults"), 0);
+        assert_eq!(super::swap_flags("nofail"), 0);
+        assert_eq!(super::swap_flags("pri=10"), super::SWAP_FLAG_PREFER | 10);
+        assert_eq!(super::swap_flags("pri=-1"), 0);
         assert_eq!(
-            super::filter_units(&HashMap::from([]), &HashMap::from([])),
-            HashMap::from([])
+            super::swap_flags("discard=once,pri=1"),
+            super::SWAP_FLAG_DISCARD | super::SWAP_FLAG_DISCARD_ONCE | super::SWAP_FLAG_PREFER | 1
         );
+    }
 
-        assert_eq!(
-            super::filter_units(
-                &HashMap::from([("foo".to_string(), ())]),
-                &HashMap::from([("foo".to_string(), ()), ("bar".to_string(), ())])
+    #[test]
+    fn plan_fstab_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut current = snapshot(
+            tmp.path(),
+            &[
+                "dev-sda2.swap",
+                "dev-sda3.swap",
+                "dev-sda4.swap",
+                "-.mount",
+                "nix.mount",
+                "data.mount",
+                "backup.automount",
+                "backup.mount",
+                "media.mount",
+            ],
+        );
+        current.fstab_units = parse_units(&[
+            ("dev-sda2.swap", "[Swap]\nWhat=/dev/sda2\nOptions=pri=1\n"),
+            ("dev-sda3.swap", "[Swap]\nWhat=/dev/sda3\n"),
+            ("dev-sda4.swap", "[Swap]\nWhat=/dev/sda4\n"),
+            ("-.mount", "[Mount]\nWhat=/dev/vda1\nWhere=/\nType=ext4\n"),
+            (
+                "nix.mount",
+                "[Mount]\nWhat=/dev/vda2\nWhere=/nix\nType=ext4\n",
             ),
-            HashMap::from([("bar".to_string(), ())])
+            (
+                "data.mount",
+                "[Mount]\nWhat=/dev/vdb\nWhere=/data\nType=ext4\n",
+            ),
+            (
+                "backup.automount",
+                "[Automount]\nWhere=/backup\nTimeoutIdleSec=60\n",
+            ),
+            ("backup.mount", "[Mount]\nWhat=/dev/vdc\nWhere=/backup\n"),
+            ("media.mount", "[Mount]\nWhat=/dev/vdd\nWhere=/media\n"),
+            // Not mounted because of noauto
+            ("cdrom.mount", "[Mount]\nWhat=/dev/sr0\nWhere=/cdrom\n"),
+        ]);
+        let new_fstab_units = parse_units(&[
+            ("dev-sda2.swap", "[Swap]\nWhat=/dev/sda2\nOptions=pri=5\n"),
+            ("dev-sda3.swap", "[Swap]\nWhat=/dev/sda3\nOptions=nofail\n"),
+            ("dev-sda5.swap", "[Swap]\nWhat=/dev/sda5\n"),
+            (
+                "-.mount",
+                "[Mount]\nWhat=/dev/vda1\nWhere=/\nType=ext4\nOptions=noatime\n",
+            ),
+            (
+                "nix.mount",
+                "[Mount]\nWhat=/dev/vda3\nWhere=/nix\nType=ext4\n",
+            ),
+            (
+                "data.mount",
+                "[Unit]\nRequires=foo.service\n[Mount]\nWhat=/dev/vdb\nWhere=/data\nType=ext4\n",
+            ),
+            (
+                "backup.automount",
+                "[Automount]\nWhere=/backup\nTimeoutIdleSec=120\n",
+            ),
+            ("backup.mount", "[Mount]\nWhat=/dev/vdc\nWhere=/backup\n"),
+            ("cdrom.mount", "[Mount]\nWhat=/dev/sr1\nWhere=/cdrom\n"),
+        ]);
+
+        let plan = super::plan_switch(
+            &current,
+            &tmp.path().join("new"),
+            Path::new("/nix/store/systemd/lib/systemd/systemd"),
+            &new_fstab_units,
+            false,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(plan.swaps_to_stop, ["/dev/sda4"]);
+        assert_eq!(plan.swaps_to_start, ["/dev/sda5"]);
+        assert_eq!(
+            plan.swaps_to_reapply,
+            [super::SwapChange {
+                device: "/dev/sda2".into(),
+                old_options: "pri=1".into(),
+                new_options: "pri=5".into(),
+            }]
+        );
+        assert_eq!(super::sorted_units(&plan.units_to_start), ["dev-sda5.swap"]);
+
+        assert_eq!(
+            plan.mounts
+                .iter()
+                .map(|change| (change.unit.as_str(), &change.action))
+                .collect::<Vec<_>>(),
+            [
+                ("-.mount", &super::MountAction::Reload),
+                ("backup.automount", &super::MountAction::Restart),
+                ("media.mount", &super::MountAction::Stop),
+                ("nix.mount", &super::MountAction::Skip),
+            ]
+        );
+        assert_eq!(super::sorted_units(&plan.units_to_reload), ["-.mount"]);
+        assert_eq!(
+            super::sorted_units(&plan.units_to_restart),
+            ["backup.automount"]
         );
+        assert_eq!(super::sorted_units(&plan.units_to_stop), ["media.mount"]);
+        assert_eq!(super::sorted_units(&plan.units_to_skip), ["nix.mount"]);
     }
 
     #[test]
-    fn compare_units() {
-        {
-            assert!(
-                super::compare_units(&HashMap::from([]), &HashMap::from([]))
-                    == super::UnitComparison::Equal
-            );
+    fn collapse_aliases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let unit_dir = tmp.path().join("etc/systemd/system");
 
-            assert!(
-                super::compare_units(
-                    &HashMap::from([("Unit".to_string(), HashMap::from([]))]),
-                    &HashMap::from([])
-                ) == super::UnitComparison::Equal
-            );
+        write_file(
+            &unit_dir.join("dbus-broker.service"),
+            "[Service]\nExecStart=/bin\n[Install]\nAlias=dbus.service\n",
+        );
+        write_file(
+            &unit_dir.join("getty@.service"),
+            "[Service]\nExecStart=/bin\n",
+        );
+        std::os::unix::fs::symlink("getty@.service", unit_dir.join("autovt@.service")).unwrap();
+        write_file(
+            &unit_dir.join("sshd.service"),
+            "[Service]\nExecStart=/bin\n",
+        );
+        std::os::unix::fs::symlink("/dev/null", unit_dir.join("masked.service")).unwrap();
 
-            assert!(
-                super::compare_units(
-                    &HashMap::from([(
-                        "Unit".to_string(),
-                        HashMap::from([(
-                            "X-Reload-Triggers".to_string(),
-                            vec!["foobar".to_string()]
-                        )])
-                    )]),
-                    &HashMap::from([])
-                ) == super::UnitComparison::Equal
-            );
-        }
+        let aliases = super::unit_aliases(&unit_dir).unwrap();
+        assert_eq!(
+            aliases,
+            HashMap::from([
+                (
+                    "dbus.service".to_string(),
+                    "dbus-broker.service".to_string()
+                ),
+                ("autovt@.service".to_string(), "getty@.service".to_string()),
+            ])
+        );
+        assert_eq!(
+            super::canonical_unit_name("autovt@tty1.service", &aliases),
+            "getty@tty1.service"
+        );
 
-        {
-            assert!(
-                super::compare_units(
-                    &HashMap::from([("foobar".to_string(), HashMap::from([]))]),
-                    &HashMap::from([])
-                ) == super::UnitComparison::UnequalNeedsRestart
-            );
+        let mut plan = super::SwitchPlan {
+            units_to_start: HashMap::from([
+                ("dbus.service".to_string(), ()),
+                ("dbus-broker.service".to_string(), ()),
+                ("autovt@tty1.service".to_string(), ()),
+            ]),
+            units_to_restart: HashMap::from([("sshd.service".to_string(), ())]),
+            units_to_reload: HashMap::from([("dbus.service".to_string(), ())]),
+            ..Default::default()
+        };
+        plan.collapse_aliases(&aliases);
 
-            assert!(
-                super::compare_units(
-                    &HashMap::from([(
-                        "Mount".to_string(),
-                        HashMap::from([("Options".to_string(), vec![])])
-                    )]),
-                    &HashMap::from([(
-                        "Mount".to_string(),
-                        HashMap::from([("Options".to_string(), vec!["ro".to_string()])])
-                    )])
-                ) == super::UnitComparison::UnequalNeedsReload
-            );
-        }
+        assert_eq!(
+            super::sorted_units(&plan.units_to_start),
+            ["dbus-broker.service", "getty@tty1.service"]
+        );
+        assert_eq!(
+            super::sorted_units(&plan.units_to_reload),
+            ["dbus-broker.service"]
+        );
+        assert_eq!(
+            plan.unit_aliases.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "autovt@tty1.service".to_string(),
+                    "getty@tty1.service".to_string()
+                ),
+                (
+                    "dbus.service".to_string(),
+                    "dbus-broker.service".to_string()
+                ),
+            ]
+        );
+    }
 
-        {
-            assert!(
-                super::compare_units(
-                    &HashMap::from([]),
-                    &HashMap::from([(
-                        "Unit".to_string(),
-                        HashMap::from([(
-                            "X-Reload-Triggers".to_string(),
-                            vec!["foobar".to_string()]
-                        )])
-                    )])
-                ) == super::UnitComparison::UnequalNeedsReload
-            );
+    #[test]
+    fn expired_jobs() {
+        let start = std::time::Instant::now();
+        let now = start + Duration::from_secs(60);
+        let jobs = HashMap::from([
+            ("old", ("old.service".to_string(), super::Job::Start, start)),
+            ("new", ("new.service".to_string(), super::Job::Restart, now)),
+        ]);
+
+        assert!(super::expired_jobs(&jobs, now, &super::JobTimeouts::default(), None).is_empty());
+
+        let per_job = super::JobTimeouts {
+            per_job: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(super::expired_jobs(&jobs, now, &per_job, None), ["old"]);
 
-            assert!(
-                super::compare_units(
-                    &HashMap::from([(
-                        "Unit".to_string(),
-                        HashMap::from([(
-                            "X-Reload-Triggers".to_string(),
-                            vec!["foobar".to_string()]
-                        )])
-                    )]),
-                    &HashMap::from([(
-                        "Unit".to_string(),
-                        HashMap::from([(
-                            "X-Reload-Triggers".to_string(),
-                            vec!["barfoo".to_string()]
-                        )])
-                    )])
-                ) == super::UnitComparison::UnequalNeedsReload
-            );
+        let mut expired =
+            super::expired_jobs(&jobs, now, &super::JobTimeouts::default(), Some(start));
+        expired.sort();
+        assert_eq!(expired, ["new", "old"]);
 
-            assert!(
-                super::compare_units(
-                    &HashMap::from([(
-                        "Mount".to_string(),
-                        HashMap::from([("Type".to_string(), vec!["ext4".to_string()])])
-                    )]),
-                    &HashMap::from([(
-                        "Mount".to_string(),
-                        HashMap::from([("Type".to_string(), vec!["btrfs".to_string()])])
-                    )])
-                ) == super::UnitComparison::UnequalNeedsRestart
-            );
-        }
+        // The deadline counts from the start of the switch, even if that was in another process.
+        let total = super::JobTimeouts {
+            total: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let before = std::time::Instant::now();
+        let deadline = total
+            .deadline(std::time::SystemTime::now() - Duration::from_secs(50))
+            .unwrap();
+        assert!(deadline <= std::time::Instant::now() + Duration::from_secs(10));
+        assert!(deadline >= before + Duration::from_secs(9));
+        let deadline = total
+            .deadline(std::time::SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        assert!(deadline <= std::time::Instant::now());
+        assert!(super::JobTimeouts::default()
+            .deadline(std::time::SystemTime::now())
+            .is_none());
+    }
+
+    #[test]
+    fn timed_out_exit_code() {
+        assert_eq!(super::timed_out_exit_code(0), super::JOB_TIMEOUT_EXIT_CODE);
+        assert_eq!(super::timed_out_exit_code(4), super::JOB_TIMEOUT_EXIT_CODE);
+        // The activation script failed and a job timed out.
+        assert_eq!(super::timed_out_exit_code(2), 2);
     }
 
     #[test]